@@ -0,0 +1,84 @@
+//! Throughput of the user store under concurrent read load.
+//!
+//! `global_mutex` is how the web server used to share the store: one `Mutex` around the whole
+//! `UserManager`, so every read waits for every other request. `user_manager` is the store as it
+//! is shared today, where reads only take a shared lock and run in parallel.
+//!
+//! Run with `cargo bench --bench concurrent_reads`.
+
+use std::{hint::black_box, sync::Mutex, thread};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use guided_telemetry::{
+    audit::Actor,
+    business::{NewUser, ReadUser, UserManager},
+};
+
+const USERS: usize = 1_000;
+const READS_PER_THREAD: usize = 200;
+
+fn store() -> UserManager {
+    let store = UserManager::with_failure_rates(0.0, 0.0);
+    let actor = Actor::new("bench", None);
+    for i in 0..USERS {
+        store
+            .create(&actor, NewUser::new(&format!("user-{i}")))
+            .unwrap();
+    }
+    store
+}
+
+/// Spawns `threads` readers that each look up `READS_PER_THREAD` users through `read`.
+fn read_concurrently(threads: usize, read: impl Fn(&str) + Sync) {
+    thread::scope(|scope| {
+        for t in 0..threads {
+            let read = &read;
+            scope.spawn(move || {
+                for i in 0..READS_PER_THREAD {
+                    read(&format!("user-{}", (t * READS_PER_THREAD + i) % USERS));
+                }
+            });
+        }
+    });
+}
+
+fn concurrent_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_reads");
+
+    let global_mutex = Mutex::new(store());
+    let user_manager = store();
+
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((threads * READS_PER_THREAD) as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("global_mutex", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    read_concurrently(threads, |name| {
+                        let store = global_mutex.lock().unwrap();
+                        black_box(store.read_by_name(ReadUser::new(name)).unwrap());
+                    })
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("user_manager", threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    read_concurrently(threads, |name| {
+                        black_box(user_manager.read_by_name(ReadUser::new(name)).unwrap());
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_reads);
+criterion_main!(benches);
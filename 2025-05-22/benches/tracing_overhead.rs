@@ -0,0 +1,285 @@
+//! What our tracing layer stack costs per request.
+//!
+//! Sends requests through the whole router, in-process, under four subscribers:
+//! - `none`: no subscriber at all, the baseline
+//! - `fmt_only`: `EnvFilter` and the fmt layer, writing to nowhere
+//! - `full_otel`: what `otel.rs` installs, built by its `SubscriberBuilder`, with exporters that
+//!   throw the telemetry away, so the network does not add noise
+//! - `sampled_off`: the same, but no trace is sampled
+//!
+//! Every subscriber gets two requests: `GET /enrichment/{name}`, which no middleware of ours
+//! stops, and `GET /users`, which goes through authentication and the tenant middleware. Our user
+//! store fails half of its reads on purpose, so `/users` takes the error path as often as not. We
+//! turn off retries, their backoff would drown out what we measure, and keep the circuit breaker
+//! from opening. The configuration is spelled out here instead of read from the environment, so
+//! variables like `TAIL_SAMPLING` or `REDACT_FIELDS` in your shell do not change what we measure.
+//!
+//! Criterion reports the latency per request. Next to it, we print how many allocations a request
+//! makes, counted by our global allocator. Background threads like the one of the batch processor
+//! allocate, too, and are counted along.
+//!
+//! Run with `cargo bench --bench tracing_overhead`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    hint::black_box,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::{Router, body::Body, http::Request};
+use criterion::{Criterion, criterion_group, criterion_main};
+use guided_telemetry::{
+    auth::API_KEY_HEADER,
+    cfg::{
+        AuthCfg, Cfg, CircuitBreakerCfg, EnrichmentCfg, JobsCfg, ProfilingCfg, RedactionCfg,
+        RetryCfg, TelemetryCfg, TenantCfg,
+    },
+    log_format::{LogFormat, LogLines},
+    otel::{OtelGuard, SubscriberBuilder},
+    redaction::{Policy, RedactingFields, Redactor},
+    server,
+};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::ManualReader,
+    trace::{Sampler, SpanData, SpanExporter},
+};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+use tracing::Dispatch;
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt};
+
+/// Requests we count the allocations of, per subscriber
+const COUNTED_REQUESTS: u64 = 1_000;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts every allocation, then leaves it to the system allocator
+struct CountingAllocator;
+
+// SAFETY: we only count, all the work is done by `System`
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The key `GET /users` authenticates with
+const API_KEY: &str = "bench-key";
+
+/// What we send under every subscriber, by name
+const ROUTES: [(&str, &str); 2] = [
+    ("enrichment", "/enrichment/Ferris%20the%20Crab"),
+    ("users", "/users"),
+];
+
+/// An exporter that throws every span away
+#[derive(Debug)]
+struct Discard;
+
+impl SpanExporter for Discard {
+    async fn export(&self, _batch: Vec<SpanData>) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl opentelemetry_sdk::logs::LogExporter for Discard {
+    async fn export(&self, _batch: opentelemetry_sdk::logs::LogBatch<'_>) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// An audit log for one router, so neither earlier runs nor other routers add to it. Removed once
+/// dropped.
+struct AuditLogFile(PathBuf);
+
+impl AuditLogFile {
+    fn new() -> Self {
+        Self(std::env::temp_dir().join(format!(
+            "tracing_overhead-audit-{}.jsonl",
+            uuid::Uuid::new_v4()
+        )))
+    }
+}
+
+impl Drop for AuditLogFile {
+    fn drop(&mut self) {
+        // Not there if no router opened it
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The defaults of `Cfg::from_env`, except for what the benchmark needs
+fn cfg(audit_log: &Path) -> Cfg {
+    let redacted = ["name", "new_name", "user_uuid", "email"];
+    Cfg {
+        port: 0,
+        grpc_port: 0,
+        audit_log: audit_log.to_owned(),
+        auth: AuthCfg {
+            api_keys: HashMap::from([(API_KEY.to_owned(), "bench".to_owned())]),
+            ..AuthCfg::default()
+        },
+        tenants: TenantCfg {
+            default_tenant: "default".to_owned(),
+            allowed_tenants: vec![],
+            max_tenants: 100,
+            max_users_per_tenant: 10_000,
+        },
+        enrichment: EnrichmentCfg {
+            url: None,
+            timeout: Duration::from_secs(2),
+        },
+        jobs: JobsCfg {
+            workers: 2,
+            queue_capacity: 1_000,
+        },
+        profiling: ProfilingCfg {
+            max_duration: Duration::from_secs(60),
+            frequency: 99,
+            admins: vec![],
+        },
+        retry: RetryCfg {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        },
+        circuit_breaker: CircuitBreakerCfg {
+            failure_rate_threshold: 1.0,
+            window_size: 1_000,
+            minimum_calls: 1_000,
+            open_duration: Duration::from_secs(5),
+            half_open_calls: 3,
+        },
+        telemetry: TelemetryCfg {
+            tail_sampling: None,
+            redaction: RedactionCfg {
+                policies: redacted
+                    .into_iter()
+                    .map(|field| (field.to_owned(), Policy::Hash))
+                    .collect(),
+                hash_key: b"bench".to_vec(),
+            },
+            disk_buffer: None,
+            log_format: LogFormat::Pretty,
+        },
+    }
+}
+
+fn fmt_only(cfg: &Cfg) -> Dispatch {
+    let redactor = Redactor::new(cfg.telemetry.redaction.clone());
+    tracing_subscriber::registry()
+        .with(EnvFilter::new("info"))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(RedactingFields::new(redactor.clone()))
+                .event_format(LogLines::new(cfg.telemetry.log_format, redactor))
+                .with_writer(std::io::sink),
+        )
+        .into()
+}
+
+/// The subscriber of `otel.rs`, with exporters that throw everything away, sampling with `sampler`
+/// unless it is `None`. Sets the global meter provider, so the router has to be built afterwards to
+/// record its metrics.
+fn full_otel(cfg: &Cfg, sampler: Option<Sampler>) -> (Dispatch, OtelGuard) {
+    let builder = SubscriberBuilder::new(&cfg.telemetry)
+        .with_env_filter(EnvFilter::new("info"))
+        .with_writer(std::io::sink)
+        .with_span_exporter(Discard)
+        .with_metric_reader(ManualReader::builder().build());
+    let builder = match sampler {
+        Some(sampler) => builder.with_sampler(sampler),
+        None => builder,
+    };
+    #[cfg(feature = "otlp")]
+    let builder = builder.with_log_exporter(Discard);
+    builder.build()
+}
+
+async fn request(app: &Router, uri: &str) {
+    let request = Request::get(uri)
+        .header(API_KEY_HEADER, API_KEY)
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    // Failed reads are part of the benchmark, but requests that do not make it to the handler are
+    // not
+    assert!(
+        !response.status().is_client_error(),
+        "{uri}: {}",
+        response.status()
+    );
+    black_box(
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap(),
+    );
+}
+
+/// Benchmarks requests to a fresh router, with `dispatch` as the subscriber (`None` for none).
+fn bench(c: &mut Criterion, rt: &Runtime, name: &str, dispatch: Option<Dispatch>) {
+    let _guard = dispatch.map(|dispatch| tracing::dispatcher::set_default(&dispatch));
+    let audit_log = AuditLogFile::new();
+    let app = rt.block_on(async { server::router(cfg(&audit_log.0)).unwrap() });
+
+    for (route, uri) in ROUTES {
+        let name = format!("{name}/{route}");
+        c.bench_function(&name, |b| b.iter(|| rt.block_on(request(&app, uri))));
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        rt.block_on(async {
+            for _ in 0..COUNTED_REQUESTS {
+                request(&app, uri).await;
+            }
+        });
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "{name}: {:.1} allocations per request\n",
+            allocations as f64 / COUNTED_REQUESTS as f64
+        );
+    }
+}
+
+fn tracing_overhead(c: &mut Criterion) {
+    // A single thread, so every task the router spawns reports to the subscriber of the benchmark
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // Only for the subscribers, which do not touch the audit log
+    let audit_log = AuditLogFile::new();
+    let cfg = cfg(&audit_log.0);
+
+    bench(c, &rt, "tracing_overhead/none", None);
+    bench(c, &rt, "tracing_overhead/fmt_only", Some(fmt_only(&cfg)));
+
+    // Dropping the guards shuts the providers down
+    let (dispatch, otel_guard) = full_otel(&cfg, None);
+    bench(c, &rt, "tracing_overhead/full_otel", Some(dispatch));
+    drop(otel_guard);
+
+    let (dispatch, otel_guard) = full_otel(&cfg, Some(Sampler::AlwaysOff));
+    bench(c, &rt, "tracing_overhead/sampled_off", Some(dispatch));
+    drop(otel_guard);
+}
+
+criterion_group!(benches, tracing_overhead);
+criterion_main!(benches);
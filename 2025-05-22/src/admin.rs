@@ -0,0 +1,420 @@
+//! Managing users from the command line, see `src/bin/users.rs`.
+//!
+//! The user store lives in the memory of the server, and the audit log is all it keeps on disk.
+//! So the CLI finds our [`Users`] in one of two places:
+//! - [`UsersClient`] goes through the HTTP API of a running server, with the same authentication
+//!   and validation as every other client, and every change it makes shows up in the audit log with
+//!   the principal of its API key.
+//! - [`LocalUsers`] replays the audit log into a store of its own, for when no server is running.
+//!   Its changes go to the audit log, too, and the server picks them up when it starts. While a
+//!   server runs, it holds the lock on the audit log and [`LocalUsers::open`] fails.
+
+use std::{convert::Infallible, path::Path, sync::Arc};
+
+use anyhow::{Context, bail};
+use axum::body::Bytes;
+use reqwest::{StatusCode, Url, header::CONTENT_TYPE};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    audit::{Actor, AuditLog, AuditQuery},
+    auth::API_KEY_HEADER,
+    business::{NewUser, ReadUser, User, UserManager, ValidationErrors},
+    http_client::BaseUrl,
+    import::{Format, ImportReport, Rows},
+};
+
+/// Where the CLI finds our users
+pub enum Users {
+    /// A running server
+    Server(UsersClient),
+    /// The audit log
+    Local(LocalUsers),
+}
+
+impl Users {
+    /// All users, or only the one named `name`
+    pub async fn list(&self, name: Option<&str>) -> anyhow::Result<Vec<User>> {
+        match self {
+            Users::Server(client) => client.list(name).await,
+            Users::Local(users) => users.list(name),
+        }
+    }
+
+    pub async fn get(&self, name: &str) -> anyhow::Result<Option<User>> {
+        Ok(self.list(Some(name)).await?.pop())
+    }
+
+    pub async fn add(&self, name: &str, email: Option<&str>) -> anyhow::Result<()> {
+        match self {
+            Users::Server(client) => client.add(name, email).await,
+            Users::Local(users) => users.add(name, email),
+        }
+    }
+
+    /// Deletes a user, returns `false` if there is no user with this name.
+    pub async fn delete(&self, name: &str) -> anyhow::Result<bool> {
+        match self {
+            Users::Server(client) => client.delete(name).await,
+            Users::Local(users) => users.delete(name),
+        }
+    }
+
+    /// Creates the users of a CSV or NDJSON file.
+    pub async fn import(&self, upload: Vec<u8>, format: Format) -> anyhow::Result<ImportReport> {
+        match self {
+            Users::Server(client) => client.import(upload, format).await,
+            Users::Local(users) => users.import(upload, format).await,
+        }
+    }
+}
+
+/// Talks to the `/users` routes of a running server.
+pub struct UsersClient {
+    http: reqwest::Client,
+    base_url: BaseUrl,
+    api_key: Option<String>,
+}
+
+impl UsersClient {
+    /// A client for the server at `base_url`, e.g. `http://localhost:5173`. Changes need an
+    /// `api_key`, reads work without one.
+    pub fn new(base_url: &str, api_key: Option<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base_url: BaseUrl::parse(base_url)?,
+            api_key,
+        })
+    }
+
+    /// All users, or only the one named `name`
+    pub async fn list(&self, name: Option<&str>) -> anyhow::Result<Vec<User>> {
+        let mut url = self.url(&["users"]);
+        if let Some(name) = name {
+            url.query_pairs_mut().append_pair("name", name);
+        }
+        let response = self.send(self.http.get(url)).await?;
+        response.json().await.context("unexpected response")
+    }
+
+    pub async fn get(&self, name: &str) -> anyhow::Result<Option<User>> {
+        Ok(self.list(Some(name)).await?.pop())
+    }
+
+    /// Creates a user. The request carries an idempotency key, so the server can retry it safely.
+    pub async fn add(&self, name: &str, email: Option<&str>) -> anyhow::Result<()> {
+        let mut url = self.url(&["users", "add", name]);
+        if let Some(email) = email {
+            url.query_pairs_mut().append_pair("email", email);
+        }
+        let request = self
+            .http
+            .post(url)
+            .header("idempotency-key", Uuid::new_v4().to_string());
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Deletes a user, returns `false` if there is no user with this name.
+    pub async fn delete(&self, name: &str) -> anyhow::Result<bool> {
+        let response = self
+            .send(self.http.delete(self.url(&["users", "delete", name])))
+            .await?;
+        Ok(response.status() == StatusCode::OK)
+    }
+
+    /// Uploads a CSV or NDJSON file to `/users/import`.
+    pub async fn import(&self, upload: Vec<u8>, format: Format) -> anyhow::Result<ImportReport> {
+        let content_type = match format {
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+        };
+        let request = self
+            .http
+            .post(self.url(&["users", "import"]))
+            .header(CONTENT_TYPE, content_type)
+            .body(upload);
+        let response = self.send_unchecked(request).await?;
+        // An aborted import still reports what happened to the rows before the error
+        if response.status() == StatusCode::BAD_REQUEST {
+            let body = response.text().await.unwrap_or_default();
+            match serde_json::from_str(&body) {
+                Ok(report) => return Ok(report),
+                Err(_) => bail!(
+                    "the server responded with {}: {body}",
+                    StatusCode::BAD_REQUEST
+                ),
+            }
+        }
+        let response = check(response).await?;
+        response.json().await.context("unexpected response")
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        self.base_url.join(segments)
+    }
+
+    /// Sends `request` with our API key and turns error responses into errors.
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        check(self.send_unchecked(request).await?).await
+    }
+
+    /// Sends `request` with our API key, whatever the response.
+    async fn send_unchecked(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let request = match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        };
+        request
+            .send()
+            .await
+            .with_context(|| format!("could not reach {}", self.base_url))
+    }
+}
+
+/// Turns error responses into errors.
+async fn check(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    match status {
+        StatusCode::UNAUTHORIZED => bail!("not authorized, is the API key right?"),
+        StatusCode::UNPROCESSABLE_ENTITY => match serde_json::from_str::<ValidationErrors>(&body) {
+            Ok(errors) => bail!("{errors}"),
+            Err(_) => bail!("invalid user: {body}"),
+        },
+        _ if body.is_empty() => bail!("the server responded with {status}"),
+        _ => bail!("the server responded with {status}: {body}"),
+    }
+}
+
+/// The users of one tenant, replayed from the audit log into a store of our own.
+pub struct LocalUsers {
+    store: UserManager,
+    actor: Actor,
+}
+
+impl LocalUsers {
+    /// Replays the entries of `tenant` from the audit log at `path`. Entries without a tenant
+    /// belong to `default_tenant`. Changes are audited as made by `principal`. Fails while a server
+    /// has the audit log open.
+    pub fn open(
+        path: &Path,
+        tenant: &str,
+        default_tenant: &str,
+        principal: &str,
+    ) -> anyhow::Result<Self> {
+        let audit_log = Arc::new(
+            AuditLog::open(path)
+                .context("could not open the audit log, use --server while a server runs")?,
+        );
+        let store = UserManager::with_failure_rates(0.0, 0.0).with_audit_log(audit_log.clone());
+        for entry in audit_log.query_all(AuditQuery::default())? {
+            if entry.tenant.as_deref().unwrap_or(default_tenant) == tenant {
+                store.replay(&entry)?;
+            }
+        }
+        Ok(Self {
+            store,
+            actor: Actor::new(principal, None).with_tenant(tenant),
+        })
+    }
+
+    pub fn list(&self, name: Option<&str>) -> anyhow::Result<Vec<User>> {
+        match name {
+            Some(name) => Ok(self
+                .store
+                .read_by_name(ReadUser::new(name))?
+                .into_iter()
+                .collect()),
+            None => self.store.list(),
+        }
+    }
+
+    pub fn add(&self, name: &str, email: Option<&str>) -> anyhow::Result<()> {
+        let new_user = NewUser::new(name);
+        let new_user = match email {
+            Some(email) => new_user.with_email(email),
+            None => new_user,
+        };
+        self.store.create(&self.actor, new_user)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .store
+            .delete(&self.actor, ReadUser::new(name))?
+            .is_some())
+    }
+
+    pub async fn import(&self, upload: Vec<u8>, format: Format) -> anyhow::Result<ImportReport> {
+        let chunks = futures_util::stream::iter([Ok::<_, Infallible>(Bytes::from(upload))]);
+        let mut rows = Rows::new(chunks, format);
+        let mut report = ImportReport::default();
+        loop {
+            match rows.next().await {
+                Ok(Some(row)) => {
+                    let result = row
+                        .user
+                        .and_then(|new_user| self.store.create(&self.actor, new_user));
+                    report.record(row.number, result);
+                }
+                Ok(None) => return Ok(report),
+                // Like the server, we keep what was imported before the error
+                Err(e) => {
+                    report.aborted = Some(format!("{e:#}"));
+                    return Ok(report);
+                }
+            }
+        }
+    }
+}
+
+/// A user as written by `users export`, one JSON object per line. `users import` reads it back.
+#[derive(Serialize)]
+pub struct ExportedUser<'a> {
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<&'a str>,
+}
+
+impl<'a> From<&'a User> for ExportedUser<'a> {
+    fn from(user: &'a User) -> Self {
+        Self {
+            name: &user.name,
+            email: user.email.as_deref(),
+        }
+    }
+}
+
+/// Renders `users` as a table with aligned columns for humans.
+pub fn users_table(users: &[User]) -> String {
+    let header = ["ID", "NAME", "EMAIL", "CREATED", "UPDATED"].map(str::to_owned);
+    let rows: Vec<[String; 5]> = users
+        .iter()
+        .map(|user| {
+            [
+                user.id.to_string(),
+                user.name.clone(),
+                user.email.clone().unwrap_or_else(|| "-".to_owned()),
+                format_timestamp(user.created_at),
+                format_timestamp(user.updated_at),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|column| column.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Formats milliseconds since the Unix epoch as UTC, e.g. `2025-05-22T18:30:00Z`.
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1_000;
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn formats_users_as_table() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_747_938_600_000), "2025-05-22T18:30:00Z");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29T00:00:00Z");
+
+        let users = [User {
+            id: Uuid::nil(),
+            name: "mert".to_owned(),
+            email: None,
+            created_at: 0,
+            updated_at: 1_747_938_600_000,
+        }];
+        assert_eq!(
+            users_table(&users),
+            "\
+ID                                    NAME  EMAIL  CREATED               UPDATED
+00000000-0000-0000-0000-000000000000  mert  -      1970-01-01T00:00:00Z  2025-05-22T18:30:00Z
+"
+        );
+    }
+
+    #[test]
+    fn encodes_path_segments() {
+        let client = UsersClient::new("http://localhost:5173/", None).unwrap();
+        assert_eq!(
+            client.url(&["users", "add", "Ferris O'Crab"]).as_str(),
+            "http://localhost:5173/users/add/Ferris%20O'Crab"
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_users_in_the_audit_log() {
+        let path = TempPath::new("audit.jsonl");
+        let users = LocalUsers::open(&path, "team-a", "default", "alice").unwrap();
+        users.add("mert", Some("mert@example.com")).unwrap();
+        users.add("ferris", None).unwrap();
+        assert!(users.delete("mert").unwrap());
+        let report = users
+            .import(b"name\nkim\n\n".to_vec(), Format::Csv)
+            .await
+            .unwrap();
+        assert_eq!(report.created, 1);
+        drop(users);
+
+        let reopened = LocalUsers::open(&path, "team-a", "default", "alice").unwrap();
+        let names: Vec<_> = reopened
+            .list(None)
+            .unwrap()
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        assert_eq!(names, ["ferris", "kim"]);
+        drop(reopened);
+        let other_tenant = LocalUsers::open(&path, "team-b", "default", "alice").unwrap();
+        assert!(other_tenant.list(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,521 @@
+//! Who changed which user, and when.
+//!
+//! Traces are sampled, redacted and thrown away after a while, so they are no record for
+//! compliance. The audit log is: every mutation of the [`UserManager`](crate::business::UserManager)
+//! appends one JSON line to a file that we never rewrite. Each entry names the [`Actor`] that made
+//! the change, holds the user before and after it, and carries the correlation and trace ID of the
+//! request, so we can look up everything else that happened in that request.
+//!
+//! Writing to disk takes a while, so a thread of its own writes the entries. Entries that arrive
+//! while it waits for the disk are written together, with a single `fsync` for all of them.
+//!
+//! `GET /users/audit` reads the entries back, filtered by user and time range, a page at a time.
+//! Reads do not wait for writes: a line that is still being written is left for the next read, and
+//! lines we cannot read, e.g. from a crash in the middle of a write, are skipped and counted. When
+//! we open the log after such a crash, we end its unfinished last line first, so the next entry
+//! gets a line of its own instead of being glued to the broken one.
+//!
+//! One process at a time writes the log: [`AuditLog::open`] takes an exclusive lock on the file
+//! and holds it until the log is dropped, so the `users` CLI cannot append to the log of a running
+//! server, whose store would never see those changes.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+};
+
+use anyhow::{Context, anyhow, bail};
+use opentelemetry::trace::TraceContextExt;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    business::{User, unix_millis},
+    events::UserEventKind,
+};
+
+/// Whoever makes a change to our users
+#[derive(Debug, Clone)]
+pub struct Actor {
+    /// The authenticated principal, or the job that made the change
+    pub name: String,
+    /// Correlation ID of the request that made the change
+    pub correlation_id: Option<String>,
+    /// The tenant whose user was changed
+    pub tenant: Option<String>,
+}
+
+impl Actor {
+    pub fn new(name: &str, correlation_id: Option<String>) -> Self {
+        Self {
+            name: name.to_owned(),
+            correlation_id,
+            tenant: None,
+        }
+    }
+
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_owned());
+        self
+    }
+}
+
+/// One change to one user
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Entries written before we had tenants have none
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub actor: String,
+    pub correlation_id: Option<String>,
+    /// Trace of the request that made the change
+    pub trace_id: Option<String>,
+    pub action: UserEventKind,
+    pub user_id: Uuid,
+    /// The user before the change, `None` if it was created
+    pub before: Option<User>,
+    /// The user after the change, `None` if it was deleted
+    pub after: Option<User>,
+}
+
+impl AuditEntry {
+    /// An entry for a change made now, within the current span.
+    pub fn new(
+        actor: &Actor,
+        action: UserEventKind,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> anyhow::Result<Self> {
+        let user_id = after
+            .or(before)
+            .map(|user| user.id)
+            .context("a change needs a user before or after it")?;
+        let span_context = tracing::Span::current()
+            .context()
+            .span()
+            .span_context()
+            .clone();
+
+        Ok(Self {
+            timestamp: unix_millis(),
+            tenant: actor.tenant.clone(),
+            actor: actor.name.clone(),
+            correlation_id: actor.correlation_id.clone(),
+            trace_id: span_context
+                .is_valid()
+                .then(|| span_context.trace_id().to_string()),
+            action,
+            user_id,
+            before: before.cloned(),
+            after: after.cloned(),
+        })
+    }
+}
+
+/// Which entries `GET /users/audit` returns
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only changes to this user
+    pub user_id: Option<Uuid>,
+    /// Only changes at or after this time, in milliseconds since the Unix epoch
+    pub from: Option<u64>,
+    /// Only changes before this time, in milliseconds since the Unix epoch
+    pub to: Option<u64>,
+    /// Where to continue, the `next` of the previous page
+    pub cursor: Option<u64>,
+    /// Most entries to return, at most 1000
+    pub limit: Option<usize>,
+    /// Only changes of this tenant. Set by the server to the tenant of the request.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+/// Most entries of a page of the audit log
+pub const MAX_PAGE_ENTRIES: usize = 1000;
+
+/// Entries of the audit log that match a query
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditPage {
+    /// Oldest first
+    pub entries: Vec<AuditEntry>,
+    /// `cursor` of the next page, `None` if this is the last one
+    pub next: Option<u64>,
+    /// Number of lines that could not be read
+    pub skipped: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user_id.is_none_or(|id| id == entry.user_id)
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|tenant| entry.tenant.as_ref() == Some(tenant))
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+/// An append-only JSON lines file of [`AuditEntry`]s
+pub struct AuditLog {
+    path: PathBuf,
+    /// Hands the entries to the thread that writes them
+    appends: Mutex<mpsc::Sender<Append>>,
+    /// Holds the lock on the log. A handle of its own, so the lock is gone once the log is dropped,
+    /// not once the writer thread notices.
+    _lock: File,
+}
+
+/// An entry on its way to disk
+struct Append {
+    line: Vec<u8>,
+    /// Told once the entry is on disk, or why it is not
+    written: mpsc::SyncSender<Result<(), Arc<std::io::Error>>>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path` for appending, creates it if it does not exist. An unfinished last
+    /// line is ended, see [`end_last_line`]. Fails if another log, e.g. that of a running server,
+    /// has the file open.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open audit log {}", path.display()))?;
+        let lock = File::open(&path)
+            .with_context(|| format!("could not open audit log {}", path.display()))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => bail!(
+                "audit log {} is in use by another process, e.g. a running server",
+                path.display()
+            ),
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(e)
+                    .with_context(|| format!("could not lock audit log {}", path.display()));
+            }
+        }
+        end_last_line(&mut file)
+            .with_context(|| format!("could not repair audit log {}", path.display()))?;
+
+        let (appends, received) = mpsc::channel();
+        // Stops once the log is dropped
+        std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || write_entries(file, received))
+            .context("could not start audit log writer")?;
+
+        Ok(Self {
+            path,
+            appends: Mutex::new(appends),
+            _lock: lock,
+        })
+    }
+
+    /// Appends `entry`. Once this returns, the entry is on disk.
+    pub fn append(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry).context("could not serialize audit entry")?;
+        line.push(b'\n');
+
+        let (written, on_disk) = mpsc::sync_channel(1);
+        self.appends
+            .lock()
+            .map_err(|_| anyhow!("audit log lock is poisoned"))?
+            .send(Append { line, written })
+            .map_err(|_| anyhow!("the audit log writer is gone"))?;
+        on_disk
+            .recv()
+            .map_err(|_| anyhow!("the audit log writer is gone"))?
+            .context("could not write audit entry")
+    }
+
+    /// A page of the entries matching `query`, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> anyhow::Result<AuditPage> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("could not open audit log {}", self.path.display()))?;
+        let mut offset = query.cursor.unwrap_or(0);
+        file.seek(SeekFrom::Start(offset))
+            .context("could not read audit log")?;
+        let limit = query
+            .limit
+            .unwrap_or(MAX_PAGE_ENTRIES)
+            .clamp(1, MAX_PAGE_ENTRIES);
+
+        let mut page = AuditPage {
+            entries: vec![],
+            next: None,
+            skipped: 0,
+        };
+        let mut reader = BufReader::new(file);
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .context("could not read audit log")?;
+            // A line without its line break is still being written
+            if line.last() != Some(&b'\n') {
+                break;
+            }
+            match serde_json::from_slice::<AuditEntry>(&line) {
+                Ok(entry) if query.matches(&entry) => {
+                    if page.entries.len() == limit {
+                        page.next = Some(offset);
+                        break;
+                    }
+                    page.entries.push(entry);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Skipping the line at byte {offset} of the audit log: {e}");
+                    page.skipped += 1;
+                }
+            }
+            offset += read as u64;
+        }
+        Ok(page)
+    }
+
+    /// All entries matching `query`, oldest first, however many pages they take.
+    pub fn query_all(&self, mut query: AuditQuery) -> anyhow::Result<Vec<AuditEntry>> {
+        let mut entries = vec![];
+        loop {
+            let page = self.query(&query)?;
+            if page.skipped > 0 {
+                warn!("Skipped {} unreadable lines of the audit log", page.skipped);
+            }
+            entries.extend(page.entries);
+            match page.next {
+                Some(next) => query.cursor = Some(next),
+                None => return Ok(entries),
+            }
+        }
+    }
+}
+
+/// Ends the last line of `file` if a crash left it without its line break. The broken line stays,
+/// reads skip it like any other line they cannot parse.
+fn end_last_line(file: &mut File) -> std::io::Result<()> {
+    if file.metadata()?.len() == 0 {
+        return Ok(());
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0];
+    file.read_exact(&mut last)?;
+    if last != *b"\n" {
+        warn!("The last line of the audit log is unfinished, probably from a crash");
+        file.write_all(b"\n")?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Writes the entries that arrive at `appends` to `file`, until the [`AuditLog`] is dropped.
+fn write_entries(mut file: File, appends: mpsc::Receiver<Append>) {
+    while let Ok(first) = appends.recv() {
+        // Whatever arrived while we waited for the disk goes in the same write
+        let batch: Vec<_> = std::iter::once(first).chain(appends.try_iter()).collect();
+        let lines: Vec<u8> = batch
+            .iter()
+            .flat_map(|append| &append.line)
+            .copied()
+            .collect();
+        let result = file
+            .write_all(&lines)
+            .and_then(|()| file.sync_data())
+            .map_err(Arc::new);
+        for append in batch {
+            // The appender waits for the answer, unless it panicked
+            let _ = append.written.send(result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    /// A log along with its path, which goes once the test drops it
+    fn temp_log() -> (AuditLog, TempPath) {
+        let path = TempPath::new("audit.jsonl");
+        (AuditLog::open(&*path).unwrap(), path)
+    }
+
+    #[test]
+    fn queries_entries_by_user_and_time() {
+        let (log, _path) = temp_log();
+        let actor = Actor::new("alice", Some("42".to_owned()));
+        let mert = User {
+            id: Uuid::new_v4(),
+            name: "mert".to_owned(),
+            email: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+        let ferris = User {
+            id: Uuid::new_v4(),
+            name: "ferris".to_owned(),
+            ..mert.clone()
+        };
+        let renamed = User {
+            name: "ferris the crab".to_owned(),
+            ..ferris.clone()
+        };
+
+        let mut entries = [
+            AuditEntry::new(&actor, UserEventKind::Created, None, Some(&mert)).unwrap(),
+            AuditEntry::new(&actor, UserEventKind::Created, None, Some(&ferris)).unwrap(),
+            AuditEntry::new(
+                &actor,
+                UserEventKind::Updated,
+                Some(&ferris),
+                Some(&renamed),
+            )
+            .unwrap(),
+        ];
+        for (timestamp, entry) in entries.iter_mut().enumerate() {
+            entry.timestamp = timestamp as u64;
+            log.append(entry).unwrap();
+        }
+
+        let of_ferris = log
+            .query(&AuditQuery {
+                user_id: Some(ferris.id),
+                ..AuditQuery::default()
+            })
+            .unwrap()
+            .entries;
+        assert_eq!(of_ferris.len(), 2);
+        assert_eq!(of_ferris[1].before.as_ref().unwrap().name, "ferris");
+        assert_eq!(of_ferris[1].after.as_ref().unwrap().name, "ferris the crab");
+        assert_eq!(of_ferris[1].actor, "alice");
+        assert_eq!(of_ferris[1].correlation_id.as_deref(), Some("42"));
+
+        let window = log
+            .query(&AuditQuery {
+                from: Some(1),
+                to: Some(2),
+                ..AuditQuery::default()
+            })
+            .unwrap()
+            .entries;
+        assert_eq!(window.len(), 1);
+        assert_eq!(window[0].user_id, ferris.id);
+        assert_eq!(window[0].action, UserEventKind::Created);
+    }
+
+    #[test]
+    fn pages_through_entries_and_skips_broken_lines() {
+        let (log, _path) = temp_log();
+        let actor = Actor::new("alice", None);
+        let users: Vec<_> = (0..3)
+            .map(|n| User {
+                id: Uuid::new_v4(),
+                name: format!("user-{n}"),
+                email: None,
+                created_at: 0,
+                updated_at: 0,
+            })
+            .collect();
+        for (n, user) in users.iter().enumerate() {
+            log.append(&AuditEntry::new(&actor, UserEventKind::Created, None, Some(user)).unwrap())
+                .unwrap();
+            if n == 0 {
+                // As if we crashed in the middle of a write
+                let mut file = OpenOptions::new().append(true).open(&log.path).unwrap();
+                file.write_all(b"{\"timestamp\":\n").unwrap();
+            }
+        }
+        // Still being written, so not there yet
+        let mut file = OpenOptions::new().append(true).open(&log.path).unwrap();
+        file.write_all(b"{\"timestamp\":").unwrap();
+
+        let mut names = vec![];
+        let mut query = AuditQuery {
+            limit: Some(2),
+            ..AuditQuery::default()
+        };
+        let mut skipped = 0;
+        loop {
+            let page = log.query(&query).unwrap();
+            names.extend(
+                page.entries
+                    .iter()
+                    .map(|entry| entry.after.clone().unwrap().name),
+            );
+            skipped += page.skipped;
+            match page.next {
+                Some(next) => query.cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(names, ["user-0", "user-1", "user-2"]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn appends_after_an_unfinished_line() {
+        let path = TempPath::new("audit.jsonl");
+        let actor = Actor::new("alice", None);
+        let user = |name: &str| User {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            email: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+
+        let log = AuditLog::open(&*path).unwrap();
+        log.append(
+            &AuditEntry::new(&actor, UserEventKind::Created, None, Some(&user("mert"))).unwrap(),
+        )
+        .unwrap();
+        drop(log);
+        // We crashed in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&*path).unwrap();
+        file.write_all(b"{\"timestamp\":").unwrap();
+
+        let log = AuditLog::open(&*path).unwrap();
+        log.append(
+            &AuditEntry::new(&actor, UserEventKind::Created, None, Some(&user("ferris"))).unwrap(),
+        )
+        .unwrap();
+
+        let page = log.query(&AuditQuery::default()).unwrap();
+        let names: Vec<_> = page
+            .entries
+            .iter()
+            .map(|entry| entry.after.clone().unwrap().name)
+            .collect();
+        assert_eq!(names, ["mert", "ferris"]);
+        assert_eq!(page.skipped, 1);
+    }
+
+    #[test]
+    fn opens_a_log_once_at_a_time() {
+        let path = TempPath::new("audit.jsonl");
+        let log = AuditLog::open(&*path).unwrap();
+
+        let e = AuditLog::open(&*path).err().unwrap();
+        assert!(e.to_string().contains("in use"), "{e:#}");
+
+        drop(log);
+        AuditLog::open(&*path).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! Authentication of incoming requests.
+//!
+//! Remember the flow from the README: "Request comes in → User is authenticated → Data is queried".
+//! This module is the second step. It runs in its own `authenticate` span, which makes it easy to
+//! see in Grafana who sent a request and how much time we spent finding that out.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, bail};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tracing::{debug, field, info_span, warn};
+
+use crate::cfg::AuthCfg;
+
+/// Header that carries static API keys
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Whoever sent the request. Authenticated requests carry this as a request extension.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    /// Which provider authenticated the principal
+    pub method: &'static str,
+    /// The tenant the credentials belong to, `*` if they may act for every tenant, see
+    /// `tenant.rs`
+    pub tenant: Option<String>,
+}
+
+/// A source of credentials. Providers look at the request headers and return
+/// - `Ok(None)` if the request does not carry credentials this provider understands,
+/// - `Ok(Some(principal))` if it does and they are valid,
+/// - an error if it does and they are invalid.
+pub trait AuthProvider: Send + Sync {
+    fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<Option<Principal>>;
+}
+
+/// Static API keys sent in the `x-api-key` header.
+pub struct ApiKeys {
+    /// Maps a key to the principal it authenticates, `principal@tenant` ties it to a tenant
+    keys: HashMap<String, String>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: HashMap<String, String>) -> Self {
+        Self { keys }
+    }
+}
+
+impl AuthProvider for ApiKeys {
+    fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<Option<Principal>> {
+        let Some(key) = headers.get(API_KEY_HEADER) else {
+            return Ok(None);
+        };
+        let key = key.to_str().context("API key is not valid ASCII")?;
+
+        let Some(principal) = self.keys.get(key) else {
+            bail!("unknown API key");
+        };
+        let (name, tenant) = match principal.split_once('@') {
+            Some((name, tenant)) => (name, Some(tenant.to_owned())),
+            None => (principal.as_str(), None),
+        };
+        Ok(Some(Principal {
+            name: name.to_owned(),
+            method: "api_key",
+            tenant,
+        }))
+    }
+}
+
+/// JWTs sent as `Authorization: Bearer <token>`, validated against a locally configured key.
+pub struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+    method: &'static str,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    tenant: Option<String>,
+}
+
+impl Jwt {
+    pub fn hs256(secret: &str) -> Self {
+        Self {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation: Validation::new(Algorithm::HS256),
+            method: "jwt_hs256",
+        }
+    }
+
+    pub fn rs256(public_key_pem: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            key: DecodingKey::from_rsa_pem(public_key_pem.as_bytes())
+                .context("invalid RS256 public key")?,
+            validation: Validation::new(Algorithm::RS256),
+            method: "jwt_rs256",
+        })
+    }
+}
+
+impl AuthProvider for Jwt {
+    fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<Option<Principal>> {
+        let Some(token) = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return Ok(None);
+        };
+
+        // Leave tokens signed with other algorithms to the other providers
+        let header = jsonwebtoken::decode_header(token).context("malformed JWT")?;
+        if !self.validation.algorithms.contains(&header.alg) {
+            return Ok(None);
+        }
+
+        let token = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .context("invalid JWT")?;
+
+        Ok(Some(Principal {
+            name: token.claims.sub,
+            method: self.method,
+            tenant: token.claims.tenant,
+        }))
+    }
+}
+
+/// Asks its providers in order. The first one that recognizes the credentials decides.
+#[derive(Default)]
+pub struct Authenticator {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl Authenticator {
+    pub fn from_cfg(cfg: &AuthCfg) -> anyhow::Result<Self> {
+        let mut authenticator = Self::default();
+        if !cfg.api_keys.is_empty() {
+            authenticator = authenticator.with_provider(ApiKeys::new(cfg.api_keys.clone()));
+        }
+        if let Some(secret) = &cfg.jwt_hs256_secret {
+            authenticator = authenticator.with_provider(Jwt::hs256(secret));
+        }
+        if let Some(pem) = &cfg.jwt_rs256_public_key {
+            authenticator = authenticator.with_provider(Jwt::rs256(pem)?);
+        }
+        Ok(authenticator)
+    }
+
+    pub fn with_provider(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.providers.push(Box::new(provider));
+        self
+    }
+
+    /// Credentials that none of the providers recognizes, e.g. a JWT signed with an algorithm we
+    /// have no key for, are invalid, not absent.
+    pub fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<Option<Principal>> {
+        for provider in &self.providers {
+            if let Some(principal) = provider.authenticate(headers)? {
+                return Ok(Some(principal));
+            }
+        }
+        if headers.contains_key(API_KEY_HEADER) || headers.contains_key(AUTHORIZATION) {
+            bail!("unsupported credentials");
+        }
+        Ok(None)
+    }
+}
+
+/// Middleware that authenticates requests and rejects unauthenticated mutations.
+///
+/// Read requests without credentials are let through anonymously, requests with invalid
+/// credentials are always rejected.
+pub async fn authenticate(
+    State(authenticator): State<Arc<Authenticator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let span = info_span!(
+        "authenticate",
+        principal = field::Empty,
+        auth.method = field::Empty
+    );
+
+    // Only the check itself happens in the `authenticate` span. The handler runs afterwards, so
+    // its spans become siblings and not children of this step.
+    let principal = span.in_scope(|| match authenticator.authenticate(request.headers()) {
+        Ok(Some(principal)) => {
+            span.record("principal", principal.name.as_str());
+            span.record("auth.method", principal.method);
+            debug!("Authenticated request");
+            Ok(Some(principal))
+        }
+        Ok(None) if !request.method().is_safe() => {
+            warn!("Rejecting unauthenticated {} request", request.method());
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        Ok(None) => {
+            debug!("Anonymous request");
+            Ok(None)
+        }
+        Err(e) => {
+            warn!("Rejecting request with invalid credentials: {e:#}");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    });
+
+    match principal {
+        Ok(Some(principal)) => {
+            request.extensions_mut().insert(principal);
+        }
+        Ok(None) => {}
+        Err(status) => return status.into_response(),
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        Router,
+        body::Body,
+        middleware,
+        routing::{get, post},
+    };
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        exp: u64,
+        tenant: &'a str,
+    }
+
+    fn authenticator() -> Arc<Authenticator> {
+        let keys = HashMap::from([
+            ("secret-key".to_owned(), "alice".to_owned()),
+            ("team-key".to_owned(), "carol@team-a".to_owned()),
+        ]);
+        Arc::new(
+            Authenticator::default()
+                .with_provider(ApiKeys::new(keys))
+                .with_provider(Jwt::hs256("jwt-secret")),
+        )
+    }
+
+    fn token(secret: &str) -> String {
+        let claims = TestClaims {
+            sub: "bob",
+            exp: jsonwebtoken::get_current_timestamp() + 60,
+            tenant: "team-b",
+        };
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    async fn status(request: Request<Body>) -> StatusCode {
+        let app = Router::new()
+            .route("/users/read", get(|| async { "ok" }))
+            .route("/users/add", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                authenticator(),
+                authenticate,
+            ));
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn api_keys_and_jwts_authenticate() {
+        let authenticator = authenticator();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "secret-key".parse().unwrap());
+        let principal = authenticator.authenticate(&headers).unwrap().unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.tenant, None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "team-key".parse().unwrap());
+        let principal = authenticator.authenticate(&headers).unwrap().unwrap();
+        assert_eq!(principal.name, "carol");
+        assert_eq!(principal.tenant.as_deref(), Some("team-a"));
+
+        let mut headers = HeaderMap::new();
+        let bearer = format!("Bearer {}", token("jwt-secret"));
+        headers.insert(AUTHORIZATION, bearer.parse().unwrap());
+        let principal = authenticator.authenticate(&headers).unwrap().unwrap();
+        assert_eq!(principal.name, "bob");
+        assert_eq!(principal.method, "jwt_hs256");
+        assert_eq!(principal.tenant.as_deref(), Some("team-b"));
+    }
+
+    #[test]
+    fn invalid_credentials_are_errors() {
+        let authenticator = authenticator();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "wrong-key".parse().unwrap());
+        assert!(authenticator.authenticate(&headers).is_err());
+
+        let mut headers = HeaderMap::new();
+        let bearer = format!("Bearer {}", token("wrong-secret"));
+        headers.insert(AUTHORIZATION, bearer.parse().unwrap());
+        assert!(authenticator.authenticate(&headers).is_err());
+
+        // Signed with an algorithm none of the providers accepts
+        let claims = TestClaims {
+            sub: "mallory",
+            exp: jsonwebtoken::get_current_timestamp() + 60,
+            tenant: "team-b",
+        };
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS512),
+            &claims,
+            &EncodingKey::from_secret(b"jwt-secret"),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        assert!(authenticator.authenticate(&headers).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        assert!(authenticator.authenticate(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn only_mutations_require_authentication() {
+        let read = Request::get("/users/read").body(Body::empty()).unwrap();
+        assert_eq!(status(read).await, StatusCode::OK);
+
+        let add = Request::post("/users/add").body(Body::empty()).unwrap();
+        assert_eq!(status(add).await, StatusCode::UNAUTHORIZED);
+
+        let add = Request::post("/users/add")
+            .header(API_KEY_HEADER, "secret-key")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(add).await, StatusCode::OK);
+
+        // Invalid credentials are no excuse to read anonymously
+        let read = Request::get("/users/read")
+            .header(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(read).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,43 @@
+//! Receives OTLP on the default ports and prints what it gets, see `src/receiver.rs`.
+//!
+//! ```sh
+//! cargo run --bin otlp_receiver -- [--json] [--grpc-port 4317] [--http-port 4318]
+//! ```
+
+use anyhow::Context;
+use guided_telemetry::receiver::{Echo, Receiver};
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut echo = Echo::Pretty;
+    let mut grpc_port = 4317;
+    let mut http_port = 4318;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => echo = Echo::Json,
+            "--grpc-port" => grpc_port = port(args.next())?,
+            "--http-port" => http_port = port(args.next())?,
+            _ => anyhow::bail!(
+                "unknown argument `{arg}`, expected `--json`, `--grpc-port` or `--http-port`"
+            ),
+        }
+    }
+
+    let grpc = TcpListener::bind(("::", grpc_port)).await?;
+    let http = TcpListener::bind(("::", http_port)).await?;
+    eprintln!(
+        "OTLP receiver listening on port {grpc_port} (gRPC) and {http_port} (HTTP), \
+         see http://localhost:{http_port}/traces and http://localhost:{http_port}/telemetry"
+    );
+
+    Receiver::new().with_echo(echo).serve(grpc, http).await
+}
+
+fn port(arg: Option<String>) -> anyhow::Result<u16> {
+    arg.context("expected a port")?
+        .parse()
+        .context("could not parse port")
+}
@@ -0,0 +1,173 @@
+//! Manage the users of a running server, or of the audit log while no server runs, see
+//! `src/admin.rs`.
+//!
+//! ```sh
+//! cargo run --bin users -- [--server http://localhost:5173] [--api-key KEY] [--json] <command>
+//! cargo run --bin users -- --audit-log audit.jsonl [--tenant TENANT] [--json] <command>
+//! ```
+//!
+//! Commands: `list`, `get <name>`, `add <name> [--email <email>]`, `delete <name>`, `export` (NDJSON
+//! on stdout) and `import <file>` (`.csv` or NDJSON, `-` for NDJSON on stdin). The server and API
+//! key can also be set as `USERS_SERVER` and `USERS_API_KEY`. With `--audit-log`, the CLI works on
+//! the users of `--tenant` (`TENANT_DEFAULT` by default) and records its changes as made by `$USER`.
+//!
+//! Data goes to stdout, everything else to stderr. The exit code is `0` on success, `2` if `get`
+//! or `delete` found no user and `1` on every other error, including imports with failed rows.
+
+use std::{io::Read, process::ExitCode};
+
+use anyhow::{Context, bail};
+use guided_telemetry::{
+    admin::{ExportedUser, LocalUsers, Users, UsersClient, users_table},
+    import::Format,
+};
+
+const USAGE: &str = "usage: users [--server <url>] [--api-key <key>] [--audit-log <file>] [--tenant <tenant>] \
+                     [--json] <list | get <name> | add <name> [--email <email>] | delete <name> | export | import <file>>";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> anyhow::Result<ExitCode> {
+    dotenvy::dotenv().ok();
+    let mut server =
+        std::env::var("USERS_SERVER").unwrap_or_else(|_| "http://localhost:5173".to_owned());
+    let mut api_key = std::env::var("USERS_API_KEY").ok();
+    let default_tenant = std::env::var("TENANT_DEFAULT").unwrap_or_else(|_| "default".to_owned());
+    let mut audit_log = None;
+    let mut tenant = None;
+    let mut json = false;
+    let mut email = None;
+    let mut positional = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().context("expected a URL after --server")?,
+            "--api-key" => api_key = Some(args.next().context("expected a key after --api-key")?),
+            "--email" => email = Some(args.next().context("expected an address after --email")?),
+            "--audit-log" => {
+                audit_log = Some(args.next().context("expected a file after --audit-log")?);
+            }
+            "--tenant" => tenant = Some(args.next().context("expected a tenant after --tenant")?),
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ if arg.starts_with("--") => bail!("unknown option `{arg}`\n{USAGE}"),
+            _ => positional.push(arg),
+        }
+    }
+
+    let client = match audit_log {
+        Some(path) => {
+            let principal = std::env::var("USER").unwrap_or_else(|_| "users-cli".to_owned());
+            let tenant = tenant.as_deref().unwrap_or(&default_tenant);
+            Users::Local(LocalUsers::open(
+                path.as_ref(),
+                tenant,
+                &default_tenant,
+                &principal,
+            )?)
+        }
+        None if tenant.is_some() => bail!("--tenant needs --audit-log\n{USAGE}"),
+        None => Users::Server(UsersClient::new(&server, api_key)?),
+    };
+    let not_found = |name: &str| {
+        eprintln!("no user named `{name}`");
+        Ok(ExitCode::from(2))
+    };
+
+    match positional
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["list"] => {
+            let users = client.list(None).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&users)?);
+            } else {
+                print!("{}", users_table(&users));
+            }
+        }
+        ["get", name] => {
+            let Some(user) = client.get(name).await? else {
+                return not_found(name);
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&user)?);
+            } else {
+                print!("{}", users_table(&[user]));
+            }
+        }
+        ["add", name] => {
+            client.add(name, email.as_deref()).await?;
+            eprintln!("created `{name}`");
+        }
+        ["delete", name] => {
+            if !client.delete(name).await? {
+                return not_found(name);
+            }
+            eprintln!("deleted `{name}`");
+        }
+        ["export"] => {
+            for user in client.list(None).await? {
+                println!("{}", serde_json::to_string(&ExportedUser::from(&user))?);
+            }
+        }
+        ["import", path] => {
+            let (upload, format) = read_upload(path)?;
+            let report = client.import(upload, format).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for row in report.rows.iter().filter(|row| row.error.is_some()) {
+                    println!(
+                        "row {}: {}",
+                        row.row,
+                        row.error.as_deref().unwrap_or_default()
+                    );
+                }
+                println!("created {}, failed {}", report.created, report.failed);
+                if let Some(reason) = &report.aborted {
+                    println!("aborted: {reason}");
+                }
+            }
+            if report.failed > 0 || report.aborted.is_some() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        _ => bail!("{USAGE}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads the file to import, `-` being stdin. Files ending in `.csv` are CSV, all others NDJSON.
+fn read_upload(path: &str) -> anyhow::Result<(Vec<u8>, Format)> {
+    let mut upload = vec![];
+    if path == "-" {
+        std::io::stdin()
+            .read_to_end(&mut upload)
+            .context("could not read stdin")?;
+        return Ok((upload, Format::Ndjson));
+    }
+
+    upload = std::fs::read(path).with_context(|| format!("could not read {path}"))?;
+    let format = if path.to_ascii_lowercase().ends_with(".csv") {
+        Format::Csv
+    } else {
+        Format::Ndjson
+    };
+    Ok((upload, format))
+}
@@ -1,75 +1,444 @@
 //! This module contains some crazy business logic
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::bail;
+use anyhow::{Context, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+use crate::{
+    audit::{Actor, AuditEntry, AuditLog},
+    events::{UserEventKind, UserEvents},
+};
+
+/// Longest name we accept, in characters
+pub const MAX_NAME_CHARS: usize = 64;
+/// Longest email address we accept, in bytes, as in RFC 5321
+pub const MAX_EMAIL_BYTES: usize = 254;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub id: Uuid,
     pub name: String,
+    pub email: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+    /// Milliseconds since the Unix epoch, the same as `created_at` until the first change
+    pub updated_at: u64,
 }
 
+#[derive(Debug, Clone)]
 pub struct NewUser {
     name: String,
+    email: Option<String>,
+}
+
+/// Why a field of a [`NewUser`] was rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    /// Stable, machine readable reason: `empty`, `too_long`, `invalid_characters` or `invalid_email`
+    pub code: String,
+    pub message: String,
+}
+
+/// All problems of a [`NewUser`], returned by [`NewUser::validate`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid user: ")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Returned when a store already holds as many users as it may
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub max_users: usize,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the quota of {} users is used up", self.max_users)
+    }
 }
 
+impl std::error::Error for QuotaExceeded {}
+
+/// Returned when a user is created with, or renamed to, the name of another user
+#[derive(Debug)]
+pub struct NameTaken {
+    pub name: String,
+}
+
+// The name is personal data, and messages are only redacted where they repeat a field of their
+// own record, so it stays out of the message
+impl fmt::Display for NameTaken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("there already is a user with this name")
+    }
+}
+
+impl std::error::Error for NameTaken {}
+
 pub struct ReadUser<'a> {
     name: &'a str,
 }
 
+#[derive(Default)]
+struct Storage {
+    users: HashMap<Uuid, User>,
+    /// Idempotency keys of successful creates and the IDs of the users they created
+    idempotency_keys: HashMap<String, Uuid>,
+}
+
+/// The user store is synchronized internally: reads only take a shared lock, so any number of
+/// requests can read in parallel. Changes happen one at a time, and only take the exclusive lock
+/// to apply the change, so reads do not wait while a change is written to disk.
+///
+/// Every change is written to the [`AuditLog`] first, if there is one, and published to
+/// [`UserManager::events`] once it is applied. A change we cannot audit does not happen. Changes
+/// block until their entry is on disk, so async code makes them on a blocking thread.
 pub struct UserManager {
-    pub storage: HashMap<Uuid, User>,
+    storage: RwLock<Storage>,
+    /// Held for the whole of a change, see [`UserManager::lock_writes`]
+    writes: Mutex<()>,
+    events: UserEvents,
+    audit_log: Option<Arc<AuditLog>>,
+    /// Quota of users, `None` for no limit
+    max_users: Option<usize>,
+    /// Chance of a failing `create`
+    create_failure_rate: f64,
+    /// Chance of a failing read
+    read_failure_rate: f64,
 }
 
 impl UserManager {
     pub fn new() -> Self {
+        Self::with_failure_rates(0.2, 0.5)
+    }
+
+    /// A store with custom failure rates. Setting both to `0.0` gives you a store that never fails.
+    pub fn with_failure_rates(create_failure_rate: f64, read_failure_rate: f64) -> Self {
         Self {
-            storage: HashMap::new(),
+            storage: RwLock::new(Storage::default()),
+            writes: Mutex::new(()),
+            events: UserEvents::new(),
+            audit_log: None,
+            max_users: None,
+            create_failure_rate,
+            read_failure_rate,
         }
     }
 
+    /// Records every change in `audit_log`, which may be shared with other stores
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Rejects creates with [`QuotaExceeded`] once the store holds `max_users`
+    pub fn with_max_users(mut self, max_users: usize) -> Self {
+        self.max_users = Some(max_users);
+        self
+    }
+
+    /// The changes to our users
+    pub fn events(&self) -> &UserEvents {
+        &self.events
+    }
+
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_deref()
+    }
+
+    /// Number of users in the store. Unlike the other reads, this one never fails: we use it for
+    /// metrics, not for business.
+    pub fn user_count(&self) -> usize {
+        let storage = self.storage.read().unwrap_or_else(|e| e.into_inner());
+        storage.users.len()
+    }
+
+    /// Fails with [`QuotaExceeded`] if there is no room for another user.
+    pub fn check_quota(&self) -> Result<(), QuotaExceeded> {
+        let storage = self.storage.read().unwrap_or_else(|e| e.into_inner());
+        storage.check_quota(self.max_users)
+    }
+
     /// Add user with random chance of failure :-)
-    pub fn create(&mut self, new_user: NewUser) -> anyhow::Result<Uuid> {
-        let success = rand::random_bool(0.8);
-        if !success {
+    ///
+    /// Invalid users are rejected with [`ValidationErrors`], names of other users with
+    /// [`NameTaken`].
+    pub fn create(&self, actor: &Actor, new_user: NewUser) -> anyhow::Result<Uuid> {
+        new_user.validate()?;
+        if rand::random_bool(self.create_failure_rate) {
             bail!("Error, lost connection to database or something");
         }
 
+        let _writing = self.lock_writes()?;
+        {
+            let storage = self.read_storage()?;
+            storage.check_quota(self.max_users)?;
+            storage.check_name(&new_user.name, None)?;
+        }
         let user = User::with_auto_id(new_user);
         let id = user.id;
-        self.storage.insert(user.id, user);
+        self.audit(actor, UserEventKind::Created, None, Some(&user))?;
+        self.write_storage()?.users.insert(user.id, user.clone());
+        self.events.publish(UserEventKind::Created, &user);
 
         Ok(id)
     }
 
-    pub fn read_by_name(&mut self, user: ReadUser) -> anyhow::Result<Option<User>> {
-        let success = rand::random_bool(0.5);
-        if !success {
+    /// Like [`UserManager::create`], but creates at most one user per `idempotency_key`. Repeating
+    /// a create with the same key returns the ID of the user created the first time, which makes
+    /// it safe to retry.
+    pub fn create_idempotent(
+        &self,
+        actor: &Actor,
+        idempotency_key: &str,
+        new_user: NewUser,
+    ) -> anyhow::Result<Uuid> {
+        new_user.validate()?;
+        if rand::random_bool(self.create_failure_rate) {
+            bail!("Error, lost connection to database or something");
+        }
+
+        let _writing = self.lock_writes()?;
+        {
+            let storage = self.read_storage()?;
+            if let Some(id) = storage.idempotency_keys.get(idempotency_key) {
+                return Ok(*id);
+            }
+            storage.check_quota(self.max_users)?;
+            storage.check_name(&new_user.name, None)?;
+        }
+
+        let user = User::with_auto_id(new_user);
+        let id = user.id;
+        self.audit(actor, UserEventKind::Created, None, Some(&user))?;
+        {
+            let mut storage = self.write_storage()?;
+            storage.users.insert(user.id, user.clone());
+            storage
+                .idempotency_keys
+                .insert(idempotency_key.to_owned(), id);
+        }
+        self.events.publish(UserEventKind::Created, &user);
+
+        Ok(id)
+    }
+
+    pub fn read_by_name(&self, user: ReadUser) -> anyhow::Result<Option<User>> {
+        if rand::random_bool(self.read_failure_rate) {
             bail!("Read error, lost connection to database or something");
         }
 
-        let name = user.name;
-        let users: Vec<_> = self
-            .storage
+        let storage = self.read_storage()?;
+        Ok(storage
+            .find(user.name)?
+            .map(|id| storage.users[&id].clone()))
+    }
+
+    pub fn read_by_id(&self, id: Uuid) -> anyhow::Result<Option<User>> {
+        if rand::random_bool(self.read_failure_rate) {
+            bail!("Read error, lost connection to database or something");
+        }
+
+        Ok(self.read_storage()?.users.get(&id).cloned())
+    }
+
+    /// All users, ordered by name
+    pub fn list(&self) -> anyhow::Result<Vec<User>> {
+        if rand::random_bool(self.read_failure_rate) {
+            bail!("Read error, lost connection to database or something");
+        }
+
+        let mut users: Vec<_> = self.read_storage()?.users.values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(users)
+    }
+
+    /// Renames a user, returns the renamed user or `None` if there is no user with this name.
+    /// Only the name of `new_user` is used, the email address stays as it is. Names stay unique:
+    /// renaming a user to the name of another one fails with [`NameTaken`].
+    pub fn update(
+        &self,
+        actor: &Actor,
+        user: ReadUser,
+        new_user: NewUser,
+    ) -> anyhow::Result<Option<User>> {
+        new_user.validate()?;
+        if rand::random_bool(self.create_failure_rate) {
+            bail!("Error, lost connection to database or something");
+        }
+
+        let _writing = self.lock_writes()?;
+        let user = {
+            let storage = self.read_storage()?;
+            let Some(id) = storage.find(user.name)? else {
+                return Ok(None);
+            };
+            storage.check_name(&new_user.name, Some(id))?;
+            let Some(user) = storage.users.get(&id) else {
+                return Ok(None);
+            };
+            user.clone()
+        };
+        let renamed = User {
+            name: new_user.name,
+            updated_at: unix_millis(),
+            ..user.clone()
+        };
+        self.audit(actor, UserEventKind::Updated, Some(&user), Some(&renamed))?;
+        self.write_storage()?
+            .users
+            .insert(renamed.id, renamed.clone());
+        self.events.publish(UserEventKind::Updated, &renamed);
+
+        Ok(Some(renamed))
+    }
+
+    /// Deletes a user, returns the deleted user or `None` if there is no user with this name.
+    pub fn delete(&self, actor: &Actor, user: ReadUser) -> anyhow::Result<Option<User>> {
+        if rand::random_bool(self.create_failure_rate) {
+            bail!("Error, lost connection to database or something");
+        }
+
+        let _writing = self.lock_writes()?;
+        let user = {
+            let storage = self.read_storage()?;
+            let Some(id) = storage.find(user.name)? else {
+                return Ok(None);
+            };
+            let Some(user) = storage.users.get(&id) else {
+                return Ok(None);
+            };
+            user.clone()
+        };
+        self.audit(actor, UserEventKind::Deleted, Some(&user), None)?;
+        self.write_storage()?.users.remove(&user.id);
+        self.events.publish(UserEventKind::Deleted, &user);
+
+        Ok(Some(user))
+    }
+
+    /// Applies a change read back from the audit log, without auditing or publishing it again.
+    /// Replaying all entries of a store, oldest first, restores its users.
+    pub fn replay(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut storage = self.write_storage()?;
+        match &entry.after {
+            Some(user) => storage.users.insert(user.id, user.clone()),
+            None => storage.users.remove(&entry.user_id),
+        };
+        Ok(())
+    }
+
+    /// Writes a change to the audit log. Call it before applying the change, so a change that
+    /// cannot be audited is not applied.
+    fn audit(
+        &self,
+        actor: &Actor,
+        kind: UserEventKind,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> anyhow::Result<()> {
+        if let Some(audit_log) = &self.audit_log {
+            let entry = AuditEntry::new(actor, kind, before, after)?;
+            audit_log.append(&entry).context("could not audit change")?;
+        }
+        Ok(())
+    }
+
+    /// Taken for the whole of a change, so changes happen one at a time and in the order of
+    /// their audit entries and events
+    fn lock_writes(&self) -> anyhow::Result<MutexGuard<'_, ()>> {
+        self.writes
+            .lock()
+            .map_err(|_| anyhow!("user writes lock is poisoned"))
+    }
+
+    fn read_storage(&self) -> anyhow::Result<RwLockReadGuard<'_, Storage>> {
+        self.storage
+            .read()
+            .map_err(|_| anyhow!("user storage lock is poisoned"))
+    }
+
+    fn write_storage(&self) -> anyhow::Result<RwLockWriteGuard<'_, Storage>> {
+        self.storage
+            .write()
+            .map_err(|_| anyhow!("user storage lock is poisoned"))
+    }
+}
+
+impl Storage {
+    fn check_quota(&self, max_users: Option<usize>) -> Result<(), QuotaExceeded> {
+        match max_users {
+            Some(max_users) if self.users.len() >= max_users => Err(QuotaExceeded { max_users }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with [`NameTaken`] if a user other than `renamed` is called `name`
+    fn check_name(&self, name: &str, renamed: Option<Uuid>) -> anyhow::Result<()> {
+        if self.find(name)?.is_some_and(|id| Some(id) != renamed) {
+            bail!(NameTaken {
+                name: name.to_owned()
+            });
+        }
+        Ok(())
+    }
+
+    /// The ID of the user with this name
+    fn find(&self, name: &str) -> anyhow::Result<Option<Uuid>> {
+        let ids: Vec<_> = self
+            .users
             .iter()
             .filter(|(_id, user)| user.name.as_str() == name)
+            .map(|(id, _user)| *id)
             .collect();
 
-        if users.len() > 1 {
-            bail!("corrupt database - more than one user with name {name} in it")
+        if ids.len() > 1 {
+            bail!("corrupt database - more than one user with the same name in it")
         }
 
-        Ok(users.first().map(|(_id, user)| (*user).clone()))
+        Ok(ids.first().copied())
+    }
+}
+
+impl Default for UserManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl User {
     fn with_auto_id(new_user: NewUser) -> Self {
+        let now = unix_millis();
         User {
             id: Uuid::new_v4(),
             name: new_user.name,
+            email: new_user.email,
+            created_at: now,
+            updated_at: now,
         }
     }
 }
@@ -78,8 +447,103 @@ impl NewUser {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_owned(),
+            email: None,
         }
     }
+
+    pub fn with_email(mut self, email: &str) -> Self {
+        self.email = Some(email.to_owned());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    /// Checks every field and returns all problems at once:
+    /// - the name must not be empty or longer than [`MAX_NAME_CHARS`], and may only contain
+    ///   letters, digits, spaces and `-`, `_`, `.`, `'`
+    /// - the email address, if there is one, must look like `local@domain.tld` and must not be
+    ///   longer than [`MAX_EMAIL_BYTES`]
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = vec![];
+        let mut reject = |field: &str, code: &str, message: &str| {
+            errors.push(FieldError {
+                field: field.to_owned(),
+                code: code.to_owned(),
+                message: message.to_owned(),
+            })
+        };
+
+        if self.name.trim().is_empty() {
+            reject("name", "empty", "must not be empty");
+        } else if self.name.chars().count() > MAX_NAME_CHARS {
+            reject(
+                "name",
+                "too_long",
+                &format!("must not be longer than {MAX_NAME_CHARS} characters"),
+            );
+        } else if !self
+            .name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | '\''))
+        {
+            reject(
+                "name",
+                "invalid_characters",
+                "may only contain letters, digits, spaces and - _ . '",
+            );
+        }
+
+        if let Some(email) = &self.email {
+            if email.len() > MAX_EMAIL_BYTES {
+                reject(
+                    "email",
+                    "too_long",
+                    &format!("must not be longer than {MAX_EMAIL_BYTES} bytes"),
+                );
+            } else if !is_email(email) {
+                reject("email", "invalid_email", "is not an email address");
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors { errors })
+        }
+    }
+}
+
+/// A pragmatic check for `local@domain.tld`. The full grammar of RFC 5322 allows a lot more, but
+/// nobody types those addresses into a sign up form.
+fn is_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    let labels: Vec<_> = domain.split('.').collect();
+    !local.is_empty()
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && !domain.contains('@')
+        && labels.len() > 1
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 impl<'a> ReadUser<'a> {
@@ -87,3 +551,115 @@ impl<'a> ReadUser<'a> {
         Self { name }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The problems of `new_user` as `field.code`
+    fn codes(new_user: NewUser) -> Vec<String> {
+        match new_user.validate() {
+            Ok(()) => vec![],
+            Err(e) => e
+                .errors
+                .iter()
+                .map(|e| format!("{}.{}", e.field, e.code))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn validates_every_field() {
+        assert!(codes(NewUser::new("Ferris O'Crab-2")).is_empty());
+        assert!(codes(NewUser::new("ferris").with_email("ferris@rust-lang.org")).is_empty());
+        assert_eq!(codes(NewUser::new(" ")), ["name.empty"]);
+        assert_eq!(codes(NewUser::new(&"a".repeat(65))), ["name.too_long"]);
+        assert_eq!(
+            codes(NewUser::new("<script>").with_email("ferris@localhost")),
+            ["name.invalid_characters", "email.invalid_email"]
+        );
+        for email in [
+            "ferris",
+            "@rust-lang.org",
+            "ferris@rust..org",
+            "fer ris@rust.org",
+        ] {
+            assert_eq!(
+                codes(NewUser::new("ferris").with_email(email)),
+                ["email.invalid_email"],
+                "{email}"
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_the_quota() {
+        let store = UserManager::with_failure_rates(0.0, 0.0).with_max_users(1);
+        let actor = Actor::new("test", None);
+
+        store.create(&actor, NewUser::new("mert")).unwrap();
+        assert!(store.check_quota().is_err());
+        let error = store.create(&actor, NewUser::new("ferris")).unwrap_err();
+        assert!(error.is::<QuotaExceeded>());
+
+        // Room again after a delete
+        store.delete(&actor, ReadUser::new("mert")).unwrap();
+        store.create(&actor, NewUser::new("ferris")).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_users_and_stamps_valid_ones() {
+        let store = UserManager::with_failure_rates(0.0, 0.0);
+        let actor = Actor::new("test", None);
+
+        let error = store.create(&actor, NewUser::new("")).unwrap_err();
+        assert!(error.is::<ValidationErrors>());
+
+        store
+            .create(&actor, NewUser::new("mert").with_email("mert@example.com"))
+            .unwrap();
+        let user = store
+            .update(&actor, ReadUser::new("mert"), NewUser::new("ferris"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email.as_deref(), Some("mert@example.com"));
+        assert!(user.updated_at >= user.created_at);
+    }
+
+    #[test]
+    fn keeps_names_unique_when_creating() {
+        let store = UserManager::with_failure_rates(0.0, 0.0);
+        let actor = Actor::new("test", None);
+        store.create(&actor, NewUser::new("mert")).unwrap();
+
+        let error = store.create(&actor, NewUser::new("mert")).unwrap_err();
+        assert!(error.is::<NameTaken>());
+        let error = store
+            .create_idempotent(&actor, "key", NewUser::new("mert"))
+            .unwrap_err();
+        assert!(error.is::<NameTaken>());
+        assert!(store.read_by_name(ReadUser::new("mert")).unwrap().is_some());
+        assert_eq!(store.user_count(), 1);
+    }
+
+    #[test]
+    fn keeps_names_unique_when_renaming() {
+        let store = UserManager::with_failure_rates(0.0, 0.0);
+        let actor = Actor::new("test", None);
+        store.create(&actor, NewUser::new("mert")).unwrap();
+        store.create(&actor, NewUser::new("ferris")).unwrap();
+
+        let error = store
+            .update(&actor, ReadUser::new("mert"), NewUser::new("ferris"))
+            .unwrap_err();
+        assert!(error.is::<NameTaken>());
+        assert!(store.read_by_name(ReadUser::new("ferris")).is_ok());
+        assert!(store.read_by_name(ReadUser::new("mert")).unwrap().is_some());
+
+        // Keeping the name is no conflict
+        store
+            .update(&actor, ReadUser::new("mert"), NewUser::new("mert"))
+            .unwrap()
+            .unwrap();
+    }
+}
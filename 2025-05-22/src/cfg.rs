@@ -1,5 +1,465 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+
+use crate::{log_format::LogFormat, redaction::Policy, tenant::Tenant};
+
 #[derive(Debug)]
 pub struct Cfg {
     /// HTTP server port
     pub port: u16,
+    /// gRPC server port, only used with the `grpc` feature
+    pub grpc_port: u16,
+    /// Append-only log of all changes to our users, see `src/audit.rs`
+    pub audit_log: PathBuf,
+    /// Credentials the web server accepts
+    pub auth: AuthCfg,
+    /// Who we keep apart and how many users they may have
+    pub tenants: TenantCfg,
+    /// Where we look up more about our users
+    pub enrichment: EnrichmentCfg,
+    /// How much work we do after the response is sent
+    pub jobs: JobsCfg,
+    /// How we capture CPU profiles
+    pub profiling: ProfilingCfg,
+    /// How often and how patiently we retry failed user store operations
+    pub retry: RetryCfg,
+    /// When we stop calling the user store altogether
+    pub circuit_breaker: CircuitBreakerCfg,
+    /// How we collect and export telemetry
+    pub telemetry: TelemetryCfg,
+}
+
+impl Cfg {
+    /// Reads the configuration from the environment (see the `.env` file for the variables).
+    ///
+    /// - `PORT`: HTTP server port, pick another one to run a second instance next to the first
+    /// - `GRPC_PORT`
+    /// - `AUDIT_LOG_FILE`
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            port: env_or("PORT", 5173)?,
+            grpc_port: env_or("GRPC_PORT", 50051)?,
+            audit_log: env_or("AUDIT_LOG_FILE", PathBuf::from("audit.jsonl"))?,
+            auth: AuthCfg::from_env()?,
+            tenants: TenantCfg::from_env()?,
+            enrichment: EnrichmentCfg::from_env()?,
+            jobs: JobsCfg::from_env()?,
+            profiling: ProfilingCfg::from_env()?,
+            retry: RetryCfg::from_env()?,
+            circuit_breaker: CircuitBreakerCfg::from_env()?,
+            telemetry: TelemetryCfg::from_env()?,
+        })
+    }
+}
+
+/// Reads and parses the environmental variable `name`, falls back to `default` if it is not set.
+fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("could not parse {name}={value}")),
+        Err(_) => Ok(default),
+    }
+}
+
+#[derive(Default)]
+pub struct AuthCfg {
+    /// Static API keys, mapped to the principal they authenticate
+    pub api_keys: HashMap<String, String>,
+    /// Shared secret used to validate HS256 signed JWTs
+    pub jwt_hs256_secret: Option<String>,
+    /// PEM encoded public key used to validate RS256 signed JWTs
+    pub jwt_rs256_public_key: Option<String>,
+}
+
+impl AuthCfg {
+    /// - `AUTH_API_KEYS`: comma separated list of `key=principal` pairs, `key=principal@tenant`
+    ///   ties the key to a tenant
+    /// - `AUTH_JWT_HS256_SECRET`: shared secret for HS256 tokens
+    /// - `AUTH_JWT_RS256_PUBLIC_KEY_FILE`: path to a PEM encoded public key for RS256 tokens
+    fn from_env() -> anyhow::Result<Self> {
+        let api_keys = match std::env::var("AUTH_API_KEYS") {
+            Ok(keys) => keys
+                .split(',')
+                .filter(|pair| !pair.trim().is_empty())
+                .map(|pair| {
+                    let (key, principal) = pair
+                        .split_once('=')
+                        .with_context(|| format!("expected `key=principal`, got `{pair}`"))?;
+                    Ok((key.trim().to_owned(), principal.trim().to_owned()))
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("could not parse AUTH_API_KEYS")?,
+            Err(_) => HashMap::new(),
+        };
+
+        let jwt_rs256_public_key = match std::env::var("AUTH_JWT_RS256_PUBLIC_KEY_FILE") {
+            Ok(path) => Some(
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("could not read RS256 public key from {path}"))?,
+            ),
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            api_keys,
+            jwt_hs256_secret: std::env::var("AUTH_JWT_HS256_SECRET").ok(),
+            jwt_rs256_public_key,
+        })
+    }
+}
+
+// Secrets have no business showing up in our logs, so we only print what is configured.
+impl std::fmt::Debug for AuthCfg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthCfg")
+            .field("api_keys", &self.api_keys.values().collect::<Vec<_>>())
+            .field("jwt_hs256_secret", &self.jwt_hs256_secret.is_some())
+            .field("jwt_rs256_public_key", &self.jwt_rs256_public_key.is_some())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TenantCfg {
+    /// Tenant of requests whose credentials do not name one
+    pub default_tenant: String,
+    /// Tenants whose stores exist from the start, so requests with credentials for every tenant
+    /// can pick them
+    pub allowed_tenants: Vec<String>,
+    /// Upper bound for the number of tenants, requests for further tenants are rejected
+    pub max_tenants: usize,
+    /// Quota of users for every tenant
+    pub max_users_per_tenant: usize,
+}
+
+impl TenantCfg {
+    /// - `TENANT_DEFAULT`
+    /// - `TENANT_ALLOWED`: comma separated list of tenants
+    /// - `TENANT_MAX_TENANTS`
+    /// - `TENANT_MAX_USERS`
+    fn from_env() -> anyhow::Result<Self> {
+        let default_tenant = env_or("TENANT_DEFAULT", "default".to_owned())?;
+        Tenant::parse(&default_tenant).context("invalid TENANT_DEFAULT")?;
+        let allowed_tenants = std::env::var("TENANT_ALLOWED")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|tenant| !tenant.is_empty())
+            .map(|tenant| {
+                Tenant::parse(tenant).context("invalid TENANT_ALLOWED")?;
+                Ok(tenant.to_owned())
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            default_tenant,
+            allowed_tenants,
+            max_tenants: env_or("TENANT_MAX_TENANTS", 100)?,
+            max_users_per_tenant: env_or("TENANT_MAX_USERS", 10_000)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EnrichmentCfg {
+    /// Base URL of the instance that enriches our users, `None` to enrich them ourselves
+    pub url: Option<String>,
+    /// How long we wait for its answer
+    pub timeout: Duration,
+}
+
+impl EnrichmentCfg {
+    /// - `ENRICHMENT_URL`, e.g. `http://localhost:5174`
+    /// - `ENRICHMENT_TIMEOUT_MS`
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            url: std::env::var("ENRICHMENT_URL").ok(),
+            timeout: Duration::from_millis(env_or("ENRICHMENT_TIMEOUT_MS", 2_000)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobsCfg {
+    /// Number of jobs that run at the same time
+    pub workers: usize,
+    /// Number of jobs that may wait for a worker, further jobs are dropped
+    pub queue_capacity: usize,
+}
+
+impl JobsCfg {
+    /// - `JOBS_WORKERS`
+    /// - `JOBS_QUEUE_CAPACITY`
+    fn from_env() -> anyhow::Result<Self> {
+        // Without workers, jobs would wait forever
+        let workers = env_or("JOBS_WORKERS", 2)?;
+        anyhow::ensure!(workers > 0, "JOBS_WORKERS must be at least 1");
+        // Tokio has no channels without room for a message
+        let queue_capacity = env_or("JOBS_QUEUE_CAPACITY", 1_000)?;
+        anyhow::ensure!(queue_capacity > 0, "JOBS_QUEUE_CAPACITY must be at least 1");
+
+        Ok(Self {
+            workers,
+            queue_capacity,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfilingCfg {
+    /// Upper bound for the duration of a profile
+    pub max_duration: Duration,
+    /// Samples per second
+    pub frequency: i32,
+    /// Principals that may capture profiles, nobody else can
+    pub admins: Vec<String>,
+}
+
+impl ProfilingCfg {
+    /// - `PROFILING_MAX_SECONDS`
+    /// - `PROFILING_FREQUENCY_HZ`
+    /// - `PROFILING_ADMINS`: comma separated list of principals
+    fn from_env() -> anyhow::Result<Self> {
+        let admins = std::env::var("PROFILING_ADMINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|admin| !admin.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        // The sampler divides by the frequency, so at 0 the first profile would panic
+        let frequency = env_or("PROFILING_FREQUENCY_HZ", 99)?;
+        anyhow::ensure!(frequency > 0, "PROFILING_FREQUENCY_HZ must be at least 1");
+
+        Ok(Self {
+            max_duration: Duration::from_secs(env_or("PROFILING_MAX_SECONDS", 60)?),
+            frequency,
+            admins,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryCfg {
+    /// Attempts including the first one, so `1` disables retries
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every further retry
+    pub initial_backoff: Duration,
+    /// Upper bound for the backoff
+    pub max_backoff: Duration,
+}
+
+impl RetryCfg {
+    /// - `RETRY_MAX_ATTEMPTS`
+    /// - `RETRY_INITIAL_BACKOFF_MS`
+    /// - `RETRY_MAX_BACKOFF_MS`
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_attempts: env_or("RETRY_MAX_ATTEMPTS", 3)?,
+            initial_backoff: Duration::from_millis(env_or("RETRY_INITIAL_BACKOFF_MS", 10)?),
+            max_backoff: Duration::from_millis(env_or("RETRY_MAX_BACKOFF_MS", 200)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerCfg {
+    /// Share of failed calls (`0.0..=1.0`) within the window that opens the breaker
+    pub failure_rate_threshold: f64,
+    /// Number of most recent calls the failure rate is computed over
+    pub window_size: usize,
+    /// The breaker does not open before it has seen this many calls
+    pub minimum_calls: usize,
+    /// How long the breaker stays open before it lets probe calls through
+    pub open_duration: Duration,
+    /// Successful probe calls needed to close the breaker again
+    pub half_open_calls: u32,
+}
+
+impl CircuitBreakerCfg {
+    /// - `CIRCUIT_BREAKER_FAILURE_RATE`
+    /// - `CIRCUIT_BREAKER_WINDOW_SIZE`
+    /// - `CIRCUIT_BREAKER_MINIMUM_CALLS`
+    /// - `CIRCUIT_BREAKER_OPEN_MS`
+    /// - `CIRCUIT_BREAKER_HALF_OPEN_CALLS`
+    fn from_env() -> anyhow::Result<Self> {
+        let window_size = env_or("CIRCUIT_BREAKER_WINDOW_SIZE", 20)?;
+        // An empty window has no failure rate, so the breaker would never open
+        anyhow::ensure!(
+            window_size > 0,
+            "CIRCUIT_BREAKER_WINDOW_SIZE must be at least 1"
+        );
+        // The window never holds more calls than its size, so the breaker would never open
+        let minimum_calls = env_or("CIRCUIT_BREAKER_MINIMUM_CALLS", 10)?;
+        anyhow::ensure!(
+            minimum_calls <= window_size,
+            "CIRCUIT_BREAKER_MINIMUM_CALLS must not be larger than CIRCUIT_BREAKER_WINDOW_SIZE"
+        );
+        // At 0, the breaker would open without any failure, above 1 it would never open
+        let failure_rate_threshold = env_or("CIRCUIT_BREAKER_FAILURE_RATE", 0.6)?;
+        anyhow::ensure!(
+            0.0 < failure_rate_threshold && failure_rate_threshold <= 1.0,
+            "CIRCUIT_BREAKER_FAILURE_RATE must be above 0 and at most 1"
+        );
+
+        Ok(Self {
+            failure_rate_threshold,
+            window_size,
+            minimum_calls,
+            open_duration: Duration::from_millis(env_or("CIRCUIT_BREAKER_OPEN_MS", 5_000)?),
+            half_open_calls: env_or("CIRCUIT_BREAKER_HALF_OPEN_CALLS", 3)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetryCfg {
+    /// Tail based sampling of traces, `None` exports every trace
+    pub tail_sampling: Option<TailSamplingCfg>,
+    /// Which span and log fields hold personal data and how we hide it
+    pub redaction: RedactionCfg,
+    /// Where we keep telemetry the collector did not accept, `None` drops it
+    pub disk_buffer: Option<DiskBufferCfg>,
+    /// How our log lines look on stdout
+    pub log_format: LogFormat,
+}
+
+impl TelemetryCfg {
+    /// - `LOG_FORMAT`: `pretty` (the default) or `json`
+    fn from_env() -> anyhow::Result<Self> {
+        let log_format = match std::env::var("LOG_FORMAT") {
+            Ok(format) => format
+                .parse()
+                .with_context(|| format!("could not parse LOG_FORMAT={format}"))?,
+            Err(_) => LogFormat::default(),
+        };
+
+        Ok(Self {
+            tail_sampling: TailSamplingCfg::from_env()?,
+            redaction: RedactionCfg::from_env()?,
+            disk_buffer: DiskBufferCfg::from_env()?,
+            log_format,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TailSamplingCfg {
+    /// Share of traces (`0.0..=1.0`) without errors and below the latency threshold that we keep
+    pub sample_rate: f64,
+    /// Traces whose root span takes longer than this are always kept
+    pub latency_threshold: Duration,
+    /// Upper bound of traces we buffer while waiting for their root span to end
+    pub max_traces: usize,
+    /// Upper bound of spans we buffer per trace
+    pub max_spans_per_trace: usize,
+}
+
+impl TailSamplingCfg {
+    /// - `TAIL_SAMPLING`: set to `true` to enable tail sampling
+    /// - `TAIL_SAMPLING_RATE`
+    /// - `TAIL_SAMPLING_LATENCY_THRESHOLD_MS`
+    /// - `TAIL_SAMPLING_MAX_TRACES`
+    /// - `TAIL_SAMPLING_MAX_SPANS_PER_TRACE`
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        if !env_or("TAIL_SAMPLING", false)? {
+            return Ok(None);
+        }
+
+        let sample_rate = env_or("TAIL_SAMPLING_RATE", 0.1)?;
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&sample_rate),
+            "TAIL_SAMPLING_RATE must be between 0 and 1"
+        );
+
+        Ok(Some(Self {
+            sample_rate,
+            latency_threshold: Duration::from_millis(env_or(
+                "TAIL_SAMPLING_LATENCY_THRESHOLD_MS",
+                500,
+            )?),
+            max_traces: env_or("TAIL_SAMPLING_MAX_TRACES", 10_000)?,
+            max_spans_per_trace: env_or("TAIL_SAMPLING_MAX_SPANS_PER_TRACE", 1_000)?,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskBufferCfg {
+    /// Directory for the buffered batches, created if it does not exist
+    pub dir: PathBuf,
+    /// Upper bound for the size of all buffered batches per signal, the oldest ones go first
+    pub max_bytes: u64,
+    /// Buffered batches older than this are dropped
+    pub max_age: Duration,
+}
+
+impl DiskBufferCfg {
+    /// - `TELEMETRY_BUFFER_DIR`: set to enable the disk buffer
+    /// - `TELEMETRY_BUFFER_MAX_MB`
+    /// - `TELEMETRY_BUFFER_MAX_AGE_S`
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(dir) = std::env::var("TELEMETRY_BUFFER_DIR") else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            dir: dir.into(),
+            max_bytes: env_or::<u64>("TELEMETRY_BUFFER_MAX_MB", 64)? * 1024 * 1024,
+            max_age: Duration::from_secs(env_or("TELEMETRY_BUFFER_MAX_AGE_S", 3_600)?),
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct RedactionCfg {
+    /// The policy for each field name, fields without a policy are exported as they are
+    pub policies: HashMap<String, Policy>,
+    /// Key for the `hash` policy
+    pub hash_key: Vec<u8>,
+}
+
+impl RedactionCfg {
+    /// - `REDACT_FIELDS`: comma separated list of `field=policy` pairs, the policy being `drop`,
+    ///   `mask` or `hash`
+    /// - `REDACT_HASH_KEY`: key for the `hash` policy. Without it, we use a random key, so hashes
+    ///   change with every restart.
+    fn from_env() -> anyhow::Result<Self> {
+        let fields = std::env::var("REDACT_FIELDS")
+            .unwrap_or_else(|_| "name=hash,new_name=hash,user_uuid=hash,email=hash".into());
+        let policies = fields
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (field, policy) = pair
+                    .split_once('=')
+                    .with_context(|| format!("expected `field=policy`, got `{pair}`"))?;
+                Ok((field.trim().to_owned(), policy.trim().parse()?))
+            })
+            .collect::<anyhow::Result<_>>()
+            .context("could not parse REDACT_FIELDS")?;
+
+        let hash_key = match std::env::var("REDACT_HASH_KEY") {
+            Ok(key) => key.into_bytes(),
+            Err(_) => rand::random::<[u8; 32]>().to_vec(),
+        };
+
+        Ok(Self { policies, hash_key })
+    }
+}
+
+// Whoever knows the key can check guesses against our hashes, so it stays out of the logs.
+impl std::fmt::Debug for RedactionCfg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedactionCfg")
+            .field("policies", &self.policies)
+            .finish_non_exhaustive()
+    }
 }
@@ -0,0 +1,320 @@
+//! A circuit breaker in front of the user store.
+//!
+//! Retries help with the occasional hiccup, but when the store is really struggling they only
+//! make things worse. The breaker keeps track of the recent calls and once too many of them
+//! failed, it _opens_: for a while, calls fail fast with [`CircuitOpen`] without touching the store
+//! at all. Afterwards it is _half-open_ and lets a few probe calls through. If they succeed, the
+//! breaker closes again, if one of them fails, it opens again.
+//!
+//! The current state is exported as the `circuit_breaker.state` gauge and every transition is
+//! recorded as an event on the span that caused it, so you can tell from the telemetry alone when
+//! the breaker tripped.
+//!
+//! Only failures of the store count. A call rejected because of the request, e.g. an invalid user
+//! or a name that is taken, tells us the store is doing fine, so clients that keep sending bad
+//! requests cannot open the breaker for everybody.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use opentelemetry::{KeyValue, metrics::ObservableGauge};
+use tracing::{info, warn};
+
+use crate::{
+    business::{NameTaken, QuotaExceeded, ValidationErrors},
+    cfg::CircuitBreakerCfg,
+};
+
+/// Returned instead of calling the store while the breaker is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    /// Outcomes of the most recent calls while closed, `true` meaning failure
+    window: VecDeque<bool>,
+    opened_at: Instant,
+    /// Probe calls that are currently running while half-open
+    probes_in_flight: u32,
+    /// Successful probe calls while half-open
+    probes_succeeded: u32,
+}
+
+pub struct CircuitBreaker {
+    name: &'static str,
+    cfg: CircuitBreakerCfg,
+    inner: Mutex<Inner>,
+    /// The current [`State`] as a number for the gauge: 0 = closed, 1 = open, 2 = half-open
+    state_code: Arc<AtomicU64>,
+    _state_gauge: ObservableGauge<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, cfg: CircuitBreakerCfg) -> Self {
+        let state_code = Arc::new(AtomicU64::new(0));
+
+        let observed = state_code.clone();
+        let state_gauge = opentelemetry::global::meter("server_measurements")
+            .u64_observable_gauge("circuit_breaker.state")
+            .with_description("State of the circuit breaker: 0 = closed, 1 = open, 2 = half-open")
+            .with_callback(move |observer| {
+                observer.observe(
+                    observed.load(Ordering::Relaxed),
+                    &[KeyValue::new("circuit_breaker", name)],
+                )
+            })
+            .build();
+
+        Self {
+            name,
+            cfg,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                window: VecDeque::new(),
+                opened_at: Instant::now(),
+                probes_in_flight: 0,
+                probes_succeeded: 0,
+            }),
+            state_code,
+            _state_gauge: state_gauge,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.lock().state
+    }
+
+    /// Calls `operation` if the breaker lets us, fails fast with [`CircuitOpen`] otherwise.
+    pub fn call<T>(&self, operation: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.acquire()?;
+        let result = operation();
+        self.record(!result.as_ref().is_err_and(is_store_failure));
+        result
+    }
+
+    fn acquire(&self) -> Result<(), CircuitOpen> {
+        let mut inner = self.lock();
+        match inner.state {
+            State::Closed => Ok(()),
+            State::Open if inner.opened_at.elapsed() >= self.cfg.open_duration => {
+                self.transition(&mut inner, State::HalfOpen);
+                inner.probes_in_flight = 1;
+                Ok(())
+            }
+            State::Open => Err(CircuitOpen),
+            State::HalfOpen
+                if inner.probes_in_flight + inner.probes_succeeded < self.cfg.half_open_calls =>
+            {
+                inner.probes_in_flight += 1;
+                Ok(())
+            }
+            State::HalfOpen => Err(CircuitOpen),
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut inner = self.lock();
+        match inner.state {
+            State::Closed => {
+                inner.window.push_back(!success);
+                if inner.window.len() > self.cfg.window_size {
+                    inner.window.pop_front();
+                }
+
+                let failures = inner.window.iter().filter(|failed| **failed).count();
+                let failure_rate = failures as f64 / inner.window.len() as f64;
+                if inner.window.len() >= self.cfg.minimum_calls
+                    && failure_rate >= self.cfg.failure_rate_threshold
+                {
+                    self.transition(&mut inner, State::Open);
+                }
+            }
+            State::HalfOpen if !success => self.transition(&mut inner, State::Open),
+            State::HalfOpen => {
+                inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+                inner.probes_succeeded += 1;
+                if inner.probes_succeeded >= self.cfg.half_open_calls {
+                    self.transition(&mut inner, State::Closed);
+                }
+            }
+            // Calls that were let through before the breaker opened
+            State::Open => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: State) {
+        let from = inner.state;
+        inner.state = to;
+        inner.window.clear();
+        inner.probes_in_flight = 0;
+        inner.probes_succeeded = 0;
+        if to == State::Open {
+            inner.opened_at = Instant::now();
+        }
+
+        let code = match to {
+            State::Closed => 0,
+            State::Open => 1,
+            State::HalfOpen => 2,
+        };
+        self.state_code.store(code, Ordering::Relaxed);
+
+        // These events end up on whatever span is active, which is the request that tipped the scale
+        let (name, from) = (self.name, from.as_str());
+        if to == State::Open {
+            warn!(
+                circuit_breaker = name,
+                from,
+                to = to.as_str(),
+                "Circuit breaker opened"
+            );
+        } else {
+            info!(
+                circuit_breaker = name,
+                from,
+                to = to.as_str(),
+                "Circuit breaker changed state"
+            );
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // The state stays consistent even if a holder panicked, so we just carry on
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Whether `err` is the store's fault rather than the request's
+fn is_store_failure(err: &anyhow::Error) -> bool {
+    !(err.is::<ValidationErrors>() || err.is::<QuotaExceeded>() || err.is::<NameTaken>())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        audit::Actor,
+        business::{NewUser, ReadUser, UserManager},
+    };
+
+    fn breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            CircuitBreakerCfg {
+                failure_rate_threshold: 0.5,
+                window_size: 4,
+                minimum_calls: 4,
+                open_duration,
+                half_open_calls: 2,
+            },
+        )
+    }
+
+    fn fail(breaker: &CircuitBreaker) -> anyhow::Result<()> {
+        breaker.call(|| anyhow::bail!("store is down"))
+    }
+
+    fn succeed(breaker: &CircuitBreaker) -> anyhow::Result<()> {
+        breaker.call(|| Ok(()))
+    }
+
+    #[test]
+    fn opens_when_failure_rate_is_reached_and_fails_fast() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        succeed(&breaker).unwrap();
+        succeed(&breaker).unwrap();
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), State::Closed);
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), State::Open);
+
+        let mut called = false;
+        let err = breaker
+            .call(|| {
+                called = true;
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert!(!called);
+    }
+
+    #[test]
+    fn closes_after_successful_probes() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..4 {
+            fail(&breaker).unwrap_err();
+        }
+        assert_eq!(breaker.state(), State::Open);
+
+        succeed(&breaker).unwrap();
+        assert_eq!(breaker.state(), State::HalfOpen);
+        succeed(&breaker).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn reopens_when_a_probe_fails() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..4 {
+            fail(&breaker).unwrap_err();
+        }
+
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn stays_closed_when_names_are_taken() {
+        let breaker = breaker(Duration::from_secs(60));
+        let store = UserManager::with_failure_rates(0.0, 0.0);
+        let actor = Actor::new("test", None);
+        store.create(&actor, NewUser::new("mert")).unwrap();
+        store.create(&actor, NewUser::new("ferris")).unwrap();
+
+        for _ in 0..10 {
+            let err = breaker
+                .call(|| store.update(&actor, ReadUser::new("mert"), NewUser::new("ferris")))
+                .unwrap_err();
+            assert!(err.is::<NameTaken>());
+        }
+        assert_eq!(breaker.state(), State::Closed);
+    }
+}
@@ -0,0 +1,587 @@
+//! Buffering telemetry on disk while the collector is unreachable.
+//!
+//! The OTLP exporters in `otel.rs` try once and drop the batch if the collector does not answer.
+//! That is fine in a data center, but our edge deployments lose their connection for minutes at a
+//! time. With `TELEMETRY_BUFFER_DIR` set, we wrap the OTLP/gRPC exporters: a batch the collector
+//! does not accept is written to disk as an encoded OTLP request.
+//!
+//! A task in the background replays the buffered batches, oldest first, right after the next
+//! successful export and otherwise every [`REPLAY_INTERVAL`]. Each round sends at most
+//! [`REPLAY_BUDGET`] batches, so a long backlog neither holds up nor crowds out new exports. The
+//! replay reads the collector's address, headers and timeout from the same `OTEL_EXPORTER_OTLP_*`
+//! variables as the exporters, including the per-signal ones. We are built without TLS and
+//! compression, so we refuse to start with a configuration that asks for them instead of sending
+//! the batches somewhere else than the exporters would.
+//!
+//! The buffer is bounded: batches older than `TELEMETRY_BUFFER_MAX_AGE_S` are dropped, and if the
+//! buffer grows beyond `TELEMETRY_BUFFER_MAX_MB`, the oldest batches make room for new ones. How
+//! many batches went which way is counted in the `disk_buffer.batches` metric.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, bail, ensure};
+use opentelemetry::{KeyValue, metrics::Counter};
+use opentelemetry_proto::{
+    tonic::collector::{
+        metrics::v1::{ExportMetricsServiceRequest, metrics_service_client::MetricsServiceClient},
+        trace::v1::{ExportTraceServiceRequest, trace_service_client::TraceServiceClient},
+    },
+    transform::{
+        common::tonic::ResourceAttributesWithSchema,
+        trace::tonic::group_spans_by_resource_and_scope,
+    },
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter},
+    trace::{SpanData, SpanExporter},
+};
+use prost::Message;
+use tokio::sync::Notify;
+use tonic::{
+    metadata::{MetadataKey, MetadataMap, MetadataValue},
+    transport::Channel,
+};
+
+use crate::cfg::DiskBufferCfg;
+
+/// How often we try to replay buffered batches if no export succeeds
+pub const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Most batches we replay in one go
+pub const REPLAY_BUDGET: usize = 16;
+
+/// Created on first use, so that it is registered with the global meter provider and not with the
+/// no-op provider that is in place while `otel.rs` builds the exporters.
+static BATCHES: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    opentelemetry::global::meter("telemetry_pipeline")
+        .u64_counter("disk_buffer.batches")
+        .with_description("Telemetry batches that went through the disk buffer")
+        .build()
+});
+
+/// The collector the buffered batches of a signal are replayed to
+#[derive(Debug, Clone)]
+struct Collector {
+    endpoint: String,
+    headers: MetadataMap,
+    timeout: Duration,
+}
+
+impl Collector {
+    /// Reads `OTEL_EXPORTER_OTLP_{signal}_*`, falling back to `OTEL_EXPORTER_OTLP_*`.
+    fn from_env(signal: &str) -> anyhow::Result<Self> {
+        Self::from_vars(signal, |name| std::env::var(name).ok())
+    }
+
+    fn from_vars(signal: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            lookup(&format!("OTEL_EXPORTER_OTLP_{signal}_{name}"))
+                .or_else(|| lookup(&format!("OTEL_EXPORTER_OTLP_{name}")))
+        };
+
+        // The same default the OTLP/gRPC exporters use
+        let endpoint = var("ENDPOINT").unwrap_or_else(|| "http://localhost:4317".to_owned());
+        ensure!(
+            !endpoint.starts_with("https:"),
+            "the disk buffer cannot replay to {endpoint}, we are built without TLS"
+        );
+        if let Some(compression) = var("COMPRESSION").filter(|c| c != "none") {
+            bail!("the disk buffer cannot replay with {compression} compression");
+        }
+        let timeout = match var("TIMEOUT") {
+            Some(millis) => Duration::from_millis(
+                millis
+                    .parse()
+                    .with_context(|| format!("invalid OTLP timeout `{millis}`"))?,
+            ),
+            None => Duration::from_secs(10),
+        };
+
+        let mut headers = MetadataMap::new();
+        for header in var("HEADERS").iter().flat_map(|headers| headers.split(',')) {
+            if header.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = header
+                .split_once('=')
+                .with_context(|| format!("expected `key=value`, got `{header}`"))?;
+            headers.insert(
+                MetadataKey::from_str(key.trim())
+                    .with_context(|| format!("invalid OTLP header `{key}`"))?,
+                MetadataValue::from_str(value.trim())
+                    .with_context(|| format!("invalid value of OTLP header `{key}`"))?,
+            );
+        }
+
+        Ok(Self {
+            endpoint,
+            headers,
+            timeout,
+        })
+    }
+
+    /// A lazily connecting channel to the collector. Has to be created within the Tokio runtime.
+    fn channel(&self) -> anyhow::Result<Channel> {
+        Ok(Channel::from_shared(self.endpoint.clone())?
+            .timeout(self.timeout)
+            .connect_lazy())
+    }
+
+    /// `message` as a request with our headers
+    fn request<M>(&self, message: M) -> tonic::Request<M> {
+        let mut request = tonic::Request::new(message);
+        *request.metadata_mut() = self.headers.clone();
+        request
+    }
+}
+
+/// Exports spans with `E`, buffers the batches the collector does not accept.
+#[derive(Debug)]
+pub struct BufferedSpanExporter<E> {
+    exporter: E,
+    queue: Arc<DiskQueue>,
+    /// Tells the replay that the collector is back
+    exported: Arc<Notify>,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl<E: SpanExporter> BufferedSpanExporter<E> {
+    /// Starts the replay, so this has to be called within a tokio runtime.
+    pub fn new(exporter: E, cfg: &DiskBufferCfg) -> anyhow::Result<Self> {
+        let collector = Collector::from_env("TRACES")?;
+        let client = TraceServiceClient::new(collector.channel()?);
+        let queue = Arc::new(DiskQueue::new(cfg, "traces")?);
+        let exported = Arc::new(Notify::new());
+        spawn_replay(queue.clone(), exported.clone(), move |request| {
+            let (mut client, request) = (client.clone(), collector.request(request));
+            async move { client.export(request).await.map(|_| ()) }
+        });
+
+        Ok(Self {
+            exporter,
+            queue,
+            exported,
+            resource: ResourceAttributesWithSchema::default(),
+        })
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for BufferedSpanExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        // The exporter takes the batch, we might still need it
+        let copy = batch.clone();
+        match self.exporter.export(batch).await {
+            Ok(()) => {
+                self.exported.notify_one();
+                Ok(())
+            }
+            Err(e) => {
+                let request = ExportTraceServiceRequest {
+                    resource_spans: group_spans_by_resource_and_scope(copy, &self.resource),
+                };
+                self.queue.persist(&request.encode_to_vec(), e)
+            }
+        }
+    }
+
+    fn shutdown(&mut self) -> OTelSdkResult {
+        self.exporter.shutdown()
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.exporter.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+        self.exporter.set_resource(resource);
+    }
+}
+
+/// Exports metrics with `E`, buffers the batches the collector does not accept.
+#[derive(Debug)]
+pub struct BufferedMetricExporter<E> {
+    exporter: E,
+    queue: Arc<DiskQueue>,
+    /// Tells the replay that the collector is back
+    exported: Arc<Notify>,
+}
+
+impl<E: PushMetricExporter> BufferedMetricExporter<E> {
+    /// Starts the replay, so this has to be called within a tokio runtime.
+    pub fn new(exporter: E, cfg: &DiskBufferCfg) -> anyhow::Result<Self> {
+        let collector = Collector::from_env("METRICS")?;
+        let client = MetricsServiceClient::new(collector.channel()?);
+        let queue = Arc::new(DiskQueue::new(cfg, "metrics")?);
+        let exported = Arc::new(Notify::new());
+        spawn_replay(queue.clone(), exported.clone(), move |request| {
+            let (mut client, request) = (client.clone(), collector.request(request));
+            async move { client.export(request).await.map(|_| ()) }
+        });
+
+        Ok(Self {
+            exporter,
+            queue,
+            exported,
+        })
+    }
+}
+
+impl<E: PushMetricExporter> PushMetricExporter for BufferedMetricExporter<E> {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> OTelSdkResult {
+        match self.exporter.export(metrics).await {
+            Ok(()) => {
+                self.exported.notify_one();
+                Ok(())
+            }
+            Err(e) => {
+                let request = ExportMetricsServiceRequest::from(&*metrics);
+                self.queue.persist(&request.encode_to_vec(), e)
+            }
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.exporter.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.exporter.shutdown()
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.exporter.temporality()
+    }
+}
+
+/// Replays the batches of `queue` with `send`, after every successful export and otherwise every
+/// [`REPLAY_INTERVAL`], until the runtime shuts down.
+fn spawn_replay<M, F, Fut>(queue: Arc<DiskQueue>, exported: Arc<Notify>, mut send: F)
+where
+    M: Message + Default + 'static,
+    F: FnMut(M) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), tonic::Status>> + Send,
+{
+    tokio::spawn(async move {
+        loop {
+            let _ = tokio::time::timeout(REPLAY_INTERVAL, exported.notified()).await;
+            queue.replay(REPLAY_BUDGET, &mut send).await;
+        }
+    });
+}
+
+/// A directory of encoded OTLP requests. The file names start with the time the batch was written,
+/// so sorting them by name sorts them by age.
+#[derive(Debug)]
+struct DiskQueue {
+    signal: &'static str,
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    sequence: AtomicU64,
+}
+
+impl DiskQueue {
+    fn new(cfg: &DiskBufferCfg, signal: &'static str) -> anyhow::Result<Self> {
+        let dir = cfg.dir.join(signal);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create telemetry buffer {}", dir.display()))?;
+        // Half-written batches of a process that crashed
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                fs::remove_file(&path)
+                    .with_context(|| format!("could not remove {}", path.display()))?;
+            }
+        }
+
+        Ok(Self {
+            signal,
+            dir,
+            max_bytes: cfg.max_bytes,
+            max_age: cfg.max_age,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    fn count(&self, outcome: &'static str) {
+        BATCHES.add(
+            1,
+            &[
+                KeyValue::new("signal", self.signal),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+    }
+
+    /// Buffers a batch that could not be exported. The batch is safe once it is on disk, so we
+    /// only report an error if we could not write it.
+    fn persist(&self, batch: &[u8], error: impl fmt::Display) -> OTelSdkResult {
+        self.push(batch).map_err(|e| {
+            OTelSdkError::InternalFailure(format!(
+                "export failed with {error}, could not buffer the batch: {e}"
+            ))
+        })
+    }
+
+    fn push(&self, batch: &[u8]) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{millis:016}-{sequence:010}");
+
+        // Write to a temporary file first, so that a crash never leaves half a batch behind
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        fs::write(&tmp, batch)?;
+        fs::rename(&tmp, self.dir.join(format!("{name}.pb")))?;
+        self.count("persisted");
+
+        self.evict()
+    }
+
+    /// Removes the oldest batches until the buffer fits into `max_bytes` again.
+    fn evict(&self) -> io::Result<()> {
+        let batches = self.batches()?;
+        let sizes: Vec<_> = batches
+            .iter()
+            .map(|batch| fs::metadata(batch).map(|m| m.len()).unwrap_or_default())
+            .collect();
+
+        let mut total: u64 = sizes.iter().sum();
+        for (batch, size) in batches.iter().zip(sizes) {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(batch)?;
+            total -= size;
+            self.count("evicted");
+        }
+        Ok(())
+    }
+
+    /// The buffered batches, oldest first. Expired batches are removed on the way.
+    fn batches(&self) -> io::Result<Vec<PathBuf>> {
+        let mut batches = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "pb") {
+                continue;
+            }
+            if self.expired(&path) {
+                fs::remove_file(&path)?;
+                self.count("expired");
+                continue;
+            }
+            batches.push(path);
+        }
+        batches.sort();
+        Ok(batches)
+    }
+
+    fn expired(&self, batch: &Path) -> bool {
+        let written = batch
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.split('-').next())
+            .and_then(|millis| millis.parse().ok())
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+
+        match written {
+            Some(written) => written.elapsed().is_ok_and(|age| age > self.max_age),
+            // Not one of ours, or damaged
+            None => true,
+        }
+    }
+
+    /// Sends up to `budget` of the buffered batches, oldest first. Stops at the first batch the
+    /// collector does not accept, so that it is retried in the next round.
+    async fn replay<M, F, Fut>(&self, budget: usize, mut send: F)
+    where
+        M: Message + Default,
+        F: FnMut(M) -> Fut,
+        Fut: Future<Output = Result<(), tonic::Status>>,
+    {
+        let Ok(batches) = self.batches() else {
+            return;
+        };
+
+        for batch in batches.into_iter().take(budget) {
+            let Ok(bytes) = fs::read(&batch) else {
+                continue;
+            };
+            match M::decode(bytes.as_slice()) {
+                Ok(request) => {
+                    if send(request).await.is_err() {
+                        return;
+                    }
+                    self.count("replayed");
+                }
+                Err(_) => self.count("corrupt"),
+            }
+            let _ = fs::remove_file(&batch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry_proto::tonic::trace::v1::ResourceSpans;
+
+    use super::*;
+    use crate::test_util::TempPath;
+
+    /// A queue along with its directory, which goes once the test drops it
+    fn queue(max_bytes: u64, max_age: Duration) -> (DiskQueue, TempPath) {
+        let dir = TempPath::new("disk_buffer");
+        let cfg = DiskBufferCfg {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_age,
+        };
+        (DiskQueue::new(&cfg, "traces").unwrap(), dir)
+    }
+
+    /// A request whose encoded size grows with `spans`, so we can tell them apart
+    fn request(spans: usize) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans::default(); spans],
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_oldest_first_and_stops_at_the_first_failure() {
+        let (queue, _dir) = queue(u64::MAX, Duration::from_secs(60));
+        for spans in 1..=3 {
+            queue.push(&request(spans).encode_to_vec()).unwrap();
+        }
+
+        let mut sent = vec![];
+        queue
+            .replay(usize::MAX, |request: ExportTraceServiceRequest| {
+                let spans = request.resource_spans.len();
+                sent.push(spans);
+                async move {
+                    match spans {
+                        2 => Err(tonic::Status::unavailable("collector is down")),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(sent, [1, 2]);
+        assert_eq!(queue.batches().unwrap().len(), 2, "keeps the failed batch");
+    }
+
+    #[test]
+    fn evicts_the_oldest_batches_when_full() {
+        let size = request(1).encoded_len() as u64;
+        let (queue, _dir) = queue(2 * size, Duration::from_secs(60));
+        for _ in 0..3 {
+            queue.push(&request(1).encode_to_vec()).unwrap();
+        }
+
+        let batches = queue.batches().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert!(
+            batches[0].to_string_lossy().ends_with("-0000000001.pb"),
+            "the first batch is gone"
+        );
+    }
+
+    #[test]
+    fn drops_expired_batches() {
+        let (queue, _dir) = queue(u64::MAX, Duration::from_secs(60));
+        queue.push(&request(1).encode_to_vec()).unwrap();
+        fs::write(queue.dir.join("0000000000000000-0000000000.pb"), b"").unwrap();
+
+        assert_eq!(queue.batches().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replays_at_most_the_budget() {
+        let (queue, _dir) = queue(u64::MAX, Duration::from_secs(60));
+        for spans in 1..=3 {
+            queue.push(&request(spans).encode_to_vec()).unwrap();
+        }
+
+        let mut sent = 0;
+        queue
+            .replay(2, |_: ExportTraceServiceRequest| {
+                sent += 1;
+                async { Ok(()) }
+            })
+            .await;
+
+        assert_eq!(sent, 2);
+        assert_eq!(queue.batches().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn removes_half_written_batches_at_startup() {
+        let (queue, _dir) = queue(u64::MAX, Duration::from_secs(60));
+        queue.push(&request(1).encode_to_vec()).unwrap();
+        fs::write(queue.dir.join("0000000000000000-0000000001.tmp"), b"").unwrap();
+
+        let cfg = DiskBufferCfg {
+            dir: queue.dir.parent().unwrap().to_owned(),
+            max_bytes: u64::MAX,
+            max_age: Duration::from_secs(60),
+        };
+        let reopened = DiskQueue::new(&cfg, "traces").unwrap();
+        assert_eq!(fs::read_dir(&reopened.dir).unwrap().count(), 1);
+        assert_eq!(reopened.batches().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reads_the_collector_like_the_exporters_do() {
+        let vars = |vars: &[(&str, &str)]| {
+            let vars: std::collections::HashMap<_, _> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            move |name: &str| vars.get(name).cloned()
+        };
+
+        let collector = Collector::from_vars(
+            "TRACES",
+            vars(&[
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4317"),
+                ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "http://traces:4317"),
+                (
+                    "OTEL_EXPORTER_OTLP_HEADERS",
+                    "x-tenant=edge-1, api-key=secret",
+                ),
+                ("OTEL_EXPORTER_OTLP_TIMEOUT", "2500"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(collector.endpoint, "http://traces:4317");
+        assert_eq!(collector.timeout, Duration::from_millis(2_500));
+        assert_eq!(collector.headers.get("x-tenant").unwrap(), "edge-1");
+        assert_eq!(collector.headers.get("api-key").unwrap(), "secret");
+
+        let collector = Collector::from_vars("METRICS", vars(&[])).unwrap();
+        assert_eq!(collector.endpoint, "http://localhost:4317");
+
+        for unsupported in [
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "https://collector:4317"),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "gzip"),
+        ] {
+            assert!(Collector::from_vars("TRACES", vars(&[unsupported])).is_err());
+        }
+    }
+}
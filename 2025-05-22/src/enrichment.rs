@@ -0,0 +1,98 @@
+//! Looking up more about a user at another instance of this service.
+//!
+//! `GET /users/enriched/{name}` returns a user along with its [`Enrichment`]. If `ENRICHMENT_URL`
+//! is set, the [`EnrichmentClient`] asks the instance at that URL for it, at its
+//! `GET /enrichment/{name}` route. Start a second instance with `PORT=5174` and point the first one
+//! at it, and a single request shows up as one trace spanning both services.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    cfg::EnrichmentCfg,
+    http_client::{BaseUrl, TracedClient},
+};
+
+/// What we know about a user beyond its name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Enrichment {
+    /// Up to three initials of the name, e.g. `FC` for `Ferris the Crab`
+    pub initials: String,
+    /// Background color of the avatar, e.g. `#3fa2c0`
+    pub avatar_color: String,
+}
+
+/// Computes the enrichment of the user `name`, the work a remote instance does for us.
+#[instrument(skip_all)]
+pub fn enrich(name: &str) -> Enrichment {
+    let initials = name
+        .split_whitespace()
+        .filter_map(|word| word.chars().next())
+        .filter(|c| c.is_alphanumeric() && !c.is_lowercase())
+        .take(3)
+        .collect::<String>();
+    let initials = if initials.is_empty() {
+        name.chars().take(1).flat_map(char::to_uppercase).collect()
+    } else {
+        initials
+    };
+    let hash = Sha256::digest(name.as_bytes());
+
+    Enrichment {
+        initials,
+        avatar_color: format!("#{:02x}{:02x}{:02x}", hash[0], hash[1], hash[2]),
+    }
+}
+
+/// Asks another instance to enrich our users.
+pub struct EnrichmentClient {
+    client: TracedClient,
+    base_url: BaseUrl,
+}
+
+impl EnrichmentClient {
+    /// A client for the instance configured in `ENRICHMENT_URL`, `None` if there is none.
+    pub fn from_cfg(cfg: &EnrichmentCfg) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &cfg.url else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            client: TracedClient::new(cfg.timeout)?,
+            base_url: BaseUrl::parse(url)?,
+        }))
+    }
+
+    /// The enrichment of the user `name`. `correlation_id` is passed on, so the logs of both
+    /// instances can be searched for it.
+    pub async fn lookup(
+        &self,
+        name: &str,
+        correlation_id: Option<&str>,
+    ) -> anyhow::Result<Enrichment> {
+        let url = self.base_url.join(&["enrichment", name]);
+
+        let request = self.client.http().get(url).build()?;
+        let response = self
+            .client
+            .send(request, "/enrichment/{name}", correlation_id)
+            .await?
+            .error_for_status()?;
+        response.json().await.context("unexpected enrichment")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enriches_users() {
+        assert_eq!(enrich("Ferris the Crab").initials, "FC");
+        assert_eq!(enrich("mert").initials, "M");
+        assert_eq!(enrich("mert"), enrich("mert"));
+        assert_eq!(enrich("mert").avatar_color.len(), 7);
+    }
+}
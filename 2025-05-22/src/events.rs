@@ -0,0 +1,211 @@
+//! Telling others about changes to our users.
+//!
+//! Instead of polling `/users/read/{name}`, consumers can subscribe to `/users/events` and get every
+//! create, update and delete as a server-sent event. [`UserEvents`] is the channel in between: the
+//! [`UserManager`](crate::business::UserManager) publishes, every connected client gets its own
+//! receiver.
+//!
+//! Clients lose their connection every now and then. To pick up where they left off, we keep the
+//! most recent events around and replay everything after the `Last-Event-ID` a client sends when
+//! it reconnects.
+//!
+//! Every event carries the W3C trace context of the request that caused it. Whoever consumes the
+//! event can link their work to that request, see [`UserEvent::producer_context`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use opentelemetry::{propagation::TextMapPropagator, trace::TraceContextExt};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::ToSchema;
+
+use crate::business::User;
+
+/// How many recent events we keep for clients that reconnect
+const REPLAY_CAPACITY: usize = 1_024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UserEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl UserEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UserEventKind::Created => "created",
+            UserEventKind::Updated => "updated",
+            UserEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEvent {
+    /// Increases by one with every event
+    pub id: u64,
+    pub kind: UserEventKind,
+    /// The user after the change, or before it was deleted
+    pub user: User,
+    /// `traceparent` and `tracestate` of the request that caused the change
+    pub trace_context: HashMap<String, String>,
+}
+
+impl UserEvent {
+    /// The trace context of the request that caused this event
+    pub fn producer_context(&self) -> opentelemetry::Context {
+        TraceContextPropagator::new().extract(&self.trace_context)
+    }
+
+    /// The span of sending this event to a consumer, linked to the request that caused it
+    pub fn send_span(&self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "send_user_event",
+            event.id = self.id,
+            event.kind = self.kind.as_str()
+        );
+        span.add_link(self.producer_context().span().span_context().clone());
+        span
+    }
+}
+
+struct Recent {
+    last_id: u64,
+    events: VecDeque<UserEvent>,
+}
+
+pub struct UserEvents {
+    recent: Mutex<Recent>,
+    sender: broadcast::Sender<UserEvent>,
+}
+
+impl UserEvents {
+    pub fn new() -> Self {
+        Self {
+            recent: Mutex::new(Recent {
+                last_id: 0,
+                events: VecDeque::with_capacity(REPLAY_CAPACITY),
+            }),
+            sender: broadcast::Sender::new(REPLAY_CAPACITY),
+        }
+    }
+
+    /// Publishes a change to `user`, with the trace context of the current span.
+    pub(crate) fn publish(&self, kind: UserEventKind, user: &User) {
+        let mut trace_context = HashMap::new();
+        TraceContextPropagator::new()
+            .inject_context(&tracing::Span::current().context(), &mut trace_context);
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.last_id += 1;
+        let event = UserEvent {
+            id: recent.last_id,
+            kind,
+            user: user.clone(),
+            trace_context,
+        };
+        if recent.events.len() == REPLAY_CAPACITY {
+            recent.events.pop_front();
+        }
+        recent.events.push_back(event.clone());
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns the events after `last_event_id` we still have, and a receiver for all events that
+    /// follow them. Without `last_event_id`, there is nothing to replay.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (Vec<UserEvent>, broadcast::Receiver<UserEvent>) {
+        // Holding the lock makes sure no event is published between the replay and the receiver
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let missed = match last_event_id {
+            Some(last_event_id) => recent
+                .events
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        (missed, self.sender.subscribe())
+    }
+}
+
+impl Default for UserEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            email: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn replays_events_after_the_last_event_id() {
+        let events = UserEvents::new();
+        events.publish(UserEventKind::Created, &user("mert"));
+        events.publish(UserEventKind::Created, &user("ferris"));
+
+        let (missed, mut receiver) = events.subscribe(Some(1));
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].id, 2);
+        assert_eq!(missed[0].user.name, "ferris");
+
+        events.publish(UserEventKind::Deleted, &user("ferris"));
+        let live = receiver.try_recv().unwrap();
+        assert_eq!((live.id, live.kind), (3, UserEventKind::Deleted));
+
+        let (missed, _) = events.subscribe(None);
+        assert!(missed.is_empty());
+    }
+
+    #[test]
+    fn events_carry_the_trace_context_of_their_producer() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::OpenTelemetryLayer::new(provider.tracer("test")),
+        );
+        let events = UserEvents::new();
+        let (_, mut receiver) = events.subscribe(None);
+
+        let producer = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("add_user");
+            span.in_scope(|| events.publish(UserEventKind::Created, &user("mert")));
+            span.context().span().span_context().clone()
+        });
+
+        let event = receiver.try_recv().unwrap();
+        let context = event.producer_context();
+        assert!(producer.is_valid());
+        assert_eq!(
+            context.span().span_context().trace_id(),
+            producer.trace_id()
+        );
+        assert_eq!(context.span().span_context().span_id(), producer.span_id());
+    }
+}
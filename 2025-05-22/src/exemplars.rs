@@ -0,0 +1,258 @@
+//! Exemplars link metrics to traces.
+//!
+//! A histogram tells us _that_ some requests were slow, a trace tells us _why_ one of them was.
+//! Exemplars connect the two: next to a histogram bucket, we export the trace and span ID of a
+//! request that ended up in this bucket. In Grafana, a slow bucket then links straight to a trace
+//! that shows what took so long.
+//!
+//! The OTEL SDK has a field for exemplars on every data point but (as of version 0.29) never fills
+//! it. So we do it ourselves: [`record`] remembers recordings made inside a sampled span, and
+//! [`ExemplarExporter`] attaches them to the matching histogram buckets right before the data
+//! points are exported.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use opentelemetry::{KeyValue, metrics::Histogram, trace::TraceContextExt};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{
+        Temporality,
+        data::{self, Exemplar, ResourceMetrics},
+        exporter::PushMetricExporter,
+    },
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// How many recent exemplars we keep per histogram and attribute set
+const EXEMPLARS_PER_SERIES: usize = 64;
+
+/// Exemplars older than this are forgotten. That is two intervals of the periodic export, so the
+/// OTLP export and a scraping Prometheus both get to see every exemplar, but a bucket nobody
+/// ended up in for a while does not link to the same old trace forever.
+const MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Recent exemplars by histogram name and (sorted) attributes, oldest first
+type Reservoir = HashMap<&'static str, HashMap<Vec<KeyValue>, VecDeque<Exemplar<f64>>>>;
+
+static RESERVOIR: LazyLock<Mutex<Reservoir>> = LazyLock::new(Default::default);
+
+/// Records `value` on `histogram`. If we are within a sampled span, the recording is also
+/// remembered as an exemplar. `name` has to be the name the histogram was built with.
+pub fn record(histogram: &Histogram<f64>, name: &'static str, value: f64, attributes: &[KeyValue]) {
+    histogram.record(value, attributes);
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    if !span_context.is_valid() || !span_context.is_sampled() {
+        return;
+    }
+
+    let exemplar = Exemplar {
+        filtered_attributes: vec![],
+        time: SystemTime::now(),
+        value,
+        span_id: span_context.span_id().to_bytes(),
+        trace_id: span_context.trace_id().to_bytes(),
+    };
+
+    let mut reservoir = RESERVOIR.lock().unwrap_or_else(|e| e.into_inner());
+    let exemplars = reservoir
+        .entry(name)
+        .or_default()
+        .entry(sorted(attributes))
+        .or_default();
+    if exemplars.len() == EXEMPLARS_PER_SERIES {
+        exemplars.pop_front();
+    }
+    exemplars.push_back(exemplar);
+}
+
+/// Attaches the most recent exemplar of every bucket to the histogram data points in `metrics`.
+pub fn attach(metrics: &mut ResourceMetrics) {
+    let mut reservoir = RESERVOIR.lock().unwrap_or_else(|e| e.into_inner());
+    forget_old(&mut reservoir, SystemTime::now());
+    if reservoir.is_empty() {
+        return;
+    }
+
+    for metric in metrics
+        .scope_metrics
+        .iter_mut()
+        .flat_map(|scope| scope.metrics.iter_mut())
+    {
+        let Some(series) = reservoir.get(metric.name.as_ref()) else {
+            continue;
+        };
+        let data = data::Aggregation::as_mut(metric.data.as_mut());
+        let Some(histogram) = data.downcast_mut::<data::Histogram<f64>>() else {
+            continue;
+        };
+
+        for data_point in &mut histogram.data_points {
+            let Some(exemplars) = series.get(&sorted(&data_point.attributes)) else {
+                continue;
+            };
+
+            // One exemplar per bucket. `bounds` are the upper bounds, the last bucket is unbounded.
+            let mut per_bucket = vec![None; data_point.bounds.len() + 1];
+            for exemplar in exemplars {
+                let bucket = data_point
+                    .bounds
+                    .partition_point(|bound| *bound < exemplar.value);
+                per_bucket[bucket] = Some(exemplar.clone());
+            }
+            data_point.exemplars = per_bucket.into_iter().flatten().collect();
+        }
+    }
+}
+
+/// Drops the exemplars recorded before `now - MAX_AGE`, and the series left without any.
+fn forget_old(reservoir: &mut Reservoir, now: SystemTime) {
+    let Some(oldest) = now.checked_sub(MAX_AGE) else {
+        return;
+    };
+    reservoir.retain(|_name, series| {
+        series.retain(|_attributes, exemplars| {
+            while exemplars.front().is_some_and(|e| e.time < oldest) {
+                exemplars.pop_front();
+            }
+            !exemplars.is_empty()
+        });
+        !series.is_empty()
+    });
+}
+
+fn sorted(attributes: &[KeyValue]) -> Vec<KeyValue> {
+    let mut attributes = attributes.to_vec();
+    attributes.sort_by(|a, b| a.key.cmp(&b.key));
+    attributes
+}
+
+/// Wraps a metric exporter and attaches exemplars before every export.
+#[derive(Debug)]
+pub struct ExemplarExporter<E> {
+    inner: E,
+}
+
+impl<E> ExemplarExporter<E> {
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+}
+
+impl<E: PushMetricExporter> PushMetricExporter for ExemplarExporter<E> {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> OTelSdkResult {
+        attach(metrics);
+        self.inner.export(metrics).await
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.inner.shutdown()
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{
+        Resource, metrics::data::HistogramDataPoint, trace::SdkTracerProvider,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// A name of its own, the reservoir is shared with the other tests
+    const NAME: &str = "test.exemplars";
+
+    /// Attaches the exemplars to a data point of `NAME` with `attributes` and returns them
+    fn attached(attributes: &[KeyValue]) -> Vec<Exemplar<f64>> {
+        let data_point = HistogramDataPoint {
+            attributes: attributes.to_vec(),
+            count: 0,
+            bounds: vec![10.0, 100.0],
+            bucket_counts: vec![0; 3],
+            min: None,
+            max: None,
+            sum: 0.0,
+            exemplars: vec![],
+        };
+        let mut metrics = ResourceMetrics {
+            resource: Resource::builder_empty().build(),
+            scope_metrics: vec![data::ScopeMetrics {
+                metrics: vec![data::Metric {
+                    name: NAME.into(),
+                    description: "".into(),
+                    unit: "".into(),
+                    data: Box::new(data::Histogram {
+                        data_points: vec![data_point],
+                        start_time: SystemTime::now(),
+                        time: SystemTime::now(),
+                        temporality: Temporality::Cumulative,
+                    }),
+                }],
+                ..Default::default()
+            }],
+        };
+        attach(&mut metrics);
+
+        let data = data::Aggregation::as_mut(metrics.scope_metrics[0].metrics[0].data.as_mut());
+        let histogram = data.downcast_mut::<data::Histogram<f64>>().unwrap();
+        std::mem::take(&mut histogram.data_points[0].exemplars)
+    }
+
+    #[test]
+    fn attaches_recent_exemplars_of_sampled_spans_to_their_buckets() {
+        let histogram = opentelemetry::global::meter("test")
+            .f64_histogram(NAME)
+            .build();
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let attributes = [KeyValue::new("b", 2), KeyValue::new("a", 1)];
+        // Not within a span, so no exemplar
+        record(&histogram, NAME, 1.0, &attributes);
+        tracing::info_span!("request").in_scope(|| {
+            record(&histogram, NAME, 5.0, &attributes);
+            record(&histogram, NAME, 7.0, &attributes);
+            record(&histogram, NAME, 500.0, &attributes);
+        });
+
+        // The most recent one of each bucket, whatever the order of the attributes
+        let attributes = [KeyValue::new("a", 1), KeyValue::new("b", 2)];
+        let exemplars = attached(&attributes);
+        let values: Vec<_> = exemplars.iter().map(|e| e.value).collect();
+        assert_eq!(values, [7.0, 500.0]);
+        assert_ne!(exemplars[0].trace_id, [0; 16]);
+        assert!(attached(&[KeyValue::new("a", 1)]).is_empty());
+
+        // Once they are old, they are gone
+        for exemplars in RESERVOIR
+            .lock()
+            .unwrap()
+            .get_mut(NAME)
+            .unwrap()
+            .values_mut()
+        {
+            for exemplar in exemplars {
+                exemplar.time -= MAX_AGE * 2;
+            }
+        }
+        assert!(attached(&attributes).is_empty());
+        assert!(!RESERVOIR.lock().unwrap().contains_key(NAME));
+    }
+}
@@ -0,0 +1,736 @@
+//! Our users, served via gRPC.
+//!
+//! Internal callers would rather speak gRPC than HTTP, so next to the web server we serve the
+//! `UserService` of `proto/users.proto` on `GRPC_PORT`. It works on the same user stores as the
+//! `/users` routes and goes through the same middlewares: correlation IDs, authentication,
+//! tenants and caught panics. Every gRPC call is a `POST`, so every call needs credentials.
+//! Rejections of the middlewares are plain HTTP responses, which gRPC clients turn into a status,
+//! e.g. `UNAUTHENTICATED` for a `401`.
+//!
+//! Calls are traced like requests to the web server: each gets a `grpc_request` span, with the
+//! `rpc.*` attributes of the semantic conventions where `http_request` has the HTTP ones, and its
+//! latency ends up in the `rpc.server.latency` histogram.
+//!
+//! There is no `protoc` at build time, so instead of generating the messages and the service with
+//! `tonic-build`, we write them out with the derives of `prost`. [`UserService`] does what the
+//! generated server would do: dispatch a call by its path to the matching handler.
+
+// gRPC handlers fail with a `tonic::Status`, however large clippy finds it
+#![allow(clippy::result_large_err)]
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Body,
+    http::{HeaderMap, Request, Response},
+    middleware,
+};
+use futures_util::{
+    StreamExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
+use opentelemetry::KeyValue;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Status, body::BoxBody, codec::ProstCodec, server::Grpc};
+use tower::{Service, service_fn};
+use tower_http::{
+    classify::{GrpcCode, GrpcErrorsAsFailures, SharedClassifier},
+    trace::TraceLayer,
+};
+use tracing::{Span, debug, field::Empty, info, info_span, instrument, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, Authenticator},
+    business::{NameTaken, NewUser, QuotaExceeded, ReadUser, UserManager, ValidationErrors},
+    circuit_breaker::CircuitOpen,
+    events::{self, UserEventKind},
+    exemplars, http_client, panics,
+    server::{self, AppState, CorrelationId},
+    tenant::{self, TENANT_ATTRIBUTE, Tenant, Tenants},
+};
+use proto::{
+    CreateUserRequest, CreateUserResponse, GetUserRequest, ListUsersRequest, ListUsersResponse,
+    UserEvent, WatchUsersRequest, get_user_request::Key,
+};
+
+/// Package and name of the service, the first part of the path of every call
+pub const SERVICE_NAME: &str = "users.v1.UserService";
+
+/// Name of the histogram of our call latencies
+const LATENCY_HISTOGRAM: &str = "rpc.server.latency";
+
+/// The messages of `proto/users.proto`
+pub mod proto {
+    use crate::business;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct User {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(string, optional, tag = "3")]
+        pub email: Option<String>,
+        /// Milliseconds since the Unix epoch
+        #[prost(uint64, tag = "4")]
+        pub created_at: u64,
+        /// Milliseconds since the Unix epoch, the same as `created_at` until the first change
+        #[prost(uint64, tag = "5")]
+        pub updated_at: u64,
+    }
+
+    impl From<business::User> for User {
+        fn from(user: business::User) -> Self {
+            Self {
+                id: user.id.to_string(),
+                name: user.name,
+                email: user.email,
+                created_at: user.created_at,
+                updated_at: user.updated_at,
+            }
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateUserRequest {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, optional, tag = "2")]
+        pub email: Option<String>,
+        /// Makes the call safe to retry: the same key never creates a second user
+        #[prost(string, optional, tag = "3")]
+        pub idempotency_key: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateUserResponse {
+        #[prost(string, tag = "1")]
+        pub id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetUserRequest {
+        #[prost(oneof = "get_user_request::Key", tags = "1, 2")]
+        pub key: Option<get_user_request::Key>,
+    }
+
+    pub mod get_user_request {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Key {
+            #[prost(string, tag = "1")]
+            Id(String),
+            #[prost(string, tag = "2")]
+            Name(String),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListUsersRequest {
+        /// Only the user with this name
+        #[prost(string, optional, tag = "1")]
+        pub name: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListUsersResponse {
+        /// Ordered by name
+        #[prost(message, repeated, tag = "1")]
+        pub users: Vec<User>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WatchUsersRequest {
+        /// Resume after this event
+        #[prost(uint64, optional, tag = "1")]
+        pub last_event_id: Option<u64>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum UserEventKind {
+        Unspecified = 0,
+        Created = 1,
+        Updated = 2,
+        Deleted = 3,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UserEvent {
+        /// Increases by one with every event
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(enumeration = "UserEventKind", tag = "2")]
+        pub kind: i32,
+        /// The user after the change, or before it was deleted
+        #[prost(message, optional, tag = "3")]
+        pub user: Option<User>,
+    }
+}
+
+/// Builds the gRPC server, to be bound to a port of its own. It shares the state and the user
+/// stores with the web server.
+pub(crate) fn router(
+    state: AppState,
+    authenticator: Arc<Authenticator>,
+    tenants: Arc<Tenants>,
+) -> Router {
+    let latency_histogram = opentelemetry::global::meter("server_measurements")
+        .f64_histogram(LATENCY_HISTOGRAM)
+        .with_description("Latency of gRPC calls, until the response headers are sent")
+        .with_unit("us")
+        .build();
+    // -- Like the 4xx responses of the web server, these are the caller's fault, not ours
+    let classifier = [
+        GrpcCode::InvalidArgument,
+        GrpcCode::NotFound,
+        GrpcCode::PermissionDenied,
+        GrpcCode::ResourceExhausted,
+        GrpcCode::Unauthenticated,
+    ]
+    .into_iter()
+    .fold(
+        GrpcErrorsAsFailures::new(),
+        GrpcErrorsAsFailures::with_success,
+    );
+
+    Router::new()
+        .route_service(
+            &format!("/{SERVICE_NAME}/{{method}}"),
+            UserService { state },
+        )
+        // -- The same order as for the `/users` routes: who sent the call, then for which tenant
+        .route_layer(middleware::from_fn_with_state(tenants, tenant::identify))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator,
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn(panics::catch_panic))
+        .layer(
+            TraceLayer::new(SharedClassifier::new(classifier))
+                .make_span_with(|request: &Request<_>| {
+                    // -- The path of a call is `/{service}/{method}`
+                    let path = request.uri().path().trim_start_matches('/');
+                    let (service, method) = path.split_once('/').unwrap_or((path, ""));
+
+                    let span = info_span!(
+                        "grpc_request",
+                        rpc.system = "grpc",
+                        rpc.service = service,
+                        rpc.method = method,
+                        rpc.grpc.status_code = Empty,
+                        correlation_id = Empty,
+                        { TENANT_ATTRIBUTE } = Empty,
+                    );
+                    // -- Callers pass their trace context as metadata, which is just headers
+                    span.set_parent(http_client::extract_context(request.headers()));
+                    span
+                })
+                .on_request(|request: &Request<_>, span: &Span| {
+                    if let Some(CorrelationId(id)) = request.extensions().get() {
+                        span.record("correlation_id", id.as_str());
+                    }
+                })
+                .on_response(
+                    move |response: &Response<_>, latency: Duration, span: &Span| {
+                        // -- Calls that fail right away carry their status in the headers
+                        record_status(response.headers(), span);
+                        let attributes: Vec<_> = response
+                            .extensions()
+                            .get::<Tenant>()
+                            .map(|tenant| KeyValue::new(TENANT_ATTRIBUTE, tenant.to_string()))
+                            .into_iter()
+                            .collect();
+                        exemplars::record(
+                            &latency_histogram,
+                            LATENCY_HISTOGRAM,
+                            latency.as_micros() as f64,
+                            &attributes,
+                        );
+                        debug!("latency micros: {:#?}", latency.as_micros());
+                    },
+                )
+                // -- All other calls carry it in the trailers, after the last message
+                .on_eos(
+                    |trailers: Option<&HeaderMap>, _duration: Duration, span: &Span| {
+                        if let Some(trailers) = trailers {
+                            record_status(trailers, span);
+                        }
+                    },
+                ),
+        )
+        .layer(middleware::from_fn(server::correlation_id))
+}
+
+/// Records the `grpc-status` of a call on its span, if `headers` carry it.
+fn record_status(headers: &HeaderMap, span: &Span) {
+    let status = headers
+        .get(Status::GRPC_STATUS)
+        .and_then(|status| status.to_str().ok())
+        .and_then(|status| status.parse::<i64>().ok());
+    if let Some(status) = status {
+        span.record("rpc.grpc.status_code", status);
+    }
+}
+
+/// Maps errors of the user store to a status, like `store_error_status` does for HTTP.
+fn store_error(err: &anyhow::Error) -> Status {
+    if err.is::<CircuitOpen>() {
+        Status::unavailable("the user store is unavailable, try again later")
+    } else if err.is::<ValidationErrors>() {
+        Status::invalid_argument(err.to_string())
+    } else if err.is::<QuotaExceeded>() {
+        Status::resource_exhausted(err.to_string())
+    } else if err.is::<NameTaken>() {
+        Status::already_exists(err.to_string())
+    } else {
+        Status::internal("the user store failed")
+    }
+}
+
+/// The store of the tenant of a call, picked by `tenant::identify`
+fn store<T>(request: &tonic::Request<T>) -> Result<Arc<UserManager>, Status> {
+    request
+        .extensions()
+        .get::<Arc<UserManager>>()
+        .cloned()
+        .ok_or_else(|| Status::internal("the call belongs to no tenant"))
+}
+
+/// Serves the calls of `users.v1.UserService`.
+#[derive(Clone)]
+pub(crate) struct UserService {
+    state: AppState,
+}
+
+impl Service<Request<Body>> for UserService {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let service = self.clone();
+        let method = request
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or("")
+            .to_owned();
+        Box::pin(async move {
+            // -- Each method has messages of its own, and so a codec of its own
+            let response = match method.as_str() {
+                "CreateUser" => {
+                    let handler = |request| service.clone().create_user(request);
+                    Grpc::new(ProstCodec::default())
+                        .unary(service_fn(handler), request)
+                        .await
+                }
+                "GetUser" => {
+                    let handler = |request| service.clone().get_user(request);
+                    Grpc::new(ProstCodec::default())
+                        .unary(service_fn(handler), request)
+                        .await
+                }
+                "ListUsers" => {
+                    let handler = |request| service.clone().list_users(request);
+                    Grpc::new(ProstCodec::default())
+                        .unary(service_fn(handler), request)
+                        .await
+                }
+                "WatchUsers" => {
+                    let handler = |request| service.clone().watch_users(request);
+                    Grpc::new(ProstCodec::default())
+                        .server_streaming(service_fn(handler), request)
+                        .await
+                }
+                _ => Status::unimplemented(format!("{SERVICE_NAME} has no method `{method}`"))
+                    .into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+impl UserService {
+    #[instrument(skip_all, fields(name = request.get_ref().name.as_str()))]
+    async fn create_user(
+        self,
+        request: tonic::Request<CreateUserRequest>,
+    ) -> Result<tonic::Response<CreateUserResponse>, Status> {
+        info!("Create new user...");
+        let (user_manager, actor) = (store(&request)?, server::actor(request.extensions()));
+        let CreateUserRequest {
+            name,
+            email,
+            idempotency_key,
+        } = request.into_inner();
+
+        let mut new_user = NewUser::new(&name);
+        if let Some(email) = &email {
+            new_user = new_user.with_email(email);
+        }
+        let id = server::create_user(
+            &self.state,
+            &user_manager,
+            &actor,
+            new_user,
+            idempotency_key.as_deref(),
+        )
+        .await
+        .map_err(|e| store_error(&e))?;
+
+        Ok(tonic::Response::new(CreateUserResponse {
+            id: id.to_string(),
+        }))
+    }
+
+    #[instrument(skip_all, fields(name, user_uuid))]
+    async fn get_user(
+        self,
+        request: tonic::Request<GetUserRequest>,
+    ) -> Result<tonic::Response<proto::User>, Status> {
+        info!("Read user...");
+        let user_manager = store(&request)?;
+        let key = request
+            .into_inner()
+            .key
+            .ok_or_else(|| Status::invalid_argument("either `id` or `name` is required"))?;
+
+        let (user_manager, breaker) = (&user_manager, &self.state.circuit_breaker);
+        let user = match &key {
+            Key::Id(id) => {
+                let id = Uuid::parse_str(id)
+                    .map_err(|e| Status::invalid_argument(format!("invalid id: {e}")))?;
+                Span::current().record("user_uuid", id.to_string());
+                self.state
+                    .retry
+                    .run("read_user", || async move {
+                        breaker.call(|| user_manager.read_by_id(id))
+                    })
+                    .await
+            }
+            Key::Name(name) => {
+                Span::current().record("name", name.as_str());
+                self.state
+                    .retry
+                    .run("read_user", || async move {
+                        breaker.call(|| user_manager.read_by_name(ReadUser::new(name)))
+                    })
+                    .await
+            }
+        };
+
+        let user = match user {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(Status::not_found("no user found")),
+            Err(e) => {
+                warn!("Could not read user:\n{e:?}");
+                return Err(store_error(&e));
+            }
+        };
+        Span::current().record("user_uuid", user.id.to_string());
+
+        Ok(tonic::Response::new(user.into()))
+    }
+
+    #[instrument(skip_all, fields(users))]
+    async fn list_users(
+        self,
+        request: tonic::Request<ListUsersRequest>,
+    ) -> Result<tonic::Response<ListUsersResponse>, Status> {
+        info!("List users...");
+        let user_manager = store(&request)?;
+        let ListUsersRequest { name } = request.into_inner();
+
+        let (user_manager, breaker) = (&user_manager, &self.state.circuit_breaker);
+        let users = self
+            .state
+            .retry
+            .run("list_users", || async move {
+                breaker.call(|| user_manager.list())
+            })
+            .await;
+
+        let mut users = users.map_err(|e| {
+            warn!("Could not list users:\n{e:?}");
+            store_error(&e)
+        })?;
+        if let Some(name) = &name {
+            users.retain(|user| &user.name == name);
+        }
+        Span::current().record("users", users.len());
+
+        Ok(tonic::Response::new(ListUsersResponse {
+            users: users.into_iter().map(proto::User::from).collect(),
+        }))
+    }
+
+    #[instrument(skip_all, fields(last_event_id))]
+    async fn watch_users(
+        self,
+        request: tonic::Request<WatchUsersRequest>,
+    ) -> Result<tonic::Response<BoxStream<'static, Result<UserEvent, Status>>>, Status> {
+        let user_manager = store(&request)?;
+        let last_event_id = request.into_inner().last_event_id;
+        if let Some(id) = last_event_id {
+            Span::current().record("last_event_id", id);
+        }
+
+        let (missed, receiver) = user_manager.events().subscribe(last_event_id);
+        info!(missed = missed.len(), "Subscribe to user events...");
+
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event, receiver)),
+                // Like the SSE clients of the web server, a caller that fell behind is
+                // disconnected and resumes with the ID of the last event it got.
+                Err(RecvError::Lagged(_) | RecvError::Closed) => None,
+            }
+        });
+        let events = stream::iter(missed)
+            .chain(live)
+            .map(|event| Ok(user_event(event)))
+            .boxed();
+
+        Ok(tonic::Response::new(events))
+    }
+}
+
+/// Every event we send gets its own span, linked to the call that caused the change.
+fn user_event(event: events::UserEvent) -> UserEvent {
+    let span = event.send_span();
+    let _guard = span.enter();
+
+    let kind = match event.kind {
+        UserEventKind::Created => proto::UserEventKind::Created,
+        UserEventKind::Updated => proto::UserEventKind::Updated,
+        UserEventKind::Deleted => proto::UserEventKind::Deleted,
+    };
+    UserEvent {
+        id: event.id,
+        kind: kind.into(),
+        user: Some(event.user.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use prost::Message;
+
+    use super::proto::*;
+
+    /// Field numbers and wire types of `proto/users.proto`, by message and field, and the values
+    /// of its enums
+    fn declared() -> HashMap<(String, String), (u32, u64)> {
+        let proto = include_str!("../proto/users.proto");
+        let mut declared = HashMap::new();
+        let mut scopes: Vec<String> = vec![];
+        for line in proto.lines() {
+            let line = line.split("//").next().unwrap().trim();
+            if let Some(scope) = line.strip_suffix('{') {
+                scopes.push(scope.trim().to_owned());
+            } else if line == "}" {
+                scopes.pop();
+            } else if let Some(declaration) = line.strip_suffix(';')
+                && let Some((field, number)) = declaration.split_once('=')
+                && let Some(scope) = scopes.iter().find(|scope| !scope.starts_with("oneof"))
+            {
+                let field: Vec<_> = field.split_whitespace().collect();
+                let (kind, name) = scope.split_once(' ').unwrap();
+                let wire_type = match field[..field.len() - 1].last().copied() {
+                    _ if kind == "enum" => 0,
+                    Some("uint64" | "UserEventKind") => 0,
+                    _ => 2,
+                };
+                declared.insert(
+                    (name.to_owned(), field[field.len() - 1].to_owned()),
+                    (number.trim().parse().unwrap(), wire_type),
+                );
+            }
+        }
+        declared
+    }
+
+    /// The field number and wire type of the only field in `encoded`
+    fn key(encoded: &[u8]) -> (u32, u64) {
+        let key = prost::encoding::decode_varint(&mut &encoded[..]).unwrap();
+        ((key >> 3) as u32, key & 0b111)
+    }
+
+    #[test]
+    fn messages_match_the_proto_file() {
+        let text = || "x".to_owned();
+        let user = || User {
+            id: text(),
+            ..User::default()
+        };
+        let encoded: Vec<(&str, &str, Vec<u8>)> = vec![
+            ("User", "id", user().encode_to_vec()),
+            (
+                "User",
+                "name",
+                User {
+                    name: text(),
+                    ..User::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "User",
+                "email",
+                User {
+                    email: Some(text()),
+                    ..User::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "User",
+                "created_at",
+                User {
+                    created_at: 1,
+                    ..User::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "User",
+                "updated_at",
+                User {
+                    updated_at: 1,
+                    ..User::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "CreateUserRequest",
+                "name",
+                CreateUserRequest {
+                    name: text(),
+                    ..CreateUserRequest::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "CreateUserRequest",
+                "email",
+                CreateUserRequest {
+                    email: Some(text()),
+                    ..CreateUserRequest::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "CreateUserRequest",
+                "idempotency_key",
+                CreateUserRequest {
+                    idempotency_key: Some(text()),
+                    ..CreateUserRequest::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "CreateUserResponse",
+                "id",
+                CreateUserResponse { id: text() }.encode_to_vec(),
+            ),
+            (
+                "GetUserRequest",
+                "id",
+                GetUserRequest {
+                    key: Some(get_user_request::Key::Id(text())),
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "GetUserRequest",
+                "name",
+                GetUserRequest {
+                    key: Some(get_user_request::Key::Name(text())),
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "ListUsersRequest",
+                "name",
+                ListUsersRequest { name: Some(text()) }.encode_to_vec(),
+            ),
+            (
+                "ListUsersResponse",
+                "users",
+                ListUsersResponse {
+                    users: vec![user()],
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "WatchUsersRequest",
+                "last_event_id",
+                WatchUsersRequest {
+                    last_event_id: Some(1),
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "UserEvent",
+                "id",
+                UserEvent {
+                    id: 1,
+                    ..UserEvent::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "UserEvent",
+                "kind",
+                UserEvent {
+                    kind: 1,
+                    ..UserEvent::default()
+                }
+                .encode_to_vec(),
+            ),
+            (
+                "UserEvent",
+                "user",
+                UserEvent {
+                    user: Some(user()),
+                    ..UserEvent::default()
+                }
+                .encode_to_vec(),
+            ),
+        ];
+        let values = [
+            ("USER_EVENT_KIND_UNSPECIFIED", UserEventKind::Unspecified),
+            ("USER_EVENT_KIND_CREATED", UserEventKind::Created),
+            ("USER_EVENT_KIND_UPDATED", UserEventKind::Updated),
+            ("USER_EVENT_KIND_DELETED", UserEventKind::Deleted),
+        ];
+
+        let declared = declared();
+        for (message, field, encoded) in &encoded {
+            let declaration = declared.get(&(message.to_string(), field.to_string()));
+            assert_eq!(declaration, Some(&key(encoded)), "{message}.{field}");
+        }
+        for (name, value) in values {
+            let declaration = declared.get(&("UserEventKind".to_owned(), name.to_owned()));
+            assert_eq!(declaration, Some(&(value as u32, 0)), "{name}");
+        }
+        // Every field of the proto file is covered above
+        assert_eq!(declared.len(), encoded.len() + values.len());
+    }
+}
@@ -0,0 +1,148 @@
+//! Calling other services without losing the trace.
+//!
+//! Within one process, spans find their parent on their own. Once a request leaves the process,
+//! the trace context has to travel with it: [`TracedClient`] writes it into the W3C `traceparent`
+//! header of every request it sends, and the server on the other end picks it up again with
+//! [`extract_context`]. Both services then add their spans to the same trace.
+//!
+//! Every request gets an `http_client_request` span of kind `client`, with the attributes the
+//! OpenTelemetry semantic conventions define for HTTP clients.
+
+use std::{fmt, time::Duration};
+
+use anyhow::Context;
+use axum::http::HeaderMap;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+#[cfg(feature = "semconv-experimental")]
+use opentelemetry_semantic_conventions::trace::URL_TEMPLATE;
+use opentelemetry_semantic_conventions::trace::{
+    ERROR_TYPE, HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, SERVER_ADDRESS, SERVER_PORT,
+    URL_SCHEME,
+};
+use reqwest::{Url, header::HeaderValue};
+use tracing::{Instrument, field::Empty, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::server::CORRELATION_ID_HEADER;
+
+/// Not part of the stable semantic conventions yet
+#[cfg(not(feature = "semconv-experimental"))]
+const URL_TEMPLATE: &str = "url.template";
+
+/// An HTTP client that continues the current trace in the services it calls.
+pub struct TracedClient {
+    http: reqwest::Client,
+}
+
+impl TracedClient {
+    /// A client that gives up on requests after `timeout`.
+    pub fn new(timeout: Duration) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .context("could not build HTTP client")?;
+        Ok(Self { http })
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Sends `request` within an `http_client_request` span, along with the trace context and the
+    /// `correlation_id` of the request we are handling.
+    ///
+    /// Our URLs contain user names, so the span gets the `url_template` (e.g. `/enrichment/{name}`)
+    /// instead of the full URL. Error responses are returned as they are, but mark the span as
+    /// failed.
+    pub async fn send(
+        &self,
+        mut request: reqwest::Request,
+        url_template: &str,
+        correlation_id: Option<&str>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = request.url();
+        let span = info_span!(
+            "http_client_request",
+            otel.name = %request.method(),
+            otel.kind = "client",
+            otel.status_code = Empty,
+            { HTTP_REQUEST_METHOD } = %request.method(),
+            { URL_SCHEME } = url.scheme(),
+            { URL_TEMPLATE } = url_template,
+            { SERVER_ADDRESS } = url.host_str(),
+            { SERVER_PORT } = url.port_or_known_default(),
+            { HTTP_RESPONSE_STATUS_CODE } = Empty,
+            { ERROR_TYPE } = Empty,
+        );
+
+        // The callee's spans become children of our client span
+        TraceContextPropagator::new()
+            .inject_context(&span.context(), &mut HeaderInjector(request.headers_mut()));
+        if let Some(id) = correlation_id.and_then(|id| HeaderValue::from_str(id).ok()) {
+            request.headers_mut().insert(CORRELATION_ID_HEADER, id);
+        }
+
+        let result = self.http.execute(request).instrument(span.clone()).await;
+        match &result {
+            Ok(response) => {
+                let status = response.status();
+                span.record(HTTP_RESPONSE_STATUS_CODE, status.as_u16());
+                // For clients, the conventions count 4xx responses as errors, too
+                if status.is_client_error() || status.is_server_error() {
+                    span.record(ERROR_TYPE, status.as_str());
+                    span.record("otel.status_code", "ERROR");
+                }
+            }
+            Err(e) => {
+                let error_type = if e.is_timeout() {
+                    "timeout"
+                } else if e.is_connect() {
+                    "connect"
+                } else {
+                    "_OTHER"
+                };
+                span.record(ERROR_TYPE, error_type);
+                span.record("otel.status_code", "ERROR");
+                span.in_scope(|| warn!("Request failed: {e}"));
+            }
+        }
+        result.context("request failed")
+    }
+}
+
+/// The URL of another service, which we append the paths of its routes to
+#[derive(Debug, Clone)]
+pub struct BaseUrl(Url);
+
+impl BaseUrl {
+    /// Fails unless `url` can have a path, e.g. `http://localhost:5173`
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let parsed = Url::parse(url).with_context(|| format!("invalid URL {url}"))?;
+        anyhow::ensure!(!parsed.cannot_be_a_base(), "invalid URL {url}");
+        Ok(Self(parsed))
+    }
+
+    /// The URL with `segments` appended, each of them percent-encoded
+    pub fn join(&self, segments: &[&str]) -> Url {
+        let mut url = self.0.clone();
+        url.path_segments_mut()
+            .expect("checked in `parse`")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+}
+
+impl fmt::Display for BaseUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The trace context a caller sent along with its request. Without one, the context is empty and
+/// our spans start a new trace.
+pub fn extract_context(headers: &HeaderMap) -> opentelemetry::Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
@@ -0,0 +1,380 @@
+//! Reading users from a bulk upload.
+//!
+//! `POST /users/import` accepts a CSV file with a `name` and an optional `email` column, or NDJSON
+//! with one `{"name": ..., "email": ...}` object per line. Uploads can be large, so we never hold
+//! more than a chunk and one line of it in memory: [`Rows`] pulls chunks from the request body as
+//! it needs them and hands out one row at a time.
+//!
+//! Rows that cannot be read do not abort the import, they show up as errors in the
+//! [`ImportReport`] instead. Only an upload we cannot read on aborts it: one that breaks off, has a
+//! line that is too long or is not valid UTF-8. The report then lists the rows before, and why the
+//! import was aborted.
+
+use anyhow::{Context, anyhow, bail};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, header::CONTENT_TYPE},
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::business::NewUser;
+
+/// Longest line we accept, so a body without line breaks cannot make us buffer all of it
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// The format announced in the `Content-Type` header, if we support it
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim();
+        match mime {
+            "text/csv" => Some(Format::Csv),
+            "application/x-ndjson" | "application/jsonl" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// A row of the upload
+#[derive(Debug)]
+pub struct Row {
+    /// Counted from 1, not counting the CSV header and empty lines
+    pub number: usize,
+    /// The user to create, or why we could not read it
+    pub user: anyhow::Result<NewUser>,
+}
+
+/// What happened to every row of an import
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ImportReport {
+    /// Number of users created
+    pub created: usize,
+    /// Number of rows that did not result in a user
+    pub failed: usize,
+    pub rows: Vec<RowReport>,
+    /// Why the import stopped before the end of the upload. The rows after it were not read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aborted: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RowReport {
+    pub row: usize,
+    /// ID of the created user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// Why no user was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportReport {
+    pub fn record(&mut self, row: usize, result: anyhow::Result<Uuid>) {
+        let report = match result {
+            Ok(id) => {
+                self.created += 1;
+                RowReport {
+                    row,
+                    id: Some(id),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                self.failed += 1;
+                RowReport {
+                    row,
+                    id: None,
+                    error: Some(format!("{e:#}")),
+                }
+            }
+        };
+        self.rows.push(report);
+    }
+}
+
+#[derive(Deserialize)]
+struct NdjsonRow {
+    name: String,
+    email: Option<String>,
+}
+
+/// Reads rows from a stream of body chunks.
+pub struct Rows<S> {
+    chunks: S,
+    format: Format,
+    /// The last chunk we received, plus what was left of the line before it
+    pending: Vec<u8>,
+    /// Where the unprocessed part of `pending` starts
+    start: usize,
+    /// Lines we handed out so far, including the CSV header and empty lines
+    lines: usize,
+    done: bool,
+    /// Index of the `name` column, known after we read the CSV header
+    name_column: Option<usize>,
+    /// Index of the `email` column, if the CSV header has one
+    email_column: Option<usize>,
+    number: usize,
+}
+
+impl<S, E> Rows<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(chunks: S, format: Format) -> Self {
+        Self {
+            chunks,
+            format,
+            pending: vec![],
+            start: 0,
+            lines: 0,
+            done: false,
+            name_column: None,
+            email_column: None,
+            number: 0,
+        }
+    }
+
+    /// The next row, `None` at the end of the upload. Errors abort the import: the upload broke
+    /// off, a line is too long, the upload is not valid UTF-8 or the CSV header has no `name`
+    /// column.
+    pub async fn next(&mut self) -> anyhow::Result<Option<Row>> {
+        loop {
+            let Some(line) = self.next_line().await? else {
+                return Ok(None);
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let row = match self.format {
+                Format::Ndjson => serde_json::from_str::<NdjsonRow>(&line).context("invalid JSON"),
+                Format::Csv => {
+                    let fields = csv_fields(&line);
+                    let Some(name_column) = self.name_column else {
+                        let column = |name: &str| {
+                            fields
+                                .iter()
+                                .position(|field| field.trim().eq_ignore_ascii_case(name))
+                        };
+                        self.name_column =
+                            Some(column("name").context("the CSV header has no `name` column")?);
+                        self.email_column = column("email");
+                        continue;
+                    };
+                    let email = self
+                        .email_column
+                        .and_then(|column| fields.get(column))
+                        .filter(|email| !email.trim().is_empty())
+                        .cloned();
+                    fields
+                        .into_iter()
+                        .nth(name_column)
+                        .map(|name| NdjsonRow { name, email })
+                        .ok_or_else(|| anyhow!("the row has no `name` column"))
+                }
+            };
+
+            self.number += 1;
+            let user = row.and_then(|row| {
+                let name = row.name.trim();
+                if name.is_empty() {
+                    bail!("the name is empty");
+                }
+                let user = NewUser::new(name);
+                Ok(match row.email {
+                    Some(email) => user.with_email(email.trim()),
+                    None => user,
+                })
+            });
+            return Ok(Some(Row {
+                number: self.number,
+                user,
+            }));
+        }
+    }
+
+    async fn next_line(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            let rest = &self.pending[self.start..];
+            if let Some(end) = rest.iter().position(|b| *b == b'\n') {
+                let line = decode_line(&rest[..=end]);
+                self.start += end + 1;
+                self.lines += 1;
+                return line.map(Some);
+            }
+            if rest.len() > MAX_LINE_BYTES {
+                bail!(
+                    "line {} is longer than {MAX_LINE_BYTES} bytes",
+                    self.lines + 1
+                );
+            }
+            if self.done {
+                if rest.is_empty() {
+                    return Ok(None);
+                }
+                let line = decode_line(rest);
+                self.start = self.pending.len();
+                self.lines += 1;
+                return line.map(Some);
+            }
+
+            match self.chunks.next().await {
+                Some(chunk) => {
+                    let chunk = chunk.context("could not read the upload")?;
+                    // Only the start of a line is left of the previous chunks, move it to the front
+                    self.pending.drain(..self.start);
+                    self.start = 0;
+                    self.pending.extend_from_slice(&chunk);
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+fn decode_line(line: &[u8]) -> anyhow::Result<String> {
+    let line = std::str::from_utf8(line).context("the upload is not valid UTF-8")?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Splits a CSV line into its fields. Fields may be quoted, `""` within quotes is a literal `"`.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use futures_util::stream;
+
+    use super::*;
+
+    /// Feeds `chunks` to [`Rows`] and collects the names, errors as `Err(message)`
+    async fn read(
+        format: Format,
+        chunks: &[&'static str],
+    ) -> anyhow::Result<Vec<Result<String, String>>> {
+        let chunks = stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, Infallible>(Bytes::from_static(chunk.as_bytes()))),
+        );
+        let mut rows = Rows::new(chunks, format);
+        let mut names = vec![];
+        while let Some(row) = rows.next().await? {
+            assert_eq!(row.number, names.len() + 1);
+            names.push(
+                row.user
+                    .map(|user| user.name().to_owned())
+                    .map_err(|e| format!("{e:#}")),
+            );
+        }
+        Ok(names)
+    }
+
+    #[tokio::test]
+    async fn reads_csv_split_across_chunks() {
+        let names = read(
+            Format::Csv,
+            &[
+                "id,Name\r\n1,mert\r\n2,\"Ferris",
+                " \"\"the crab\"\"\"\r\n",
+                "\n3,\n4",
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            names,
+            [
+                Ok("mert".to_owned()),
+                Ok("Ferris \"the crab\"".to_owned()),
+                Err("the name is empty".to_owned()),
+                Err("the row has no `name` column".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_ndjson_rows() {
+        let names = read(
+            Format::Ndjson,
+            &["{\"name\":\"mert\"}\n{\"nam", "e\":\"ferris\"}\nnot json\n"],
+        )
+        .await
+        .unwrap();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], Ok("mert".to_owned()));
+        assert_eq!(names[1], Ok("ferris".to_owned()));
+        assert!(names[2].as_ref().unwrap_err().starts_with("invalid JSON"));
+    }
+
+    #[tokio::test]
+    async fn rejects_csv_without_name_column() {
+        let error = read(Format::Csv, &["id,email\n1,mert@example.com\n"])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "the CSV header has no `name` column");
+    }
+
+    #[tokio::test]
+    async fn reports_the_line_number_of_long_lines() {
+        let long = "a".repeat(MAX_LINE_BYTES + 1).leak();
+        let error = read(Format::Csv, &["name\n\nmert\n", long])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("line 4 is longer than {MAX_LINE_BYTES} bytes")
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_optional_emails() {
+        let chunks = stream::iter([Ok::<_, Infallible>(Bytes::from_static(
+            b"email,name\nmert@example.com,mert\n,ferris\n",
+        ))]);
+        let mut rows = Rows::new(chunks, Format::Csv);
+        let mert = rows.next().await.unwrap().unwrap().user.unwrap();
+        let ferris = rows.next().await.unwrap().unwrap().user.unwrap();
+        assert_eq!(
+            (mert.name(), mert.email()),
+            ("mert", Some("mert@example.com"))
+        );
+        assert_eq!((ferris.name(), ferris.email()), ("ferris", None));
+    }
+}
@@ -0,0 +1,310 @@
+//! Work that happens after the response is sent.
+//!
+//! Some work does not need to hold up the request, e.g. welcoming a new user. Handlers hand it to
+//! the [`JobQueue`] instead, and one of its workers runs it a moment later.
+//!
+//! A job is not part of the request that enqueued it: the request may be long gone when the job
+//! runs, and a span that outlives its parent makes for a confusing waterfall. So every job gets a
+//! `run_job` span that is the root of a trace of its own, with a _link_ to the span that enqueued
+//! it. In Tempo, the link takes you from the job to the request and back. A job that panics fails
+//! like one that returns an error, and its worker carries on. As the panic is caught, the panic
+//! hook of `panics.rs` logs it without flushing the telemetry pipeline.
+//!
+//! The queue exports its depth as the `jobs.queue.depth` gauge, how long jobs waited as the
+//! `jobs.wait_time` histogram and how long they ran as the `jobs.duration` histogram.
+
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use opentelemetry::{
+    KeyValue,
+    metrics::{Histogram, ObservableGauge},
+    trace::{SpanContext, TraceContextExt},
+};
+use tokio::sync::{Mutex, mpsc};
+use tracing::{Instrument, field::Empty, info, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use crate::{
+    cfg::JobsCfg,
+    exemplars, panics,
+    tenant::{self, TENANT_ATTRIBUTE, Tenant},
+};
+
+/// Name of the histogram that records how long jobs waited in the queue
+const WAIT_TIME_HISTOGRAM: &str = "jobs.wait_time";
+
+/// Name of the histogram that records how long jobs ran
+const DURATION_HISTOGRAM: &str = "jobs.duration";
+
+type Work = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+struct Job {
+    name: &'static str,
+    work: Work,
+    enqueued_at: Instant,
+    /// The span that enqueued the job
+    link: SpanContext,
+    tenant: Option<Tenant>,
+}
+
+/// Runs jobs on a fixed number of workers, in the order they were enqueued.
+pub struct JobQueue {
+    sender: mpsc::Sender<Job>,
+    depth: Arc<AtomicU64>,
+    _depth_gauge: ObservableGauge<u64>,
+}
+
+impl JobQueue {
+    /// Starts the workers, so this has to be called within a tokio runtime.
+    pub fn start(cfg: JobsCfg) -> Self {
+        let (sender, receiver) = mpsc::channel(cfg.queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let depth = Arc::new(AtomicU64::new(0));
+
+        let meter = opentelemetry::global::meter("server_measurements");
+        let observed = depth.clone();
+        let depth_gauge = meter
+            .u64_observable_gauge("jobs.queue.depth")
+            .with_description("Number of jobs waiting for a worker")
+            .with_callback(move |observer| observer.observe(observed.load(Ordering::Relaxed), &[]))
+            .build();
+        let histograms = Arc::new(Histograms {
+            wait_time: meter
+                .f64_histogram(WAIT_TIME_HISTOGRAM)
+                .with_description("Time jobs waited in the queue")
+                .with_unit("ms")
+                .build(),
+            duration: meter
+                .f64_histogram(DURATION_HISTOGRAM)
+                .with_description("Time jobs ran")
+                .with_unit("ms")
+                .build(),
+        });
+
+        for _ in 0..cfg.workers {
+            let (receiver, depth, histograms) =
+                (receiver.clone(), depth.clone(), histograms.clone());
+            tokio::spawn(async move {
+                loop {
+                    // Only one idle worker waits for the next job at a time
+                    let Some(job) = receiver.lock().await.recv().await else {
+                        return;
+                    };
+                    depth.fetch_sub(1, Ordering::Relaxed);
+                    run(job, &histograms).await;
+                }
+            });
+        }
+
+        Self {
+            sender,
+            depth,
+            _depth_gauge: depth_gauge,
+        }
+    }
+
+    /// Enqueues `work`, linked to the current span and tagged with the current tenant. Fails if
+    /// the queue is full, as we would rather drop a job than hold up the request.
+    pub fn enqueue(
+        &self,
+        name: &'static str,
+        work: impl Future<Output = anyhow::Result<()>> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let job = Job {
+            name,
+            work: Box::pin(work),
+            enqueued_at: Instant::now(),
+            link: tracing::Span::current()
+                .context()
+                .span()
+                .span_context()
+                .clone(),
+            tenant: tenant::current(),
+        };
+        // Counted before the send, so a worker that picks the job up right away never sees 0
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.try_send(job).map_err(|e| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            anyhow!("could not enqueue job {name}: {e}")
+        })?;
+        info!(job.name = name, "Enqueued job");
+        Ok(())
+    }
+}
+
+struct Histograms {
+    wait_time: Histogram<f64>,
+    duration: Histogram<f64>,
+}
+
+async fn run(job: Job, histograms: &Histograms) {
+    // Within the scope of the tenant, so the `TenantLayer` adds it to the span
+    tenant::scope(job.tenant.clone(), run_in_scope(job, histograms)).await;
+}
+
+async fn run_in_scope(job: Job, histograms: &Histograms) {
+    let wait_time = job.enqueued_at.elapsed();
+    // `parent: None` makes this span the root of a new trace, the link leads back to the request
+    let span = info_span!(
+        parent: None,
+        "run_job",
+        job.name = job.name,
+        job.id = %Uuid::new_v4(),
+        job.wait_ms = millis(wait_time),
+        otel.status_code = Empty,
+    );
+    span.add_link(job.link);
+
+    let started_at = Instant::now();
+    // A task of its own, so a job that panics takes down neither the worker nor the metrics
+    let work = tenant::scope(job.tenant.clone(), job.work.instrument(span.clone()));
+    let result = tokio::spawn(panics::caught(work)).await;
+    let duration = started_at.elapsed();
+
+    let _guard = span.enter();
+    let outcome = match result {
+        Ok(Ok(())) => "success",
+        Ok(Err(e)) => {
+            span.record("otel.status_code", "ERROR");
+            warn!("Job failed:\n{e:?}");
+            "failure"
+        }
+        Err(e) => {
+            span.record("otel.status_code", "ERROR");
+            warn!("Job panicked: {e}");
+            "failure"
+        }
+    };
+    let mut attributes: Vec<_> = job
+        .tenant
+        .map(|tenant| KeyValue::new(TENANT_ATTRIBUTE, tenant.to_string()))
+        .into_iter()
+        .collect();
+    attributes.push(KeyValue::new("job.name", job.name));
+    exemplars::record(
+        &histograms.wait_time,
+        WAIT_TIME_HISTOGRAM,
+        millis(wait_time),
+        &attributes,
+    );
+    attributes.push(KeyValue::new("job.outcome", outcome));
+    exemplars::record(
+        &histograms.duration,
+        DURATION_HISTOGRAM,
+        millis(duration),
+        &attributes,
+    );
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000.0
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tokio::sync::oneshot;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::test_util::{CollectSpans, eventually};
+
+    #[tokio::test]
+    async fn jobs_link_to_the_span_that_enqueued_them() {
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // The test runs on a single thread, so the workers report to this subscriber, too
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let queue = JobQueue::start(JobsCfg {
+            workers: 1,
+            queue_capacity: 1,
+        });
+        let (done, finished) = oneshot::channel();
+        let request = info_span!("http_request");
+        request.in_scope(|| {
+            queue
+                .enqueue("welcome_user", async move {
+                    info_span!("send_email").in_scope(|| {});
+                    done.send(()).unwrap();
+                    Ok(())
+                })
+                .unwrap();
+            // The worker did not get to run yet, so the queue is full
+            assert!(queue.enqueue("welcome_user", async { Ok(()) }).is_err());
+        });
+        drop(request);
+        finished.await.unwrap();
+
+        let spans = collect.0.lock().unwrap();
+        let span = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+        let (request, job, email) = (span("http_request"), span("run_job"), span("send_email"));
+
+        assert_ne!(job.span_context.trace_id(), request.span_context.trace_id());
+        assert_eq!(job.links.links.len(), 1);
+        let link = &job.links.links[0].span_context;
+        assert_eq!(link.trace_id(), request.span_context.trace_id());
+        assert_eq!(link.span_id(), request.span_context.span_id());
+        // The work of the job belongs to its trace
+        assert_eq!(email.parent_span_id, job.span_context.span_id());
+    }
+
+    #[tokio::test]
+    async fn jobs_that_panic_fail_without_taking_the_worker_down() {
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let queue = JobQueue::start(JobsCfg {
+            workers: 1,
+            queue_capacity: 2,
+        });
+        let (done, finished) = oneshot::channel();
+        queue
+            .enqueue("panic", async { panic!("the job panicked") })
+            .unwrap();
+        queue
+            .enqueue("welcome_user", async move {
+                done.send(()).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        finished.await.unwrap();
+        // The worker closes the span of the job after the job is done
+        eventually(|| (collect.0.lock().unwrap().len() == 2).then_some(())).await;
+
+        let spans = collect.0.lock().unwrap();
+        let status = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.attributes.iter().any(|kv| kv.value.as_str() == name))
+                .map(|span| span.status.clone())
+        };
+        assert!(matches!(
+            status("panic"),
+            Some(opentelemetry::trace::Status::Error { .. })
+        ));
+        assert_eq!(
+            status("welcome_user"),
+            Some(opentelemetry::trace::Status::Unset)
+        );
+    }
+}
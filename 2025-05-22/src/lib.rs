@@ -0,0 +1,37 @@
+//! The building blocks of our demo web server. `main.rs` puts them together, the benchmarks in
+//! `benches/` poke at them individually.
+
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod business;
+pub mod cfg;
+pub mod circuit_breaker;
+#[cfg(feature = "otlp-grpc")]
+pub mod disk_buffer;
+pub mod enrichment;
+pub mod events;
+pub mod exemplars;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http_client;
+pub mod import;
+pub mod jobs;
+pub mod log_format;
+pub mod openapi;
+#[cfg(feature = "prometheus")]
+pub mod openmetrics;
+pub mod otel;
+pub mod panics;
+pub mod pipeline;
+#[cfg(target_os = "linux")]
+pub mod profiling;
+#[cfg(feature = "otlp-grpc")]
+pub mod receiver;
+pub mod redaction;
+pub mod retry;
+pub mod server;
+pub mod tail_sampling;
+pub mod tenant;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,321 @@
+//! Log lines that lead to their trace.
+//!
+//! A log line names the spans it was logged in, but Tempo finds traces by their ID. So every line
+//! logged within a span also carries the `trace_id` and `span_id` OpenTelemetry assigned to that
+//! span, ready to be pasted into Tempo's search.
+//!
+//! [`LogLines`] writes the lines in one of two [`LogFormat`]s, picked with `LOG_FORMAT`:
+//! - `pretty`: the default format of `tracing_subscriber::fmt`, ending in
+//!   `trace_id=... span_id=...`
+//! - `json`: one JSON object per line, with `trace_id` and `span_id` keys
+//!
+//! Both redact the fields that hold personal data, see `redaction.rs`.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+        format::{Format, Writer},
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+use crate::redaction::Redactor;
+
+/// How log lines look
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// For humans
+    #[default]
+    Pretty,
+    /// For log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format `{s}`, expected `pretty` or `json`"),
+        }
+    }
+}
+
+/// Formats log lines in a [`LogFormat`], along with the IDs of the span they were logged in.
+///
+/// Span fields are formatted by the field formatter of the layer, which should be a
+/// `RedactingFields`. JSON lines carry them as text, the way the other formats print them.
+pub struct LogLines {
+    format: LogFormat,
+    redactor: Redactor,
+    pretty: Format,
+}
+
+impl LogLines {
+    pub fn new(format: LogFormat, redactor: Redactor) -> Self {
+        Self {
+            format,
+            redactor,
+            pretty: Format::default().with_line_number(true),
+        }
+    }
+
+    fn format_json<S, N>(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: &mut Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        N: for<'a> FormatFields<'a> + 'static,
+    {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+        if let Some(number) = metadata.line() {
+            line.insert("line_number".into(), number.into());
+        }
+
+        let mut fields = JsonVisitor {
+            fields: Map::new(),
+            redactor: &self.redactor,
+        };
+        event.record(&mut fields);
+        line.insert("fields".into(), fields.fields.into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans = scope
+                .from_root()
+                .map(|span| {
+                    let mut json = Map::new();
+                    json.insert("name".into(), span.name().into());
+                    if let Some(fields) = span.extensions().get::<FormattedFields<N>>()
+                        && !fields.is_empty()
+                    {
+                        json.insert("fields".into(), fields.as_str().into());
+                    }
+                    Value::Object(json)
+                })
+                .collect::<Vec<_>>();
+            line.insert("spans".into(), spans.into());
+        }
+
+        if let Some((trace_id, span_id)) = trace_ids(ctx) {
+            line.insert("trace_id".into(), trace_id.to_string().into());
+            line.insert("span_id".into(), span_id.to_string().into());
+        }
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for LogLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match self.format {
+            LogFormat::Pretty => {
+                let Some((trace_id, span_id)) = trace_ids(ctx) else {
+                    return self.pretty.format_event(ctx, writer, event);
+                };
+                // The IDs go at the end of the line, so we need the line before we write it
+                let mut line = String::new();
+                self.pretty
+                    .clone()
+                    .with_ansi(writer.has_ansi_escapes())
+                    .format_event(ctx, Writer::new(&mut line), event)?;
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                writeln!(writer, "{line} trace_id={trace_id} span_id={span_id}")
+            }
+            LogFormat::Json => self.format_json(ctx, &mut writer, event),
+        }
+    }
+}
+
+/// The IDs of the span an event was logged in, `None` outside of spans.
+fn trace_ids<S, N>(ctx: &FmtContext<'_, S, N>) -> Option<(TraceId, SpanId)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    let span = ctx.parent_span()?;
+    let extensions = span.extensions();
+    let otel = extensions.get::<OtelData>()?;
+    // Like the `OpenTelemetryLayer` does: a span with a parent continues its trace, even if the
+    // parent is in another service
+    let trace_id = if otel.parent_cx.has_active_span() {
+        otel.parent_cx.span().span_context().trace_id()
+    } else {
+        otel.builder.trace_id?
+    };
+    Some((trace_id, otel.builder.span_id?))
+}
+
+/// Collects the fields of an event as JSON, redacted
+struct JsonVisitor<'a> {
+    fields: Map<String, Value>,
+    redactor: &'a Redactor,
+}
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        let value = match (self.redactor.policy(field.name()), value) {
+            (None, value) => value,
+            (Some(policy), Value::String(value)) => match self.redactor.redact(policy, &value) {
+                Some(redacted) => redacted.into(),
+                None => return,
+            },
+            (Some(policy), value) => match self.redactor.redact(policy, &value.to_string()) {
+                Some(redacted) => redacted.into(),
+                None => return,
+            },
+        };
+        self.fields.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing::info_span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt};
+
+    use super::*;
+    use crate::{
+        cfg::RedactionCfg,
+        redaction::{Policy, RedactingFields},
+    };
+
+    /// Collects the log lines
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Lines {
+        type Writer = Lines;
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// Logs a line within a span in `format`, returns it along with the IDs of the span.
+    fn log(format: LogFormat) -> (String, String, String) {
+        let redactor = Redactor::new(RedactionCfg {
+            policies: HashMap::from([("email".to_owned(), Policy::Drop)]),
+            hash_key: b"test-key".to_vec(),
+        });
+        let lines = Lines::default();
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .fmt_fields(RedactingFields::new(redactor.clone()))
+                    .event_format(LogLines::new(format, redactor))
+                    .with_ansi(false)
+                    .with_writer(lines.clone()),
+            )
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let span = info_span!("http_request", method = "GET");
+        span.in_scope(|| tracing::info!(email = "mert@example.com", status = 200, "Done"));
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+
+        let line = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        (
+            line,
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+        )
+    }
+
+    #[test]
+    fn lines_carry_the_ids_of_their_span() {
+        let (line, trace_id, span_id) = log(LogFormat::Pretty);
+        assert!(
+            line.ends_with(&format!(" trace_id={trace_id} span_id={span_id}\n")),
+            "{line}"
+        );
+        assert!(line.contains("http_request{method=\"GET\"}"), "{line}");
+        assert!(!line.contains("mert@example.com"), "{line}");
+
+        let (line, trace_id, span_id) = log(LogFormat::Json);
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["trace_id"], trace_id);
+        assert_eq!(json["span_id"], span_id);
+        assert_eq!(json["fields"]["message"], "Done");
+        assert_eq!(json["fields"]["status"], 200);
+        assert!(json["fields"].get("email").is_none());
+        assert_eq!(json["spans"][0]["name"], "http_request");
+    }
+}
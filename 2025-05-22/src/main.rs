@@ -1,13 +1,8 @@
 use std::time::Duration;
 
-use otel::init_tracing_subscriber;
+use guided_telemetry::{cfg, otel::init_tracing_subscriber, server};
 use tracing::{error, info};
 
-mod business;
-mod cfg;
-mod otel;
-mod server;
-
 #[tokio::main]
 async fn main() {
     println!("{:#^70}", "");
@@ -17,8 +12,9 @@ async fn main() {
     println!("{:#^70}", "");
     dotenvy::dotenv().expect("shut down when environmental variables cannot be read");
 
-    let _guard = init_tracing_subscriber();
-    let cfg = cfg::Cfg { port: 5173 };
+    let cfg = cfg::Cfg::from_env().expect("shut down when the configuration is invalid");
+    let _guard = init_tracing_subscriber(&cfg.telemetry)
+        .expect("shut down when the telemetry cannot be set up");
 
     super_cool_function().await;
 
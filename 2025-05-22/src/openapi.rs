@@ -0,0 +1,47 @@
+//! The OpenAPI document of our web server, served at `/openapi.json`.
+//!
+//! The paths are collected from the `#[utoipa::path]` attributes of the handlers in `server.rs`,
+//! this module only adds what does not belong to a single handler.
+
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+use crate::auth::API_KEY_HEADER;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "guided_telemetry",
+        description = "A very simple web server that demonstrates how to generate telemetry in Rust"
+    ),
+    tags(
+        (name = "users", description = "Create and read users"),
+        (name = "admin", description = "Look into the running server")
+    ),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+/// The credentials accepted by `auth.rs`
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "jwt",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! A Prometheus style scrape endpoint.
+//!
+//! Next to pushing metrics via OTLP, we offer them in the OpenMetrics text format at `/metrics`.
+//! Unlike the classic Prometheus format, OpenMetrics can carry exemplars, so a scraping Prometheus
+//! gets the same links from histogram buckets to traces as our OTLP backend (see `exemplars.rs`).
+//!
+//! The scrape endpoint reads the metrics through a [`ManualReader`] that is registered with the
+//! meter provider next to the periodic readers.
+
+use std::{
+    fmt::{Display, Write},
+    sync::{Arc, OnceLock, Weak},
+    time::UNIX_EPOCH,
+};
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, Temporality,
+        data::{self, Exemplar, Metric, ResourceMetrics},
+        reader::MetricReader,
+    },
+};
+
+/// The content type of the OpenMetrics text format
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+static READER: OnceLock<ScrapeReader> = OnceLock::new();
+
+/// A [`ManualReader`] we can hand to the meter provider and still read from ourselves.
+#[derive(Debug, Clone)]
+pub struct ScrapeReader(Arc<ManualReader>);
+
+/// Returns the reader that backs the scrape endpoint. Register it with the meter provider.
+pub fn reader() -> ScrapeReader {
+    READER
+        .get_or_init(|| ScrapeReader(Arc::new(ManualReader::builder().build())))
+        .clone()
+}
+
+impl MetricReader for ScrapeReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.0.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// Collects the current metrics and renders them in the OpenMetrics text format.
+pub fn scrape() -> anyhow::Result<String> {
+    let mut metrics = ResourceMetrics {
+        resource: Resource::builder_empty().build(),
+        scope_metrics: vec![],
+    };
+    reader().collect(&mut metrics)?;
+    crate::exemplars::attach(&mut metrics);
+
+    let mut out = String::new();
+    for metric in metrics
+        .scope_metrics
+        .iter()
+        .flat_map(|scope| &scope.metrics)
+    {
+        render(&mut out, metric)?;
+    }
+    out.push_str("# EOF\n");
+    Ok(out)
+}
+
+fn render(out: &mut String, metric: &Metric) -> std::fmt::Result {
+    let name = sanitize(&metric.name);
+    let data = metric.data.as_any();
+
+    if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+        header(out, &name, "histogram", metric)?;
+        render_histogram(out, &name, histogram)
+    } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+        header(out, &name, "histogram", metric)?;
+        render_histogram(out, &name, histogram)
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+        render_sum(out, &name, metric, sum)
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+        render_sum(out, &name, metric, sum)
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+        render_sum(out, &name, metric, sum)
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+        render_gauge(out, &name, metric, gauge)
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+        render_gauge(out, &name, metric, gauge)
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+        render_gauge(out, &name, metric, gauge)
+    } else {
+        // Exponential histograms are not part of the OpenMetrics text format
+        Ok(())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, metric: &Metric) -> std::fmt::Result {
+    writeln!(out, "# TYPE {name} {kind}")?;
+    if !metric.description.is_empty() {
+        writeln!(out, "# HELP {name} {}", escape(&metric.description))?;
+    }
+    Ok(())
+}
+
+fn render_histogram<T: BucketValue>(
+    out: &mut String,
+    name: &str,
+    histogram: &data::Histogram<T>,
+) -> std::fmt::Result {
+    for data_point in &histogram.data_points {
+        let mut cumulative = 0;
+        let upper_bounds = data_point
+            .bounds
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(["+Inf".to_owned()]);
+
+        for (i, (le, count)) in upper_bounds.zip(&data_point.bucket_counts).enumerate() {
+            cumulative += count;
+            let le = KeyValue::new("le", le);
+            write!(
+                out,
+                "{name}_bucket{} {cumulative}",
+                labels(&data_point.attributes, Some(&le))
+            )?;
+
+            let lower = i.checked_sub(1).map(|i| data_point.bounds[i]);
+            let upper = data_point.bounds.get(i).copied();
+            let exemplar = data_point.exemplars.iter().find(|exemplar| {
+                let value = exemplar.value.as_f64();
+                lower.is_none_or(|lower| value > lower) && upper.is_none_or(|upper| value <= upper)
+            });
+            if let Some(exemplar) = exemplar {
+                write_exemplar(out, exemplar)?;
+            }
+            out.push('\n');
+        }
+
+        let labels = labels(&data_point.attributes, None);
+        writeln!(out, "{name}_count{labels} {}", data_point.count)?;
+        writeln!(out, "{name}_sum{labels} {}", data_point.sum)?;
+    }
+    Ok(())
+}
+
+fn render_sum<T: Display>(
+    out: &mut String,
+    name: &str,
+    metric: &Metric,
+    sum: &data::Sum<T>,
+) -> std::fmt::Result {
+    let (kind, suffix) = if sum.is_monotonic {
+        ("counter", "_total")
+    } else {
+        ("gauge", "")
+    };
+    header(out, name, kind, metric)?;
+    for data_point in &sum.data_points {
+        let labels = labels(&data_point.attributes, None);
+        writeln!(out, "{name}{suffix}{labels} {}", data_point.value)?;
+    }
+    Ok(())
+}
+
+fn render_gauge<T: Display>(
+    out: &mut String,
+    name: &str,
+    metric: &Metric,
+    gauge: &data::Gauge<T>,
+) -> std::fmt::Result {
+    header(out, name, "gauge", metric)?;
+    for data_point in &gauge.data_points {
+        let labels = labels(&data_point.attributes, None);
+        writeln!(out, "{name}{labels} {}", data_point.value)?;
+    }
+    Ok(())
+}
+
+fn write_exemplar<T: Copy + Display>(out: &mut String, exemplar: &Exemplar<T>) -> std::fmt::Result {
+    let timestamp = exemplar
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    write!(
+        out,
+        " # {{trace_id=\"{}\",span_id=\"{}\"}} {} {timestamp:.3}",
+        hex(&exemplar.trace_id),
+        hex(&exemplar.span_id),
+        exemplar.value,
+    )
+}
+
+fn labels(attributes: &[KeyValue], extra: Option<&KeyValue>) -> String {
+    let labels: Vec<_> = attributes
+        .iter()
+        .chain(extra)
+        .map(|kv| {
+            format!(
+                "{}=\"{}\"",
+                sanitize(kv.key.as_str()),
+                escape(&kv.value.to_string())
+            )
+        })
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Metric and label names may only contain `[a-zA-Z0-9_:]`, so `http.server.latency` becomes
+/// `http_server_latency`.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Histograms record `f64` or `u64` values, to sort exemplars into buckets we compare them with
+/// the `f64` bucket bounds.
+trait BucketValue: Display + Copy {
+    fn as_f64(self) -> f64;
+}
+
+impl BucketValue for f64 {
+    fn as_f64(self) -> f64 {
+        self
+    }
+}
+
+impl BucketValue for u64 {
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn renders_histogram_buckets_with_exemplars() {
+        let histogram = data::Histogram {
+            data_points: vec![data::HistogramDataPoint {
+                attributes: vec![KeyValue::new("method", "GET")],
+                count: 3,
+                bounds: vec![10.0, 100.0],
+                bucket_counts: vec![1, 2, 0],
+                min: Some(5.0),
+                max: Some(60.0),
+                sum: 115.0,
+                exemplars: vec![Exemplar {
+                    filtered_attributes: vec![],
+                    time: SystemTime::UNIX_EPOCH,
+                    value: 60.0,
+                    span_id: [1; 8],
+                    trace_id: [2; 16],
+                }],
+            }],
+            start_time: SystemTime::UNIX_EPOCH,
+            time: SystemTime::UNIX_EPOCH,
+            temporality: Temporality::Cumulative,
+        };
+
+        let mut out = String::new();
+        render_histogram(&mut out, "http_server_latency", &histogram).unwrap();
+
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"http_server_latency_bucket{method="GET",le="10"} 1"#
+        );
+        assert_eq!(
+            lines[1],
+            format!(
+                r#"http_server_latency_bucket{{method="GET",le="100"}} 3 # {{trace_id="{}",span_id="{}"}} 60 0.000"#,
+                "02".repeat(16),
+                "01".repeat(8)
+            )
+        );
+        assert_eq!(
+            lines[2],
+            r#"http_server_latency_bucket{method="GET",le="+Inf"} 3"#
+        );
+        assert_eq!(lines[3], r#"http_server_latency_count{method="GET"} 3"#);
+        assert_eq!(lines[4], r#"http_server_latency_sum{method="GET"} 115"#);
+    }
+}
@@ -1,18 +1,70 @@
 //! The code in this module basically is the example found at
 //! https://github.com/tokio-rs/tracing-opentelemetry/blob/v0.1.x/examples/opentelemetry-otlp.rs
+//!
+//! Where the telemetry goes depends on the cargo features we are built with: `otlp-grpc` and
+//! `otlp-http` export to a collector, `stdout` prints metrics and `prometheus` offers them at
+//! `/metrics`. Without any of them, spans and metrics are still recorded, so our logs carry trace
+//! IDs and `/health` has numbers to report, but nothing leaves the process except the log lines on
+//! stdout.
+//!
+//! The OTEL API, the SDK and the `tracing-opentelemetry` bridge are no feature of their own: the
+//! server uses them for more than exporting. The audit log and the user events carry the trace
+//! context of the `OpenTelemetryLayer`'s spans, exemplars link to them, and the circuit breaker,
+//! the job queue and the tenants record their metrics with OTEL instruments. Gating the bridge
+//! would mean a second code path for all of them, for a build that only saves the SDK.
+
+#[cfg(all(
+    feature = "otlp",
+    not(any(feature = "otlp-grpc", feature = "otlp-http"))
+))]
+compile_error!("the `otlp` feature needs a transport, enable `otlp-grpc` or `otlp-http` instead");
+
+use std::io;
 
 use opentelemetry::{KeyValue, global, trace::TracerProvider as _};
 use opentelemetry_sdk::{
     Resource,
-    metrics::{MeterProviderBuilder, PeriodicReader, SdkMeterProvider},
-    trace::{RandomIdGenerator, Sampler, SdkTracerProvider},
-};
-use opentelemetry_semantic_conventions::{
-    SCHEMA_URL,
-    attribute::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_VERSION},
+    metrics::{MeterProviderBuilder, SdkMeterProvider, reader::MetricReader},
+    trace::{
+        self, BatchSpanProcessor, RandomIdGenerator, Sampler, SdkTracerProvider, SpanExporter,
+    },
 };
+#[cfg(feature = "semconv-experimental")]
+use opentelemetry_semantic_conventions::attribute::DEPLOYMENT_ENVIRONMENT_NAME;
+use opentelemetry_semantic_conventions::{SCHEMA_URL, attribute::SERVICE_VERSION};
+use tracing::Dispatch;
 use tracing_opentelemetry::{MetricsLayer, OpenTelemetryLayer};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    EnvFilter, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::{
+    cfg::TelemetryCfg,
+    log_format::LogLines,
+    panics,
+    pipeline::{self, ObservedExporter, ObservedProcessor, PipelineMetrics},
+    redaction::{RedactingFields, RedactingProcessor, Redactor},
+    tail_sampling::TailSamplingProcessor,
+    tenant::TenantLayer,
+};
+#[cfg(feature = "otlp")]
+use {
+    crate::{
+        exemplars::ExemplarExporter, redaction::RedactingLogProcessor, tenant::TenantLogProcessor,
+    },
+    anyhow::Context as _,
+    opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge,
+    opentelemetry_sdk::{
+        logs::{self, BatchLogProcessor, LogExporter, SdkLoggerProvider},
+        metrics::{PeriodicReader, exporter::PushMetricExporter},
+    },
+    tracing::Metadata,
+    tracing_subscriber::{Layer, filter::filter_fn},
+};
+
+/// Not part of the stable semantic conventions yet
+#[cfg(not(feature = "semconv-experimental"))]
+const DEPLOYMENT_ENVIRONMENT_NAME: &str = "deployment.environment.name";
 
 /// Resources will help you categorize telemetry data.
 /// They might contain information about your kubernetes cluster, pod information and anything else
@@ -32,97 +84,489 @@ fn resource() -> Resource {
         .build()
 }
 
-/// The [`SdkMeterProvider`] collects and exports metrics data (counters, gauges, etc). Here we can
-/// configure the transmission protocol, transmission intervals and much more.
-fn init_meter_provider() -> SdkMeterProvider {
-    let exporter = opentelemetry_otlp::MetricExporter::builder()
-        .with_tonic()
-        .with_temporality(opentelemetry_sdk::metrics::Temporality::default())
-        .build()
-        .unwrap();
+/// Adds the readers that export our metrics data (counters, gauges, etc) to the
+/// [`SdkMeterProvider`]. Here we can configure the transmission protocol, transmission intervals
+/// and much more.
+fn with_metric_readers<'a, W>(builder: SubscriberBuilder<'a, W>) -> SubscriberBuilder<'a, W> {
+    // Useful for dev-time: See everything in the terminal
+    #[cfg(feature = "stdout")]
+    let builder = builder.with_metric_reader(
+        opentelemetry_sdk::metrics::PeriodicReader::builder(
+            opentelemetry_stdout::MetricExporter::default(),
+        )
+        .build(),
+    );
 
-    let reader = PeriodicReader::builder(exporter)
-        .with_interval(std::time::Duration::from_secs(30))
-        .build();
+    // Scraped at `/metrics`
+    #[cfg(feature = "prometheus")]
+    let builder = builder.with_metric_reader(crate::openmetrics::reader());
 
-    // Useful for dev-time: See everything in the terminal
-    let stdout_reader =
-        PeriodicReader::builder(opentelemetry_stdout::MetricExporter::default()).build();
+    builder
+}
 
-    // We return the provider. The handle can be useful during a graceful shutdown.
-    MeterProviderBuilder::default()
-        .with_resource(resource())
-        .with_reader(reader)
-        .with_reader(stdout_reader)
-        .build()
+/// Adds the OTLP exporters of spans, metrics and logs
+#[cfg(feature = "otlp")]
+fn with_otlp_exporters<'a, W>(
+    builder: SubscriberBuilder<'a, W>,
+    cfg: &TelemetryCfg,
+) -> anyhow::Result<SubscriberBuilder<'a, W>> {
+    // Unlike spans and metrics, log records are not buffered on disk
+    let builder = builder
+        .with_log_exporter(otlp::log_exporter().context("could not build the OTLP log exporter")?);
+
+    Ok(match &cfg.disk_buffer {
+        // Spans and metrics the collector does not accept are buffered on disk, see
+        // `disk_buffer.rs`. The pipeline observes the OTLP exporter within the buffer, so a batch
+        // the collector rejects counts as failed and turns `/health` degraded, even though the
+        // buffer keeps it.
+        #[cfg(feature = "otlp-grpc")]
+        Some(disk_buffer) if otlp::is_grpc() => {
+            let span_exporter =
+                otlp::span_exporter().context("could not build the OTLP span exporter")?;
+            let span_exporter = ObservedExporter::new(span_exporter, &pipeline::SPANS);
+            let metric_exporter =
+                otlp::metric_exporter().context("could not build the OTLP metric exporter")?;
+            builder
+                .with_batched_spans(
+                    crate::disk_buffer::BufferedSpanExporter::new(span_exporter, disk_buffer)
+                        .context("could not buffer spans on disk")?,
+                )
+                .with_metric_reader(metric_reader(
+                    crate::disk_buffer::BufferedMetricExporter::new(metric_exporter, disk_buffer)
+                        .context("could not buffer metrics on disk")?,
+                ))
+        }
+        // The buffer replays its batches over gRPC, so it cannot back exporters that speak HTTP
+        Some(_) => anyhow::bail!(
+            "TELEMETRY_BUFFER_DIR needs OTLP over gRPC: build with the `otlp-grpc` feature and \
+             leave OTEL_EXPORTER_OTLP_PROTOCOL at `grpc`"
+        ),
+        None => builder
+            .with_span_exporter(
+                otlp::span_exporter().context("could not build the OTLP span exporter")?,
+            )
+            .with_metric_reader(metric_reader(
+                otlp::metric_exporter().context("could not build the OTLP metric exporter")?,
+            )),
+    })
 }
 
-// Construct TracerProvider for OpenTelemetryLayer
-fn init_tracer_provider() -> SdkTracerProvider {
-    let exporter = opentelemetry_otlp::SpanExporter::builder()
-        .with_tonic()
+/// Exports the metrics every 30 seconds. Our histograms link to traces, see `exemplars.rs`.
+#[cfg(feature = "otlp")]
+fn metric_reader<E: PushMetricExporter>(exporter: E) -> PeriodicReader<ExemplarExporter<E>> {
+    PeriodicReader::builder(ExemplarExporter::new(exporter))
+        .with_interval(std::time::Duration::from_secs(30))
         .build()
-        .unwrap();
+}
 
-    SdkTracerProvider::builder()
-        // Customize sampling strategy
-        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
-            1.0,
-        ))))
+// Construct TracerProvider for OpenTelemetryLayer
+fn init_tracer_provider(
+    cfg: &TelemetryCfg,
+    batch_processor: Option<ObservedProcessor<BatchSpanProcessor>>,
+    sampler: Option<Sampler>,
+    redactor: &Redactor,
+) -> SdkTracerProvider {
+    let builder = SdkTracerProvider::builder()
         .with_id_generator(RandomIdGenerator::default())
+        .with_resource(resource());
+
+    // Without an exporter, spans are only good for the trace IDs in our logs and exemplars
+    let Some(batch_processor) = batch_processor else {
+        return builder.build();
+    };
+
+    // Personal data is removed from every span before it is exported, see `redaction.rs`
+    match &cfg.tail_sampling {
+        // Tail sampling can only pick from the spans it gets to see, so we record every trace and
+        // let the `TailSamplingProcessor` decide which ones are exported. See `tail_sampling.rs`.
+        Some(tail_sampling) => builder
+            .with_sampler(Sampler::AlwaysOn)
+            .with_span_processor(RedactingProcessor::new(
+                TailSamplingProcessor::new(batch_processor, tail_sampling.clone()),
+                redactor.clone(),
+            ))
+            .build(),
+        None => {
+            builder
+                // Customize sampling strategy
+                .with_sampler(sampler.unwrap_or_else(|| {
+                    Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(1.0)))
+                }))
+                .with_span_processor(RedactingProcessor::new(batch_processor, redactor.clone()))
+                .build()
+        }
+    }
+}
+
+/// The [`SdkLoggerProvider`] sends our log records to the collector
+#[cfg(feature = "otlp")]
+fn init_logger_provider(
+    batch_processor: ObservedProcessor<BatchLogProcessor>,
+    redactor: &Redactor,
+) -> SdkLoggerProvider {
+    // Personal data is removed from every log record before it is exported, see `redaction.rs`,
+    // and the tenant of the request is added, see `tenant.rs`
+    SdkLoggerProvider::builder()
         .with_resource(resource())
-        .with_batch_exporter(exporter)
+        .with_log_processor(TenantLogProcessor::new(RedactingLogProcessor::new(
+            batch_processor,
+            redactor.clone(),
+        )))
         .build()
 }
 
+/// Targets whose events never become log records: the exporters log through `tracing` as well, so
+/// exporting their events would make every export produce more to export.
+#[cfg(feature = "otlp")]
+const UNEXPORTED_TARGETS: &[&str] = &[
+    pipeline::TARGET,
+    "opentelemetry",
+    "tonic",
+    "h2",
+    "hyper",
+    "tower",
+    "reqwest",
+];
+
+/// Whether an event is sent to the collector as a log record
+#[cfg(feature = "otlp")]
+fn is_exported_as_log(metadata: &Metadata<'_>) -> bool {
+    let target = metadata.target();
+    !UNEXPORTED_TARGETS.iter().any(|unexported| {
+        target
+            .strip_prefix(unexported)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['_', ':']))
+    })
+}
+
 /// Initializes a tracing subscriber that
 /// - collects and sends out traces
 /// - collects and sends out metrics
+/// - collects and sends out logs
 /// - logs to stdout
+/// - logs panics and flushes the telemetry before a panic ends the process
 ///
-/// Returns an [`OtelGuard`] that holds the handles to the metrics and the trace providers.
-pub fn init_tracing_subscriber() -> OtelGuard {
-    let tracer_provider = init_tracer_provider();
-    let meter_provider = init_meter_provider();
-
-    global::set_meter_provider(meter_provider.clone());
-    opentelemetry::global::set_tracer_provider(tracer_provider.clone());
-    let tracer = tracer_provider.tracer("tracing-otel-subscriber");
-
-    tracing_subscriber::registry()
-        // The global level filter prevents the exporter network stack
-        // from reentering the globally installed OpenTelemetryLayer with
-        // its own spans while exporting, as the libraries should not use
-        // tracing levels below DEBUG. If the OpenTelemetry layer needs to
-        // trace spans and events with higher verbosity levels, consider using
-        // per-layer filtering to target the telemetry layer specifically,
-        // e.g. by target matching.
-        .with(tracing_subscriber::filter::EnvFilter::try_from_default_env().unwrap_or_default())
-        .with(tracing_subscriber::fmt::layer().with_line_number(true))
-        .with(MetricsLayer::new(meter_provider.clone()))
-        .with(OpenTelemetryLayer::new(tracer))
-        .init();
-
-    OtelGuard {
-        tracer_provider,
-        meter_provider,
-    }
-}
-
-/// Holds handles to tracing and metric providers.
+/// Fields holding personal data are redacted on the way out, see `redaction.rs`.
+///
+/// Returns an [`OtelGuard`] that holds the handles to the metrics and the trace providers. Fails if
+/// the exporters cannot be built, e.g. because the disk buffer cannot be opened.
+pub fn init_tracing_subscriber(cfg: &TelemetryCfg) -> anyhow::Result<OtelGuard> {
+    let builder = with_metric_readers(SubscriberBuilder::new(cfg));
+    #[cfg(feature = "otlp")]
+    let builder = with_otlp_exporters(builder, cfg)?;
+    let (subscriber, guard) = builder.build();
+    subscriber.init();
+
+    // Without OTLP there is nothing to buffer
+    #[cfg(not(feature = "otlp"))]
+    if cfg.disk_buffer.is_some() {
+        tracing::warn!(
+            "Ignoring TELEMETRY_BUFFER_DIR, the disk buffer needs the `otlp-grpc` feature"
+        );
+    }
+
+    // A panic outside of a request may end the process, so it flushes what we have, see
+    // `panics.rs`
+    let flushed = guard.providers.clone();
+    panics::install_hook(move || flushed.force_flush());
+
+    Ok(guard)
+}
+
+/// Builds the subscriber that [`init_tracing_subscriber`] installs, with the exporters left to the
+/// caller. The benchmarks use it to measure our layer stack without a collector.
+pub struct SubscriberBuilder<'a, W> {
+    cfg: &'a TelemetryCfg,
+    env_filter: Option<EnvFilter>,
+    meter_provider: MeterProviderBuilder,
+    span_processor: Option<ObservedProcessor<BatchSpanProcessor>>,
+    sampler: Option<Sampler>,
+    #[cfg(feature = "otlp")]
+    log_processor: Option<ObservedProcessor<BatchLogProcessor>>,
+    writer: W,
+}
+
+impl<'a> SubscriberBuilder<'a, fn() -> io::Stdout> {
+    /// Exports nothing, filters with `RUST_LOG` and logs to stdout
+    pub fn new(cfg: &'a TelemetryCfg) -> Self {
+        Self {
+            cfg,
+            env_filter: None,
+            meter_provider: MeterProviderBuilder::default().with_resource(resource()),
+            span_processor: None,
+            sampler: None,
+            #[cfg(feature = "otlp")]
+            log_processor: None,
+            writer: io::stdout,
+        }
+    }
+}
+
+impl<'a, W> SubscriberBuilder<'a, W> {
+    /// Filters with `env_filter` instead of `RUST_LOG`
+    pub fn with_env_filter(mut self, env_filter: EnvFilter) -> Self {
+        self.env_filter = Some(env_filter);
+        self
+    }
+
+    /// Writes the log lines to `writer` instead of stdout
+    pub fn with_writer<W2>(self, writer: W2) -> SubscriberBuilder<'a, W2> {
+        SubscriberBuilder {
+            cfg: self.cfg,
+            env_filter: self.env_filter,
+            meter_provider: self.meter_provider,
+            span_processor: self.span_processor,
+            sampler: self.sampler,
+            #[cfg(feature = "otlp")]
+            log_processor: self.log_processor,
+            writer,
+        }
+    }
+
+    /// Exports the spans with `exporter`, in batches
+    pub fn with_span_exporter<E: SpanExporter + 'static>(self, exporter: E) -> Self {
+        self.with_batched_spans(ObservedExporter::new(exporter, &pipeline::SPANS))
+    }
+
+    /// Like [`Self::with_span_exporter`], but leaves observing the exports to `exporter`, which
+    /// has an [`ObservedExporter`] somewhere within
+    fn with_batched_spans<E: SpanExporter + 'static>(mut self, exporter: E) -> Self {
+        // We enforce the queue size ourselves, so we know about every span that is dropped. See
+        // `pipeline.rs`.
+        let batch_processor = BatchSpanProcessor::builder(exporter)
+            .with_batch_config(
+                trace::BatchConfigBuilder::default()
+                    .with_max_queue_size(pipeline::QUEUE_CAPACITY)
+                    .build(),
+            )
+            .build();
+        self.span_processor = Some(ObservedProcessor::new(batch_processor, &pipeline::SPANS));
+        self
+    }
+
+    /// Samples with `sampler` instead of every trace. Tail sampling ignores it, as it has to see
+    /// every trace to pick from them.
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Collects the metrics with `reader`, in addition to the readers added before
+    pub fn with_metric_reader(mut self, reader: impl MetricReader) -> Self {
+        self.meter_provider = self.meter_provider.with_reader(reader);
+        self
+    }
+
+    /// Exports the events as log records with `exporter`, in batches
+    #[cfg(feature = "otlp")]
+    pub fn with_log_exporter<E: LogExporter + 'static>(mut self, exporter: E) -> Self {
+        let batch_processor =
+            BatchLogProcessor::builder(ObservedExporter::new(exporter, &pipeline::LOGS))
+                .with_batch_config(
+                    logs::BatchConfigBuilder::default()
+                        .with_max_queue_size(pipeline::QUEUE_CAPACITY)
+                        .build(),
+                )
+                .build();
+        self.log_processor = Some(ObservedProcessor::new(batch_processor, &pipeline::LOGS));
+        self
+    }
+
+    /// Builds the subscriber and sets the global meter and tracer providers. Returns the subscriber
+    /// along with an [`OtelGuard`] that shuts the providers down when dropped.
+    pub fn build(self) -> (Dispatch, OtelGuard)
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        // The meter provider comes first, so that the span processors can register their metrics
+        let meter_provider = self.meter_provider.build();
+        global::set_meter_provider(meter_provider.clone());
+
+        let redactor = Redactor::new(self.cfg.redaction.clone());
+        let tracer_provider =
+            init_tracer_provider(self.cfg, self.span_processor, self.sampler, &redactor);
+        global::set_tracer_provider(tracer_provider.clone());
+        let tracer = tracer_provider.tracer("tracing-otel-subscriber");
+        #[cfg(feature = "otlp")]
+        let logger_provider = self
+            .log_processor
+            .map(|batch_processor| init_logger_provider(batch_processor, &redactor));
+        // How much telemetry the providers above export, drop and fail to export
+        let pipeline_metrics = pipeline::register_metrics();
+
+        let subscriber = tracing_subscriber::registry()
+            // The global level filter prevents the exporter network stack
+            // from reentering the globally installed OpenTelemetryLayer with
+            // its own spans while exporting, as the libraries should not use
+            // tracing levels below DEBUG. If the OpenTelemetry layer needs to
+            // trace spans and events with higher verbosity levels, consider using
+            // per-layer filtering to target the telemetry layer specifically,
+            // e.g. by target matching.
+            .with(
+                self.env_filter
+                    .unwrap_or_else(|| EnvFilter::try_from_default_env().unwrap_or_default()),
+            )
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .fmt_fields(RedactingFields::new(redactor.clone()))
+                    // Every line carries the IDs of its trace and span, see `log_format.rs`
+                    .event_format(LogLines::new(self.cfg.log_format, redactor.clone()))
+                    .with_writer(self.writer),
+            )
+            .with(MetricsLayer::new(meter_provider.clone()))
+            .with(OpenTelemetryLayer::new(tracer))
+            // After the `OpenTelemetryLayer`, so the spans it adds `tenant.id` to already exist
+            .with(TenantLayer);
+        // Sends our events to the collector as log records
+        #[cfg(feature = "otlp")]
+        let subscriber = subscriber.with(logger_provider.as_ref().map(|logger_provider| {
+            OpenTelemetryTracingBridge::new(logger_provider)
+                .with_filter(filter_fn(is_exported_as_log))
+        }));
+        // Tags the samples of CPU profiles with the span they were taken in
+        #[cfg(target_os = "linux")]
+        let subscriber = subscriber.with(crate::profiling::SpanNameLayer);
+
+        let guard = OtelGuard {
+            providers: Providers {
+                tracer_provider,
+                meter_provider,
+                #[cfg(feature = "otlp")]
+                logger_provider,
+            },
+            _pipeline_metrics: pipeline_metrics,
+        };
+        (subscriber.into(), guard)
+    }
+}
+
+/// Holds handles to tracing, metric and log providers.
 /// They can be used to perform any kind of cleanup operations when the program shuts down.
 pub struct OtelGuard {
-    tracer_provider: SdkTracerProvider,
-    meter_provider: SdkMeterProvider,
+    providers: Providers,
+    _pipeline_metrics: PipelineMetrics,
+}
+
+impl OtelGuard {
+    /// Exports all the telemetry that is still queued, blocking until it is out or the export
+    /// timed out.
+    pub fn force_flush(&self) {
+        self.providers.force_flush();
+    }
 }
 
 impl Drop for OtelGuard {
     fn drop(&mut self) {
+        self.providers.shutdown();
+    }
+}
+
+/// The providers are handles, clones share their pipelines
+#[derive(Clone)]
+struct Providers {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+    /// `None` without a log exporter
+    #[cfg(feature = "otlp")]
+    logger_provider: Option<SdkLoggerProvider>,
+}
+
+impl Providers {
+    fn force_flush(&self) {
+        if let Err(err) = self.tracer_provider.force_flush() {
+            eprintln!("{err:?}");
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(Err(err)) = self.logger_provider.as_ref().map(|lp| lp.force_flush()) {
+            eprintln!("{err:?}");
+        }
+        if let Err(err) = self.meter_provider.force_flush() {
+            eprintln!("{err:?}");
+        }
+    }
+
+    fn shutdown(&self) {
         if let Err(err) = self.tracer_provider.shutdown() {
             eprintln!("{err:?}");
         }
+        #[cfg(feature = "otlp")]
+        if let Some(Err(err)) = self.logger_provider.as_ref().map(|lp| lp.shutdown()) {
+            eprintln!("{err:?}");
+        }
         if let Err(err) = self.meter_provider.shutdown() {
             eprintln!("{err:?}");
         }
     }
 }
+
+/// Builds the OTLP exporters for the transport we are built with. With both `otlp-grpc` and
+/// `otlp-http`, `OTEL_EXPORTER_OTLP_PROTOCOL=http/protobuf` picks HTTP, anything else gRPC.
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry_otlp::{ExporterBuildError, LogExporter, MetricExporter, SpanExporter};
+
+    enum Protocol {
+        #[cfg(feature = "otlp-grpc")]
+        Grpc,
+        #[cfg(feature = "otlp-http")]
+        Http,
+    }
+
+    fn protocol() -> Protocol {
+        #[cfg(all(feature = "otlp-grpc", feature = "otlp-http"))]
+        {
+            match std::env::var("OTEL_EXPORTER_OTLP_PROTOCOL") {
+                Ok(protocol) if protocol.starts_with("http") => Protocol::Http,
+                _ => Protocol::Grpc,
+            }
+        }
+        #[cfg(not(feature = "otlp-http"))]
+        {
+            Protocol::Grpc
+        }
+        #[cfg(not(feature = "otlp-grpc"))]
+        {
+            Protocol::Http
+        }
+    }
+
+    /// Whether the exporters speak gRPC, which the disk buffer needs to replay its batches
+    #[cfg(feature = "otlp-grpc")]
+    pub fn is_grpc() -> bool {
+        matches!(protocol(), Protocol::Grpc)
+    }
+
+    pub fn span_exporter() -> Result<SpanExporter, ExporterBuildError> {
+        match protocol() {
+            #[cfg(feature = "otlp-grpc")]
+            Protocol::Grpc => SpanExporter::builder().with_tonic().build(),
+            #[cfg(feature = "otlp-http")]
+            Protocol::Http => SpanExporter::builder().with_http().build(),
+        }
+    }
+
+    pub fn metric_exporter() -> Result<MetricExporter, ExporterBuildError> {
+        let temporality = opentelemetry_sdk::metrics::Temporality::default();
+        match protocol() {
+            #[cfg(feature = "otlp-grpc")]
+            Protocol::Grpc => MetricExporter::builder()
+                .with_tonic()
+                .with_temporality(temporality)
+                .build(),
+            #[cfg(feature = "otlp-http")]
+            Protocol::Http => MetricExporter::builder()
+                .with_http()
+                .with_temporality(temporality)
+                .build(),
+        }
+    }
+
+    pub fn log_exporter() -> Result<LogExporter, ExporterBuildError> {
+        match protocol() {
+            #[cfg(feature = "otlp-grpc")]
+            Protocol::Grpc => LogExporter::builder().with_tonic().build(),
+            #[cfg(feature = "otlp-http")]
+            Protocol::Http => LogExporter::builder().with_http().build(),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Panics as telemetry.
+//!
+//! A panicking handler would tear down the connection and take its span with it, leaving no trace
+//! of what happened. [`catch_panic`] catches the panic instead, answers with a `500` and records
+//! the panic on the `http_request` span as an `exception` event, with the attributes the semantic
+//! conventions define: `exception.type`, `exception.message` and `exception.stacktrace`.
+//!
+//! The backtrace has to be taken where the panic happens, which is the job of the panic hook that
+//! [`install_hook`] installs. For panics outside of requests, the hook logs a final error. Unless
+//! the panic happens within a task that is [`caught`], e.g. a job of `jobs.rs`, it also flushes the
+//! telemetry pipeline, as the process may be about to die. That blocks the panicking thread until
+//! the collector took the telemetry or the flush timed out, which we only put up with when it
+//! matters.
+
+use std::{
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    panic::{AssertUnwindSafe, PanicHookInfo},
+    pin::Pin,
+    task::{Context, Poll},
+    thread::LocalKey,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use futures_util::FutureExt;
+use opentelemetry_semantic_conventions::trace::{
+    EXCEPTION_MESSAGE, EXCEPTION_STACKTRACE, EXCEPTION_TYPE,
+};
+use tracing::error;
+
+thread_local! {
+    /// Whether this thread is handling a request right now, so a panic is caught by `catch_panic`
+    static IN_REQUEST: Cell<bool> = const { Cell::new(false) };
+    /// Whether this thread runs a task whose panics are caught by whoever spawned it
+    static IN_CAUGHT_TASK: Cell<bool> = const { Cell::new(false) };
+    /// Where the last panic on this thread happened, left by the hook for `catch_panic`
+    static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+/// Turns a panic of the handler into a `500` response and an `exception` event on the current
+/// span.
+pub async fn catch_panic(request: Request<Body>, next: Next) -> Response<Body> {
+    let handling = Marking::new(&IN_REQUEST, next.run(request));
+    match AssertUnwindSafe(handling).catch_unwind().await {
+        Ok(response) => response,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+            let backtrace = BACKTRACE.take().map_or_else(
+                || "unavailable".to_owned(),
+                |backtrace| backtrace.to_string(),
+            );
+            error!(
+                { EXCEPTION_TYPE } = "panic",
+                { EXCEPTION_MESSAGE } = message,
+                { EXCEPTION_STACKTRACE } = backtrace,
+                "exception"
+            );
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Marks `future` as a task whose panics are caught, e.g. by the `JoinHandle` it is spawned with.
+/// A panic within it does not make the hook flush the telemetry pipeline.
+pub fn caught<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    Marking::new(&IN_CAUGHT_TASK, future)
+}
+
+/// Sets `flag` on the thread while `future` is polled.
+struct Marking<F> {
+    flag: &'static LocalKey<Cell<bool>>,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Marking<F> {
+    fn new(flag: &'static LocalKey<Cell<bool>>, future: F) -> Self {
+        Self {
+            flag,
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl<F: Future> Future for Marking<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _marked = Marked(self.flag, self.flag.replace(true));
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Restores the flag when dropped, also while a panic unwinds
+struct Marked(&'static LocalKey<Cell<bool>>, bool);
+
+impl Drop for Marked {
+    fn drop(&mut self) {
+        self.0.set(self.1);
+    }
+}
+
+/// Replaces the default panic hook, which only prints to stderr. `flush` exports whatever
+/// telemetry is still queued.
+pub fn install_hook(flush: impl Fn() + Send + Sync + 'static) {
+    std::panic::set_hook(Box::new(move |info: &PanicHookInfo<'_>| {
+        let backtrace = Backtrace::force_capture();
+        if IN_REQUEST.get() {
+            // `catch_panic` takes it from here
+            BACKTRACE.set(Some(backtrace));
+            return;
+        }
+
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let thread = std::thread::current();
+        error!(
+            { EXCEPTION_TYPE } = "panic",
+            { EXCEPTION_MESSAGE } = message,
+            { EXCEPTION_STACKTRACE } = %backtrace,
+            thread = thread.name().unwrap_or("<unnamed>"),
+            location = info.location().map(ToString::to_string),
+            "Panicked"
+        );
+        if !IN_CAUGHT_TASK.get() {
+            flush();
+        }
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{Router, middleware, routing::get};
+    use opentelemetry::{Value, trace::TracerProvider as _};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tower::ServiceExt;
+    use tracing::{Instrument, info_span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::test_util::CollectSpans;
+
+    async fn gives_up() -> &'static str {
+        panic!("the handler gave up")
+    }
+
+    #[tokio::test]
+    async fn panics_become_500_and_exceptions() {
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route("/panic", get(gives_up))
+            .route("/fine", get(|| async { "fine" }))
+            .layer(middleware::from_fn(catch_panic));
+        let send = |uri: &'static str| {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone()
+                .oneshot(request)
+                .instrument(info_span!("http_request"))
+        };
+
+        assert_eq!(send("/panic").await.unwrap().status(), 500);
+        // The next request is not affected
+        assert_eq!(send("/fine").await.unwrap().status(), 200);
+        assert!(!IN_REQUEST.get());
+
+        let spans = collect.0.lock().unwrap();
+        let events = &spans[0].events.events;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "exception");
+        let message = events[0]
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == EXCEPTION_MESSAGE)
+            .map(|kv| kv.value.clone());
+        assert_eq!(message, Some(Value::from("the handler gave up")));
+        assert!(spans[1].events.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn marks_caught_tasks_while_they_are_polled() {
+        let marked = caught(async {
+            tokio::task::yield_now().await;
+            IN_CAUGHT_TASK.get()
+        })
+        .await;
+        assert!(marked);
+        assert!(!IN_CAUGHT_TASK.get());
+    }
+}
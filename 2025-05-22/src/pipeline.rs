@@ -0,0 +1,474 @@
+//! Watching the telemetry pipeline itself.
+//!
+//! Spans and log records do not leave the process right away: the batch processors in `otel.rs`
+//! queue them and a background thread exports them in batches. When the collector is slow or down,
+//! the queue fills up and the SDK drops whatever does not fit anymore - quietly, so we would never
+//! know that our traces have holes.
+//!
+//! So we count for ourselves. [`ObservedProcessor`] sits in front of a batch processor and
+//! [`ObservedExporter`] behind it. Together they know how many spans and log records are queued,
+//! and how many were exported, dropped or failed to export. The processor enforces the capacity of
+//! the queue itself, so every drop is counted. The numbers end up
+//! - in the `telemetry.exported`, `telemetry.dropped` and `telemetry.failed` counters, the
+//!   `telemetry.queue.fill` gauge and the `telemetry.export.duration` histogram, all with a
+//!   `signal` attribute of `spans` or `logs`,
+//! - in the response of `GET /health`, see [`health`],
+//! - and as warnings in our logs.
+//!
+//! With the disk buffer of `disk_buffer.rs`, [`ObservedExporter`] sits within the buffer, right in
+//! front of the OTLP exporter. A batch the collector rejects counts as failed, even though it is
+//! kept on disk for later: while the collector is down, our telemetry is not where we look for it.
+//!
+//! Those warnings must not feed the pipeline they warn about, or a failing exporter would produce
+//! more and more logs to export. They have the [`TARGET`] target, which `otel.rs` keeps away from
+//! the log exporter, and no parent span, so they do not become span events either. They are only
+//! written to stdout.
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use opentelemetry::{
+    InstrumentationScope, KeyValue,
+    metrics::{Histogram, ObservableCounter, ObservableGauge},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{LogBatch, LogExporter, LogProcessor, SdkLogRecord},
+    trace::{Span, SpanData, SpanExporter, SpanProcessor},
+};
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Target of our warnings about the pipeline. Also the name of the meter of its metrics.
+pub const TARGET: &str = "telemetry_pipeline";
+
+/// How many spans or log records the batch processors queue, the default of the SDK
+pub const QUEUE_CAPACITY: usize = 2_048;
+
+/// Name of the histogram that records how long exports took
+const EXPORT_DURATION_HISTOGRAM: &str = "telemetry.export.duration";
+
+/// We warn at most this often per signal, a full queue would flood the logs otherwise
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Share of the queue above which we consider the pipeline degraded
+const DEGRADED_FILL: f64 = 0.9;
+
+/// The spans on their way to the collector
+pub static SPANS: Signal = Signal::new("spans", QUEUE_CAPACITY);
+
+/// The log records on their way to the collector
+pub static LOGS: Signal = Signal::new("logs", QUEUE_CAPACITY);
+
+/// What happened to the telemetry of one signal (spans or log records) so far.
+#[derive(Debug)]
+pub struct Signal {
+    name: &'static str,
+    capacity: usize,
+    queued: AtomicUsize,
+    exported: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    last_export_failed: AtomicBool,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl Signal {
+    pub const fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            capacity,
+            queued: AtomicUsize::new(0),
+            exported: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            last_export_failed: AtomicBool::new(false),
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    /// Takes a place in the queue. Returns `false` and counts a drop if the queue is full.
+    fn enqueue(&self) -> bool {
+        let enqueued = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < self.capacity).then_some(queued + 1)
+            })
+            .is_ok();
+        if !enqueued {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            self.warn(format_args!(
+                "The {} queue is full, {dropped} dropped so far",
+                self.name
+            ));
+        }
+        enqueued
+    }
+
+    /// Frees the places of a batch that is being exported.
+    fn dequeue(&self, count: usize) {
+        // Never below 0, e.g. for batches of a processor we did not observe
+        let _ = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                Some(queued.saturating_sub(count))
+            });
+    }
+
+    fn exported(&self, count: usize, result: &OTelSdkResult) {
+        match result {
+            Ok(()) => {
+                self.exported.fetch_add(count as u64, Ordering::Relaxed);
+                self.last_export_failed.store(false, Ordering::Relaxed);
+            }
+            Err(e) => {
+                let failed = self.failed.fetch_add(count as u64, Ordering::Relaxed) + count as u64;
+                self.last_export_failed.store(true, Ordering::Relaxed);
+                self.warn(format_args!(
+                    "Could not export {count} {}, {failed} failed so far: {e}",
+                    self.name
+                ));
+            }
+        }
+    }
+
+    /// Logs a warning, unless we did so recently.
+    fn warn(&self, message: std::fmt::Arguments<'_>) {
+        let now = Instant::now();
+        {
+            let mut last_warning = self.last_warning.lock().unwrap_or_else(|e| e.into_inner());
+            if last_warning.is_some_and(|last| now.duration_since(last) < WARNING_INTERVAL) {
+                return;
+            }
+            *last_warning = Some(now);
+        }
+        warn!(target: TARGET, parent: None, signal = self.name, "{message}");
+    }
+
+    fn fill(&self) -> f64 {
+        self.queued.load(Ordering::Relaxed) as f64 / self.capacity as f64
+    }
+
+    pub fn stats(&self) -> SignalStats {
+        SignalStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            capacity: self.capacity,
+            exported: self.exported.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            last_export_failed: self.last_export_failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Healthy as long as the last export worked and the queue has room to spare
+    fn is_healthy(&self) -> bool {
+        !self.last_export_failed.load(Ordering::Relaxed) && self.fill() < DEGRADED_FILL
+    }
+}
+
+/// What happened to the spans or log records of this process so far
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SignalStats {
+    /// Waiting to be exported
+    pub queued: usize,
+    /// How many fit into the queue
+    pub capacity: usize,
+    pub exported: u64,
+    /// Dropped because the queue was full
+    pub dropped: u64,
+    /// Not accepted by the collector. Lost, unless `TELEMETRY_BUFFER_DIR` keeps them on disk.
+    pub failed: u64,
+    pub last_export_failed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Our telemetry is incomplete right now, the service itself works
+    Degraded,
+}
+
+/// The state of the telemetry pipeline
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Telemetry {
+    pub spans: SignalStats,
+    pub logs: SignalStats,
+}
+
+/// How this service is doing
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Health {
+    pub status: Status,
+    pub telemetry: Telemetry,
+}
+
+/// The health of this service, as far as its telemetry pipeline goes.
+pub fn health() -> Health {
+    let status = if SPANS.is_healthy() && LOGS.is_healthy() {
+        Status::Ok
+    } else {
+        Status::Degraded
+    };
+    Health {
+        status,
+        telemetry: Telemetry {
+            spans: SPANS.stats(),
+            logs: LOGS.stats(),
+        },
+    }
+}
+
+/// Reports the counts of [`SPANS`] and [`LOGS`] as metrics. They are reported as long as the
+/// returned instruments are kept around.
+pub fn register_metrics() -> PipelineMetrics {
+    let meter = opentelemetry::global::meter(TARGET);
+    let counter = |name: &'static str, description: &'static str, count: fn(&Signal) -> u64| {
+        meter
+            .u64_observable_counter(name)
+            .with_description(description)
+            .with_callback(move |observer| {
+                for signal in [&SPANS, &LOGS] {
+                    observer.observe(count(signal), &[KeyValue::new("signal", signal.name)]);
+                }
+            })
+            .build()
+    };
+
+    PipelineMetrics {
+        _exported: counter(
+            "telemetry.exported",
+            "Spans and log records the collector accepted",
+            |signal| signal.exported.load(Ordering::Relaxed),
+        ),
+        _dropped: counter(
+            "telemetry.dropped",
+            "Spans and log records dropped because the queue was full",
+            |signal| signal.dropped.load(Ordering::Relaxed),
+        ),
+        _failed: counter(
+            "telemetry.failed",
+            "Spans and log records the collector did not accept",
+            |signal| signal.failed.load(Ordering::Relaxed),
+        ),
+        _queue_fill: meter
+            .f64_observable_gauge("telemetry.queue.fill")
+            .with_description("Share of the export queue in use, from 0 to 1")
+            .with_callback(|observer| {
+                for signal in [&SPANS, &LOGS] {
+                    observer.observe(signal.fill(), &[KeyValue::new("signal", signal.name)]);
+                }
+            })
+            .build(),
+    }
+}
+
+/// Keeps the metrics of [`register_metrics`] alive.
+pub struct PipelineMetrics {
+    _exported: ObservableCounter<u64>,
+    _dropped: ObservableCounter<u64>,
+    _failed: ObservableCounter<u64>,
+    _queue_fill: ObservableGauge<f64>,
+}
+
+/// Counts what goes into the queue of the `next` (batch) processor, and drops what does not fit.
+#[derive(Debug)]
+pub struct ObservedProcessor<P> {
+    next: P,
+    signal: &'static Signal,
+}
+
+impl<P> ObservedProcessor<P> {
+    pub fn new(next: P, signal: &'static Signal) -> Self {
+        Self { next, signal }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for ObservedProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &opentelemetry::Context) {
+        self.next.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        // The batch processor ignores these, so they never take a place in the queue
+        if !span.span_context.is_sampled() {
+            return self.next.on_end(span);
+        }
+        if self.signal.enqueue() {
+            self.next.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+impl<P: LogProcessor> LogProcessor for ObservedProcessor<P> {
+    fn emit(&self, record: &mut SdkLogRecord, scope: &InstrumentationScope) {
+        if self.signal.enqueue() {
+            self.next.emit(record, scope);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+/// Counts what the `inner` exporter gets rid of, and how long it takes.
+#[derive(Debug)]
+pub struct ObservedExporter<E> {
+    inner: E,
+    signal: &'static Signal,
+    duration: Histogram<f64>,
+}
+
+impl<E> ObservedExporter<E> {
+    pub fn new(inner: E, signal: &'static Signal) -> Self {
+        let duration = opentelemetry::global::meter(TARGET)
+            .f64_histogram(EXPORT_DURATION_HISTOGRAM)
+            .with_description("Time it took to export a batch of spans or log records")
+            .with_unit("ms")
+            .build();
+        Self {
+            inner,
+            signal,
+            duration,
+        }
+    }
+
+    async fn observe(
+        &self,
+        count: usize,
+        export: impl Future<Output = OTelSdkResult>,
+    ) -> OTelSdkResult {
+        self.signal.dequeue(count);
+        let started_at = Instant::now();
+        let result = export.await;
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        self.duration.record(
+            started_at.elapsed().as_secs_f64() * 1_000.0,
+            &[
+                KeyValue::new("signal", self.signal.name),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+        self.signal.exported(count, &result);
+        result
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for ObservedExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let count = batch.len();
+        self.observe(count, self.inner.export(batch)).await
+    }
+
+    fn shutdown(&mut self) -> OTelSdkResult {
+        self.inner.shutdown()
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl<E: LogExporter> LogExporter for ObservedExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let count = batch.iter().count();
+        self.observe(count, self.inner.export(batch)).await
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.inner.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::{Span as _, Tracer, TracerProvider as _};
+    use opentelemetry_sdk::{error::OTelSdkError, trace::SdkTracerProvider};
+
+    use super::*;
+    use crate::test_util::CollectSpans;
+
+    /// A collector that is up or down
+    #[derive(Debug, Default)]
+    struct Collector {
+        down: AtomicBool,
+    }
+
+    impl SpanExporter for Collector {
+        async fn export(&self, _batch: Vec<SpanData>) -> OTelSdkResult {
+            if self.down.load(Ordering::Relaxed) {
+                Err(OTelSdkError::InternalFailure("collector is down".into()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_what_is_exported_dropped_and_failed() {
+        static SIGNAL: Signal = Signal::new("spans", 2);
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(ObservedProcessor::new(collect.clone(), &SIGNAL))
+            .build();
+        let tracer = provider.tracer("test");
+        for _ in 0..3 {
+            tracer.start("work").end();
+        }
+
+        let stats = SIGNAL.stats();
+        assert_eq!((stats.queued, stats.dropped), (2, 1));
+        assert_eq!(collect.0.lock().unwrap().len(), 2);
+        assert!(!SIGNAL.is_healthy(), "the queue is full");
+
+        let exporter = ObservedExporter::new(Collector::default(), &SIGNAL);
+        exporter.inner.down.store(true, Ordering::Relaxed);
+        let spans = std::mem::take(&mut *collect.0.lock().unwrap());
+        assert!(exporter.export(spans[..1].to_vec()).await.is_err());
+        let stats = SIGNAL.stats();
+        assert_eq!((stats.queued, stats.failed), (1, 1));
+        assert!(stats.last_export_failed);
+
+        exporter.inner.down.store(false, Ordering::Relaxed);
+        assert!(exporter.export(spans[1..].to_vec()).await.is_ok());
+        let stats = SIGNAL.stats();
+        assert_eq!((stats.queued, stats.exported), (0, 1));
+        assert!(SIGNAL.is_healthy());
+    }
+}
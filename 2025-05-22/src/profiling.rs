@@ -0,0 +1,268 @@
+//! Where does the time go?
+//!
+//! The latency histogram tells us _that_ requests got slower, traces tell us _which_ step got
+//! slower, but neither tells us which code burns the CPU. `GET /debug/pprof/profile` does: it
+//! samples the stacks of all our threads for a while (with `SIGPROF`, see the `pprof` crate) and
+//! returns the profile in the pprof format (`go tool pprof`, Grafana Pyroscope, ...) or as a
+//! flamegraph SVG.
+//!
+//! Every sample is tagged with the span that was active on its thread, so profiles can be compared
+//! with traces. The sampler reads the name of the thread it interrupted, so while a profile is
+//! being captured, the [`SpanNameLayer`] names each thread after the span it is in. The span shows
+//! up as the `thread` label in pprof and as the root of the stacks in the flamegraph.
+
+use std::{
+    cell::RefCell,
+    ffi::CString,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use pprof::{ProfilerGuardBuilder, protos::Message};
+use serde::Deserialize;
+use tracing::{Subscriber, span};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+use utoipa::ToSchema;
+
+use crate::cfg::ProfilingCfg;
+
+/// Threads names are at most 15 bytes long on Linux
+const MAX_THREAD_NAME: usize = 15;
+
+/// Whether a profile is being captured right now. Only one can be captured at a time.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static ENTERED: RefCell<EnteredSpans> = const {
+        RefCell::new(EnteredSpans { original_name: None, stack: vec![] })
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// Protobuf encoded pprof profile
+    #[default]
+    Pprof,
+    /// Flamegraph as SVG
+    Flamegraph,
+}
+
+impl ProfileFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ProfileFormat::Pprof => "application/octet-stream",
+            ProfileFormat::Flamegraph => "image/svg+xml",
+        }
+    }
+}
+
+/// Another profile is being captured
+#[derive(Debug)]
+pub struct ProfileRunning;
+
+impl fmt::Display for ProfileRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("another profile is being captured")
+    }
+}
+
+impl std::error::Error for ProfileRunning {}
+
+/// Samples the CPU for `duration` and renders the profile in `format`. Blocks the calling thread
+/// all along, so call it with `spawn_blocking`.
+pub fn capture(
+    cfg: &ProfilingCfg,
+    duration: Duration,
+    format: ProfileFormat,
+) -> anyhow::Result<Vec<u8>> {
+    if PROFILING
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(ProfileRunning.into());
+    }
+    // Ends the capture even if sampling panics, so later profiles are not refused forever
+    let _capturing = Capturing;
+    sample(cfg, duration, format)
+}
+
+/// Resets [`PROFILING`] when dropped
+struct Capturing;
+
+impl Drop for Capturing {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Release);
+    }
+}
+
+fn sample(
+    cfg: &ProfilingCfg,
+    duration: Duration,
+    format: ProfileFormat,
+) -> anyhow::Result<Vec<u8>> {
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(cfg.frequency)
+        // Unwinding through these can crash the sampler, see the docs of `pprof`
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .context("could not start profiler")?;
+    std::thread::sleep(duration);
+    let report = guard.report().build().context("could not build profile")?;
+
+    let mut body = vec![];
+    match format {
+        ProfileFormat::Pprof => report
+            .pprof()
+            .context("could not encode profile")?
+            .encode(&mut body)
+            .context("could not encode profile")?,
+        ProfileFormat::Flamegraph => report
+            .flamegraph(&mut body)
+            .context("could not render flamegraph")?,
+    }
+    Ok(body)
+}
+
+/// The spans entered on this thread while profiling, innermost last
+struct EnteredSpans {
+    /// Name of the thread before we renamed it
+    original_name: Option<CString>,
+    stack: Vec<(span::Id, CString)>,
+}
+
+/// Names every thread after the span it is in, while a profile is being captured.
+pub struct SpanNameLayer;
+
+impl<S> Layer<S> for SpanNameLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if !PROFILING.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let name = span.name().as_bytes();
+        let Ok(name) = CString::new(&name[..name.len().min(MAX_THREAD_NAME)]) else {
+            return;
+        };
+
+        ENTERED.with_borrow_mut(|entered| {
+            if entered.stack.is_empty() {
+                entered.original_name = thread_name();
+            }
+            set_thread_name(&name);
+            entered.stack.push((id.clone(), name));
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        // Also after the profile is done, so every thread gets its name back. Spans do not have to
+        // exit in the order they were entered, so we look for this one instead of popping the top.
+        ENTERED.with_borrow_mut(|entered| {
+            let Some(position) = entered.stack.iter().rposition(|(entered, _)| entered == id)
+            else {
+                return;
+            };
+            entered.stack.remove(position);
+            match entered.stack.last() {
+                Some((_, name)) => set_thread_name(name),
+                None => {
+                    if let Some(name) = entered.original_name.take() {
+                        set_thread_name(&name);
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn thread_name() -> Option<CString> {
+    let mut name = [0 as libc::c_char; MAX_THREAD_NAME + 1];
+    // SAFETY: the buffer is as long as we claim, and long enough for every thread name
+    let ret =
+        unsafe { libc::pthread_getname_np(libc::pthread_self(), name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let name: Vec<u8> = name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    CString::new(name).ok()
+}
+
+fn set_thread_name(name: &CString) {
+    // SAFETY: `name` is NUL terminated and at most `MAX_THREAD_NAME` bytes long
+    unsafe { libc::pthread_setname_np(libc::pthread_self(), name.as_ptr()) };
+}
+
+#[cfg(test)]
+mod test {
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Only one test at a time may profile
+    static PROFILER: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn samples_are_tagged_with_their_span() {
+        let _profiler = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+        let subscriber = tracing_subscriber::registry().with(SpanNameLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let before = thread_name();
+
+        let cfg = ProfilingCfg {
+            max_duration: Duration::from_secs(1),
+            frequency: 1_000,
+            admins: vec![],
+        };
+        let profiler = std::thread::spawn(move || {
+            capture(&cfg, Duration::from_millis(300), ProfileFormat::Flamegraph)
+        });
+        while !PROFILING.load(Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+        // Starting the profiler takes a while, so we keep busy until it is done
+        info_span!("busy_work").in_scope(|| {
+            let mut n: u64 = 0;
+            while !profiler.is_finished() {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+        });
+        assert_eq!(thread_name(), before);
+
+        let flamegraph = String::from_utf8(profiler.join().unwrap().unwrap()).unwrap();
+        assert!(flamegraph.contains("<svg"));
+        assert!(flamegraph.contains("busy_work"));
+    }
+
+    #[test]
+    fn threads_get_their_names_back_when_spans_exit_out_of_order() {
+        let _profiler = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+        let subscriber = tracing_subscriber::registry().with(SpanNameLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let before = thread_name();
+
+        PROFILING.store(true, Ordering::Release);
+        let (outer, inner) = (info_span!("outer"), info_span!("inner"));
+        let outer_entered = outer.enter();
+        let inner_entered = inner.enter();
+        assert_eq!(thread_name().unwrap().to_str(), Ok("inner"));
+        drop(outer_entered);
+        assert_eq!(thread_name().unwrap().to_str(), Ok("inner"));
+        drop(inner_entered);
+        PROFILING.store(false, Ordering::Release);
+
+        assert_eq!(thread_name(), before);
+        assert!(ENTERED.with_borrow(|entered| entered.stack.is_empty()));
+    }
+}
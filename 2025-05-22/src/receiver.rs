@@ -0,0 +1,534 @@
+//! A stand-in for an OTLP collector.
+//!
+//! Seeing our telemetry usually means running Grafana's `docker-otel-lgtm` image. For a quick look on
+//! a laptop without docker, or to check in a test that our telemetry really leaves the process,
+//! the [`Receiver`] is enough: it accepts OTLP via gRPC and HTTP (protobuf or JSON), keeps everything
+//! it receives in memory and renders it as JSON or as span trees.
+//!
+//! `src/bin/otlp_receiver.rs` runs it on the default OTLP ports.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use opentelemetry_proto::tonic::{
+    collector::{
+        logs::v1::{
+            ExportLogsServiceRequest, ExportLogsServiceResponse,
+            logs_service_server::{LogsService, LogsServiceServer},
+        },
+        metrics::v1::{
+            ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+            metrics_service_server::{MetricsService, MetricsServiceServer},
+        },
+        trace::v1::{
+            ExportTraceServiceRequest, ExportTraceServiceResponse,
+            trace_service_server::{TraceService, TraceServiceServer},
+        },
+    },
+    common::v1::{AnyValue, any_value},
+    logs::v1::ResourceLogs,
+    metrics::v1::ResourceMetrics,
+    trace::v1::{ResourceSpans, Span, status::StatusCode as SpanStatus},
+};
+use prost::Message;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::net::TcpListener;
+use tonic::transport::{Server, server::TcpIncoming};
+
+/// How the receiver echoes what it receives to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Echo {
+    /// Span trees, metric names and log bodies
+    Pretty,
+    /// One JSON document per request
+    Json,
+}
+
+/// Everything the receiver got so far, in the shape of the OTLP requests.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Telemetry {
+    pub resource_spans: Vec<ResourceSpans>,
+    pub resource_metrics: Vec<ResourceMetrics>,
+    pub resource_logs: Vec<ResourceLogs>,
+}
+
+impl Telemetry {
+    /// All received spans, regardless of their resource and scope
+    pub fn spans(&self) -> impl Iterator<Item = &Span> {
+        self.resource_spans
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+    }
+
+    /// The names of all received metrics
+    pub fn metric_names(&self) -> impl Iterator<Item = &str> {
+        metric_names(&self.resource_metrics)
+    }
+}
+
+fn metric_names(resource_metrics: &[ResourceMetrics]) -> impl Iterator<Item = &str> {
+    resource_metrics
+        .iter()
+        .flat_map(|resource| &resource.scope_metrics)
+        .flat_map(|scope| &scope.metrics)
+        .map(|metric| metric.name.as_str())
+}
+
+/// Accepts OTLP and keeps it in memory. Cheap to clone, all clones share the received telemetry.
+#[derive(Debug, Clone, Default)]
+pub struct Receiver {
+    telemetry: Arc<Mutex<Telemetry>>,
+    echo: Option<Echo>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prints everything the receiver gets to stdout.
+    pub fn with_echo(mut self, echo: Echo) -> Self {
+        self.echo = Some(echo);
+        self
+    }
+
+    /// A snapshot of the telemetry received so far
+    pub fn telemetry(&self) -> Telemetry {
+        self.telemetry
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Serves OTLP/gRPC on `grpc` and OTLP/HTTP on `http` until one of the servers fails.
+    pub async fn serve(self, grpc: TcpListener, http: TcpListener) -> anyhow::Result<()> {
+        let incoming =
+            TcpIncoming::from_listener(grpc, true, None).map_err(|e| anyhow::anyhow!(e))?;
+        let grpc = Server::builder()
+            .add_service(TraceServiceServer::new(self.clone()))
+            .add_service(MetricsServiceServer::new(self.clone()))
+            .add_service(LogsServiceServer::new(self.clone()))
+            .serve_with_incoming(incoming);
+        let http = axum::serve(http, self.router());
+
+        tokio::select! {
+            result = grpc => result?,
+            result = http => result?,
+        }
+        Ok(())
+    }
+
+    /// The OTLP/HTTP endpoints, plus
+    /// - `GET /telemetry`: everything received so far as JSON
+    /// - `GET /traces`: the received spans as trees
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/v1/traces", post(http_traces))
+            .route("/v1/metrics", post(http_metrics))
+            .route("/v1/logs", post(http_logs))
+            .route("/telemetry", get(dump))
+            .route("/traces", get(trees))
+            .with_state(self)
+    }
+
+    fn receive_traces(&self, request: ExportTraceServiceRequest) {
+        match self.echo {
+            Some(Echo::Pretty) => print!("{}", span_trees(&request.resource_spans)),
+            Some(Echo::Json) => echo_json(&request),
+            None => {}
+        }
+        self.lock().resource_spans.extend(request.resource_spans);
+    }
+
+    fn receive_metrics(&self, request: ExportMetricsServiceRequest) {
+        match self.echo {
+            Some(Echo::Pretty) => {
+                for name in metric_names(&request.resource_metrics) {
+                    println!("metric {name}");
+                }
+            }
+            Some(Echo::Json) => echo_json(&request),
+            None => {}
+        }
+        self.lock()
+            .resource_metrics
+            .extend(request.resource_metrics);
+    }
+
+    fn receive_logs(&self, request: ExportLogsServiceRequest) {
+        match self.echo {
+            Some(Echo::Pretty) => {
+                let records = request
+                    .resource_logs
+                    .iter()
+                    .flat_map(|resource| &resource.scope_logs)
+                    .flat_map(|scope| &scope.log_records);
+                for record in records {
+                    println!(
+                        "log {} {}",
+                        record.severity_text,
+                        record.body.as_ref().map(render_value).unwrap_or_default()
+                    );
+                }
+            }
+            Some(Echo::Json) => echo_json(&request),
+            None => {}
+        }
+        self.lock().resource_logs.extend(request.resource_logs);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Telemetry> {
+        self.telemetry.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn echo_json(request: &impl Serialize) {
+    match serde_json::to_string(request) {
+        Ok(json) => println!("{json}"),
+        Err(e) => eprintln!("could not render request as JSON: {e}"),
+    }
+}
+
+#[tonic::async_trait]
+impl TraceService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        self.receive_traces(request.into_inner());
+        Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+#[tonic::async_trait]
+impl MetricsService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.receive_metrics(request.into_inner());
+        Ok(tonic::Response::new(ExportMetricsServiceResponse::default()))
+    }
+}
+
+#[tonic::async_trait]
+impl LogsService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportLogsServiceRequest>,
+    ) -> Result<tonic::Response<ExportLogsServiceResponse>, tonic::Status> {
+        self.receive_logs(request.into_inner());
+        Ok(tonic::Response::new(ExportLogsServiceResponse::default()))
+    }
+}
+
+/// OTLP/HTTP bodies are either protobuf or JSON, the response is encoded the same way.
+fn decode<T: Message + Default + DeserializeOwned>(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(T, bool), (StatusCode, String)> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+    let request = if is_json {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    } else {
+        T::decode(body).map_err(|e| e.to_string())
+    };
+    request
+        .map(|request| (request, is_json))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+fn encode<T: Message + Serialize>(response: T, is_json: bool) -> Response {
+    if is_json {
+        Json(response).into_response()
+    } else {
+        (
+            [(header::CONTENT_TYPE, "application/x-protobuf")],
+            response.encode_to_vec(),
+        )
+            .into_response()
+    }
+}
+
+async fn http_traces(
+    State(receiver): State<Receiver>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match decode(&headers, &body) {
+        Ok((request, is_json)) => {
+            receiver.receive_traces(request);
+            encode(ExportTraceServiceResponse::default(), is_json)
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn http_metrics(
+    State(receiver): State<Receiver>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match decode(&headers, &body) {
+        Ok((request, is_json)) => {
+            receiver.receive_metrics(request);
+            encode(ExportMetricsServiceResponse::default(), is_json)
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn http_logs(State(receiver): State<Receiver>, headers: HeaderMap, body: Bytes) -> Response {
+    match decode(&headers, &body) {
+        Ok((request, is_json)) => {
+            receiver.receive_logs(request);
+            encode(ExportLogsServiceResponse::default(), is_json)
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn dump(State(receiver): State<Receiver>) -> Json<Telemetry> {
+    Json(receiver.telemetry())
+}
+
+async fn trees(State(receiver): State<Receiver>) -> String {
+    span_trees(&receiver.telemetry().resource_spans)
+}
+
+/// Renders spans as one tree per trace:
+///
+/// ```text
+/// trace 4bf92f3577b34da6a3ce929d0e0e4736
+/// └─ http_request 1.2ms
+///    └─ read_user 0.9ms
+///       ├─ retry_attempt 0.1ms ERROR
+///       │  · read_user failed on attempt 1, retrying in 5ms
+///       └─ retry_attempt 0.1ms
+/// ```
+///
+/// Spans whose parent we did not receive are shown as roots.
+pub fn span_trees(resource_spans: &[ResourceSpans]) -> String {
+    let spans: Vec<&Span> = resource_spans
+        .iter()
+        .flat_map(|resource| &resource.scope_spans)
+        .flat_map(|scope| &scope.spans)
+        .collect();
+
+    let mut traces: Vec<&[u8]> = vec![];
+    let mut children: HashMap<&[u8], Vec<&Span>> = HashMap::new();
+    for span in &spans {
+        if !traces.contains(&span.trace_id.as_slice()) {
+            traces.push(&span.trace_id);
+        }
+        children.entry(&span.parent_span_id).or_default().push(span);
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|span| span.start_time_unix_nano);
+    }
+
+    let mut out = String::new();
+    for trace_id in traces {
+        let _ = writeln!(out, "trace {}", hex(trace_id));
+        let roots: Vec<&Span> = spans
+            .iter()
+            .filter(|span| {
+                span.trace_id == trace_id
+                    && !spans
+                        .iter()
+                        .any(|parent| parent.span_id == span.parent_span_id)
+            })
+            .copied()
+            .collect();
+        render_siblings(&mut out, &roots, &children, "");
+    }
+    out
+}
+
+fn render_siblings(
+    out: &mut String,
+    siblings: &[&Span],
+    children: &HashMap<&[u8], Vec<&Span>>,
+    indent: &str,
+) {
+    for (i, span) in siblings.iter().enumerate() {
+        let last = i + 1 == siblings.len();
+        let (branch, continuation) = if last {
+            ("└─ ", "   ")
+        } else {
+            ("├─ ", "│  ")
+        };
+
+        let duration = Duration::from_nanos(
+            span.end_time_unix_nano
+                .saturating_sub(span.start_time_unix_nano),
+        );
+        let error = span
+            .status
+            .as_ref()
+            .is_some_and(|status| status.code == SpanStatus::Error as i32);
+        let _ = writeln!(
+            out,
+            "{indent}{branch}{} {duration:.1?}{}",
+            span.name,
+            if error { " ERROR" } else { "" }
+        );
+
+        let indent = format!("{indent}{continuation}");
+        let children_of_span = children
+            .get(span.span_id.as_slice())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let bar = if children_of_span.is_empty() {
+            " "
+        } else {
+            "│"
+        };
+        for event in &span.events {
+            let _ = writeln!(out, "{indent}{bar} · {}", event.name);
+        }
+        render_siblings(out, children_of_span, children, &indent);
+    }
+}
+
+fn render_value(value: &AnyValue) -> String {
+    match &value.value {
+        Some(any_value::Value::StringValue(value)) => value.clone(),
+        Some(value) => format!("{value:?}"),
+        None => String::new(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use axum::body::Body;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        metrics::{PeriodicReader, SdkMeterProvider},
+        trace::SdkTracerProvider,
+    };
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Starts a receiver on random ports, returns the address of its gRPC endpoint
+    async fn start() -> (Receiver, SocketAddr) {
+        let receiver = Receiver::new();
+        let grpc = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = grpc.local_addr().unwrap();
+        tokio::spawn(receiver.clone().serve(grpc, http));
+        (receiver, addr)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn receives_spans_from_the_tracing_pipeline() {
+        let (receiver, addr) = start().await;
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(format!("http://{addr}"))
+            .build()
+            .unwrap();
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::OpenTelemetryLayer::new(provider.tracer("test")),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = tracing::info_span!("http_request").entered();
+            let _read = tracing::info_span!("read_user").entered();
+            tracing::info!("Read user...");
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let telemetry = receiver.telemetry();
+        let names: Vec<_> = telemetry.spans().map(|span| span.name.as_str()).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"http_request") && names.contains(&"read_user"));
+
+        let trees = span_trees(&telemetry.resource_spans);
+        let lines: Vec<_> = trees.lines().skip(1).collect();
+        assert!(lines[0].starts_with("└─ http_request "), "{trees}");
+        assert!(lines[1].starts_with("   └─ read_user "), "{trees}");
+        assert_eq!(lines[2], "        · Read user...");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn receives_metrics_via_grpc() {
+        let (receiver, addr) = start().await;
+        let exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(format!("http://{addr}"))
+            .build()
+            .unwrap();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter).build())
+            .build();
+
+        opentelemetry::metrics::MeterProvider::meter(&provider, "test")
+            .u64_counter("requests")
+            .build()
+            .add(1, &[]);
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let telemetry = receiver.telemetry();
+        assert!(telemetry.metric_names().any(|name| name == "requests"));
+    }
+
+    #[tokio::test]
+    async fn accepts_json_via_http() {
+        let receiver = Receiver::new();
+        let body = r#"{"resourceSpans":[{"scopeSpans":[{"spans":[{
+            "traceId":"5b8efff798038103d269b633813fc60c",
+            "spanId":"eee19b7ec3c1b174",
+            "name":"http_request",
+            "startTimeUnixNano":"1000000",
+            "endTimeUnixNano":"3000000"
+        }]}]}]}"#;
+        let request = axum::http::Request::post("/v1/traces")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = receiver.clone().router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            span_trees(&receiver.telemetry().resource_spans),
+            "trace 5b8efff798038103d269b633813fc60c\n└─ http_request 2.0ms\n"
+        );
+    }
+}
@@ -0,0 +1,528 @@
+//! Keeping personal data out of our telemetry.
+//!
+//! Our handlers record user names as span fields, and log messages happily interpolate them. That
+//! is handy while debugging, but user names have no business in an observability backend. So every
+//! field name can get a [`Policy`]:
+//! - `drop` removes the field,
+//! - `mask` replaces its value with `***`,
+//! - `hash` replaces its value with a keyed hash. The same name always gets the same hash, so we can
+//!   still follow a user through our traces without knowing who they are.
+//!
+//! The policies are applied to
+//! - spans and span events before they are handed to the exporters ([`RedactingProcessor`]),
+//! - log records before they are handed to the exporters ([`RedactingLogProcessor`]),
+//! - the human readable log lines on stdout ([`RedactingFields`]).
+//!
+//! Log bodies are free text, so for exported spans and log records we additionally replace every
+//! occurrence of a redacted value anywhere in them, as long as it is a word of its own: a user
+//! called `al` leaves `validate` alone.
+
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use anyhow::bail;
+use hmac::{Hmac, Mac};
+use opentelemetry::{
+    Context, InstrumentationScope, Key, KeyValue, Value,
+    logs::{AnyValue, LogRecord, Logger, LoggerProvider},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{LogProcessor, SdkLogRecord, SdkLogger, SdkLoggerProvider},
+    trace::{Span, SpanData, SpanProcessor},
+};
+use sha2::Sha256;
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{FormatFields, format::Writer},
+};
+
+use crate::cfg::RedactionCfg;
+
+/// What happens to the value of a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Drop,
+    Mask,
+    Hash,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Policy::Drop),
+            "mask" => Ok(Policy::Mask),
+            "hash" => Ok(Policy::Hash),
+            _ => bail!("unknown redaction policy `{s}`, expected `drop`, `mask` or `hash`"),
+        }
+    }
+}
+
+/// Applies the configured [`Policy`] of a field to its value. Cheap to clone.
+#[derive(Clone)]
+pub struct Redactor(Arc<RedactionCfg>);
+
+impl fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Redactor").field(&self.0.policies).finish()
+    }
+}
+
+impl Redactor {
+    pub fn new(cfg: RedactionCfg) -> Self {
+        Self(Arc::new(cfg))
+    }
+
+    pub fn policy(&self, field: &str) -> Option<Policy> {
+        self.0.policies.get(field).copied()
+    }
+
+    /// Returns the redacted value, `None` if the field is to be dropped.
+    pub fn redact(&self, policy: Policy, value: &str) -> Option<String> {
+        match policy {
+            Policy::Drop => None,
+            Policy::Mask => Some("***".to_owned()),
+            Policy::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.0.hash_key)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(value.as_bytes());
+                let hash = mac.finalize().into_bytes();
+                // 64 bits are plenty to tell our users apart
+                Some(hash[..8].iter().map(|b| format!("{b:02x}")).collect())
+            }
+        }
+    }
+
+    /// Redacts `attributes` and remembers the original values of redacted ones in `secrets`.
+    fn redact_attributes(
+        &self,
+        attributes: &mut Vec<KeyValue>,
+        secrets: &mut HashMap<String, String>,
+    ) {
+        attributes.retain_mut(|kv| {
+            let Some(policy) = self.policy(kv.key.as_str()) else {
+                return true;
+            };
+            let value = kv.value.as_str().into_owned();
+            match self.redact(policy, &value) {
+                Some(redacted) => {
+                    secrets.insert(value, redacted.clone());
+                    kv.value = Value::from(redacted);
+                    true
+                }
+                None => {
+                    secrets.insert(value, "***".to_owned());
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Replaces every secret in `text` with its redacted version, `None` if there is none.
+fn replace_secrets(text: &str, secrets: &HashMap<String, String>) -> Option<String> {
+    let mut replaced = None;
+    for (secret, redacted) in secrets {
+        let current = replaced.as_deref().unwrap_or(text);
+        if let Some(text) = replace_word(current, secret, redacted) {
+            replaced = Some(text);
+        }
+    }
+    replaced
+}
+
+/// Replaces `word` in `text` wherever it is not part of a longer word, `None` if it is nowhere.
+fn replace_word(text: &str, word: &str, with: &str) -> Option<String> {
+    if word.is_empty() {
+        return None;
+    }
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut replaced = String::new();
+    let mut copied = 0;
+    for (start, _) in text.match_indices(word) {
+        let end = start + word.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            continue;
+        }
+        replaced.push_str(&text[copied..start]);
+        replaced.push_str(with);
+        copied = end;
+    }
+    if copied == 0 {
+        return None;
+    }
+    replaced.push_str(&text[copied..]);
+    Some(replaced)
+}
+
+/// Redacts spans before passing them on to the `next` processor.
+#[derive(Debug)]
+pub struct RedactingProcessor<P> {
+    next: P,
+    redactor: Redactor,
+}
+
+impl<P: SpanProcessor> RedactingProcessor<P> {
+    pub fn new(next: P, redactor: Redactor) -> Self {
+        Self { next, redactor }
+    }
+
+    fn redact(&self, span: &mut SpanData) {
+        let mut secrets = HashMap::new();
+        self.redactor
+            .redact_attributes(&mut span.attributes, &mut secrets);
+        for event in span.events.events.iter_mut() {
+            self.redactor
+                .redact_attributes(&mut event.attributes, &mut secrets);
+        }
+        if secrets.is_empty() {
+            return;
+        }
+
+        // Log messages and other free text might still contain the values we just redacted
+        for event in span.events.events.iter_mut() {
+            if let Some(name) = replace_secrets(&event.name, &secrets) {
+                event.name = name.into();
+            }
+        }
+        let events = span
+            .events
+            .events
+            .iter_mut()
+            .map(|event| &mut event.attributes);
+        for attributes in std::iter::once(&mut span.attributes).chain(events) {
+            for kv in attributes.iter_mut() {
+                if let Value::String(value) = &kv.value
+                    && let Some(replaced) = replace_secrets(value.as_str(), &secrets)
+                {
+                    kv.value = Value::from(replaced);
+                }
+            }
+        }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for RedactingProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.next.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        self.redact(&mut span);
+        self.next.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+/// Redacts log records before passing them on to the `next` processor.
+///
+/// Log records can only get more attributes, not lose or change them, so we build a new record
+/// with the redacted attributes and hand on that one.
+#[derive(Debug)]
+pub struct RedactingLogProcessor<P> {
+    next: P,
+    redactor: Redactor,
+    /// Creates the empty records we copy the redacted ones into, never emits anything
+    blank: SdkLogger,
+}
+
+impl<P: LogProcessor> RedactingLogProcessor<P> {
+    pub fn new(next: P, redactor: Redactor) -> Self {
+        Self {
+            next,
+            redactor,
+            blank: SdkLoggerProvider::builder().build().logger("redaction"),
+        }
+    }
+
+    /// The redacted copy of `record`, `None` if there is nothing to redact.
+    fn redact(&self, record: &SdkLogRecord) -> Option<SdkLogRecord> {
+        let mut secrets = HashMap::new();
+        let mut attributes: Vec<(Key, AnyValue)> = vec![];
+        for (key, value) in record.attributes_iter() {
+            let Some(policy) = self.redactor.policy(key.as_str()) else {
+                attributes.push((key.clone(), value.clone()));
+                continue;
+            };
+            let value = match value {
+                AnyValue::String(value) => value.to_string(),
+                AnyValue::Int(value) => value.to_string(),
+                AnyValue::Double(value) => value.to_string(),
+                AnyValue::Boolean(value) => value.to_string(),
+                value => format!("{value:?}"),
+            };
+            let redacted = self.redactor.redact(policy, &value);
+            if let Some(redacted) = &redacted {
+                attributes.push((key.clone(), AnyValue::from(redacted.clone())));
+            }
+            secrets.insert(value, redacted.unwrap_or_else(|| "***".to_owned()));
+        }
+        if secrets.is_empty() {
+            return None;
+        }
+
+        let mut redacted = self.blank.create_log_record();
+        if let Some(name) = record.event_name() {
+            redacted.set_event_name(name);
+        }
+        if let Some(target) = record.target() {
+            redacted.set_target(target.clone());
+        }
+        if let Some(timestamp) = record.timestamp() {
+            redacted.set_timestamp(timestamp);
+        }
+        if let Some(timestamp) = record.observed_timestamp() {
+            redacted.set_observed_timestamp(timestamp);
+        }
+        if let Some(cx) = record.trace_context() {
+            redacted.set_trace_context(cx.trace_id, cx.span_id, cx.trace_flags);
+        }
+        if let Some(text) = record.severity_text() {
+            redacted.set_severity_text(text);
+        }
+        if let Some(number) = record.severity_number() {
+            redacted.set_severity_number(number);
+        }
+        // The message might still contain the values we just redacted
+        match record.body() {
+            Some(AnyValue::String(body)) => redacted.set_body(
+                replace_secrets(body.as_str(), &secrets)
+                    .map_or_else(|| body.clone().into(), AnyValue::from),
+            ),
+            Some(body) => redacted.set_body(body.clone()),
+            None => {}
+        }
+        for (key, value) in attributes {
+            let value = match value {
+                AnyValue::String(text) => match replace_secrets(text.as_str(), &secrets) {
+                    Some(replaced) => AnyValue::from(replaced),
+                    None => AnyValue::String(text),
+                },
+                value => value,
+            };
+            redacted.add_attribute(key, value);
+        }
+        Some(redacted)
+    }
+}
+
+impl<P: LogProcessor> LogProcessor for RedactingLogProcessor<P> {
+    fn emit(&self, record: &mut SdkLogRecord, scope: &InstrumentationScope) {
+        match self.redact(record) {
+            Some(mut redacted) => self.next.emit(&mut redacted, scope),
+            None => self.next.emit(record, scope),
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+/// Formats the fields of log lines like the default formatter of `tracing_subscriber::fmt`, but
+/// applies the redaction policies.
+#[derive(Debug, Clone)]
+pub struct RedactingFields {
+    redactor: Redactor,
+}
+
+impl RedactingFields {
+    pub fn new(redactor: Redactor) -> Self {
+        Self { redactor }
+    }
+}
+
+impl<'writer> FormatFields<'writer> for RedactingFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = RedactingVisitor {
+            writer,
+            redactor: &self.redactor,
+            is_empty: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+struct RedactingVisitor<'a, 'writer> {
+    writer: Writer<'writer>,
+    redactor: &'a Redactor,
+    is_empty: bool,
+    result: fmt::Result,
+}
+
+impl RedactingVisitor<'_, '_> {
+    fn write(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.result.is_err() {
+            return;
+        }
+        if !self.is_empty {
+            self.result = self.writer.write_char(' ');
+        }
+        self.is_empty = false;
+        self.result = self.result.and_then(|_| match field.name() {
+            "message" => write!(self.writer, "{value:?}"),
+            name => write!(self.writer, "{name}={value:?}"),
+        });
+    }
+}
+
+impl Visit for RedactingVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match self.redactor.policy(field.name()) {
+            Some(policy) => {
+                if let Some(redacted) = self.redactor.redact(policy, value) {
+                    self.write(field, &redacted);
+                }
+            }
+            None => self.write(field, &value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match self.redactor.policy(field.name()) {
+            Some(policy) => {
+                let value = format!("{value:?}");
+                if let Some(redacted) = self.redactor.redact(policy, &value) {
+                    self.write(field, &redacted);
+                }
+            }
+            None => self.write(field, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use opentelemetry::trace::Event;
+
+    use super::*;
+    use crate::test_util::CollectLogs;
+
+    fn redactor() -> Redactor {
+        Redactor::new(RedactionCfg {
+            policies: HashMap::from([
+                ("name".to_owned(), Policy::Hash),
+                ("email".to_owned(), Policy::Drop),
+                ("user_uuid".to_owned(), Policy::Mask),
+            ]),
+            hash_key: b"test-key".to_vec(),
+        })
+    }
+
+    #[test]
+    fn hashes_are_keyed_and_stable() {
+        let redactor = redactor();
+        let hash = redactor.redact(Policy::Hash, "mert").unwrap();
+        assert_eq!(hash, redactor.redact(Policy::Hash, "mert").unwrap());
+        assert_ne!(hash, redactor.redact(Policy::Hash, "ferris").unwrap());
+
+        let other_key = Redactor::new(RedactionCfg {
+            policies: HashMap::new(),
+            hash_key: b"other-key".to_vec(),
+        });
+        assert_ne!(hash, other_key.redact(Policy::Hash, "mert").unwrap());
+    }
+
+    #[test]
+    fn redacts_attributes_and_log_bodies() {
+        let redactor = redactor();
+        let hash = redactor.redact(Policy::Hash, "mert").unwrap();
+
+        let mut attributes = vec![
+            KeyValue::new("name", "mert"),
+            KeyValue::new("email", "mert@example.com"),
+            KeyValue::new("user_uuid", "1234"),
+            KeyValue::new("method", "GET"),
+        ];
+        let mut secrets = HashMap::new();
+        redactor.redact_attributes(&mut attributes, &mut secrets);
+        assert_eq!(
+            attributes,
+            vec![
+                KeyValue::new("name", hash.clone()),
+                KeyValue::new("user_uuid", "***"),
+                KeyValue::new("method", "GET"),
+            ]
+        );
+
+        let event = Event::new(
+            "Create new user with name mert...",
+            SystemTime::now(),
+            vec![],
+            0,
+        );
+        assert_eq!(
+            replace_secrets(&event.name, &secrets).unwrap(),
+            format!("Create new user with name {hash}...")
+        );
+        // Only words of their own are secrets
+        assert_eq!(replace_secrets("mertens and emerta", &secrets), None);
+        assert_eq!(
+            replace_secrets("mert's friend: mert_2, mert", &secrets).unwrap(),
+            format!("{hash}'s friend: mert_2, {hash}")
+        );
+    }
+
+    #[test]
+    fn redacts_log_records() {
+        let redactor = redactor();
+        let hash = redactor.redact(Policy::Hash, "al").unwrap();
+        let collect = CollectLogs::default();
+        let provider = SdkLoggerProvider::builder()
+            .with_log_processor(RedactingLogProcessor::new(collect.clone(), redactor))
+            .build();
+        let logger = provider.logger("test");
+
+        let mut record = logger.create_log_record();
+        record.set_body("Validate user al".into());
+        record.set_severity_text("INFO");
+        record.add_attribute("name", "al");
+        record.add_attribute("email", "al@example.com");
+        record.add_attribute("method", "GET");
+        logger.emit(record);
+
+        let records = collect.0.lock().unwrap();
+        assert_eq!(
+            records[0].body(),
+            Some(&AnyValue::from(format!("Validate user {hash}")))
+        );
+        assert_eq!(records[0].severity_text(), Some("INFO"));
+        let attributes: Vec<_> = records[0].attributes_iter().cloned().collect();
+        assert_eq!(
+            attributes,
+            [
+                (Key::new("name"), AnyValue::from(hash)),
+                (Key::new("method"), AnyValue::from("GET")),
+            ]
+        );
+    }
+}
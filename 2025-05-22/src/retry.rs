@@ -0,0 +1,142 @@
+//! Retrying flaky operations.
+//!
+//! Our user store fails randomly (see `business.rs`), so instead of answering with a 500 right away,
+//! we try again a couple of times. Every attempt gets its own `retry_attempt` span, which makes
+//! retries easy to spot in the trace waterfall: failed attempts are marked as errors and the gaps
+//! between them are the backoff.
+//!
+//! Only retry operations that are safe to repeat! Reads always are, creates only when the caller
+//! sent an idempotency key.
+
+use std::{future::Future, time::Duration};
+
+use opentelemetry::{KeyValue, metrics::Counter};
+use tracing::{Instrument, info_span, warn};
+
+use crate::{cfg::RetryCfg, circuit_breaker::CircuitOpen, tenant};
+
+pub struct RetryPolicy {
+    cfg: RetryCfg,
+    /// Counts operations that failed even after the last attempt
+    exhausted: Counter<u64>,
+}
+
+impl RetryPolicy {
+    pub fn new(cfg: RetryCfg) -> Self {
+        let exhausted = opentelemetry::global::meter("server_measurements")
+            .u64_counter("retry.exhausted")
+            .with_description("Operations that failed after exhausting all retry attempts")
+            .build();
+
+        Self { cfg, exhausted }
+    }
+
+    /// Runs `operation` until it succeeds or we run out of attempts. Returns the last error in
+    /// the latter case.
+    pub async fn run<T, F, Fut>(&self, name: &'static str, mut operation: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let span = info_span!(
+                "retry_attempt",
+                operation = name,
+                attempt,
+                otel.status_code = tracing::field::Empty,
+            );
+
+            let result = operation().instrument(span.clone()).await;
+            let err = match result {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            span.record("otel.status_code", "ERROR");
+
+            // While the circuit breaker is open, another attempt would only fail fast again
+            if err.is::<CircuitOpen>() {
+                return Err(err);
+            }
+
+            if attempt >= self.cfg.max_attempts {
+                warn!("{name} failed after {attempt} attempts");
+                let mut attributes = tenant::attributes();
+                attributes.push(KeyValue::new("operation", name));
+                self.exhausted.add(1, &attributes);
+                return Err(err);
+            }
+
+            let backoff = self.backoff(attempt);
+            warn!("{name} failed on attempt {attempt}, retrying in {backoff:?}: {err:#}");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Exponential backoff with jitter: waits between half and all of
+    /// `initial_backoff * 2^(attempt - 1)`, so that callers that failed at the same time do not
+    /// retry at the same time.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .cfg
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.cfg.max_backoff);
+        exponential.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(RetryCfg {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+        })
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let mut calls = 0;
+        let result = policy(3)
+            .run("test", || {
+                calls += 1;
+                let calls = calls;
+                async move {
+                    if calls < 3 {
+                        anyhow::bail!("flaky");
+                    }
+                    Ok(calls)
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = policy(2)
+            .run("test", || {
+                calls += 1;
+                async { anyhow::bail!("always fails") }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = policy(10);
+        assert!(policy.backoff(1) <= Duration::from_millis(1));
+        assert!(policy.backoff(3) >= Duration::from_millis(2));
+        assert!(policy.backoff(8) <= Duration::from_millis(4));
+    }
+}
@@ -1,44 +1,236 @@
-use std::{sync::Arc, time::Duration};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use axum::{
-    extract::{MatchedPath, Path, State},
-    http::{Request, Response, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
+    Extension, Json, Router,
+    body::Body,
+    extract::{FromRequestParts, MatchedPath, Path, Query, State},
+    http::{
+        Extensions, HeaderMap, Request, Response, StatusCode, header::CONTENT_TYPE, request::Parts,
+    },
+    middleware::{self, Next},
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
 };
-use opentelemetry::metrics::Histogram;
-use tokio::sync::Mutex;
+use futures_util::{Stream, StreamExt, stream};
+use opentelemetry::{KeyValue, metrics::Histogram};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::trace::TraceLayer;
-use tracing::{Span, debug, info, info_span, instrument, trace, warn};
+use tracing::{Span, debug, field::Empty, info, info_span, instrument, trace, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
 
+#[cfg(feature = "grpc")]
+use crate::grpc;
+#[cfg(feature = "prometheus")]
+use crate::openmetrics;
+#[cfg(target_os = "linux")]
+use crate::profiling::{self, ProfileFormat, ProfileRunning};
 use crate::{
-    business::{NewUser, ReadUser, UserManager},
-    cfg::Cfg,
+    audit::{Actor, AuditLog, AuditPage, AuditQuery},
+    auth::{self, Authenticator, Principal},
+    business::{NameTaken, NewUser, QuotaExceeded, ReadUser, User, UserManager, ValidationErrors},
+    cfg::{Cfg, ProfilingCfg},
+    circuit_breaker::{CircuitBreaker, CircuitOpen},
+    enrichment::{self, Enrichment, EnrichmentClient},
+    events::UserEvent,
+    exemplars, http_client,
+    import::{Format, ImportReport, Row, Rows},
+    jobs::JobQueue,
+    openapi::ApiDoc,
+    panics,
+    pipeline::{self, Health},
+    retry::RetryPolicy,
+    tenant::{self, TENANT_ATTRIBUTE, Tenant, Tenants},
 };
 
+/// Header that makes creating users safe to retry, see [`UserManager::create_idempotent`]
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Number of rows of an import that are created within one `import_batch` span
+const IMPORT_BATCH_SIZE: usize = 100;
+
+/// Header that carries the correlation ID of a request
+pub(crate) const CORRELATION_ID_HEADER: &str = "correlation_id";
+
+/// The correlation ID of a request, as a request extension
+#[derive(Debug, Clone)]
+pub(crate) struct CorrelationId(pub(crate) String);
+
+/// Everything our `/users` handlers, and those of the gRPC `UserService`, need to get their job
+/// done
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) retry: Arc<RetryPolicy>,
+    pub(crate) circuit_breaker: Arc<CircuitBreaker>,
+    /// The instance that enriches our users, `None` if we do it ourselves
+    pub(crate) enrichment: Option<Arc<EnrichmentClient>>,
+    /// Work that happens after the response is sent
+    pub(crate) jobs: Arc<JobQueue>,
+}
+
+/// Maps errors of the user store to a response status.
+fn store_error_status(err: &anyhow::Error) -> StatusCode {
+    if err.is::<CircuitOpen>() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if err.is::<ValidationErrors>() {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else if err.is::<QuotaExceeded>() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else if err.is::<NameTaken>() {
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 pub async fn host_server(cfg: Cfg) -> anyhow::Result<()> {
-    let addr = format!("0.0.0.0:{}", cfg.port);
+    let listener = bind("Web server", cfg.port).await?;
+    #[cfg(feature = "grpc")]
+    let grpc_listener = bind("gRPC server", cfg.grpc_port).await?;
+
+    let routers = routers(cfg)?;
+    #[cfg(feature = "grpc")]
+    tokio::try_join!(
+        axum::serve(listener, routers.http).into_future(),
+        axum::serve(grpc_listener, routers.grpc).into_future(),
+    )
+    .context("server shut down")?;
+    #[cfg(not(feature = "grpc"))]
+    axum::serve(listener, routers.http)
+        .await
+        .context("server shut down")?;
+    Ok(())
+}
+
+/// Binds the local `port` for the server called `name`.
+async fn bind(name: &str, port: u16) -> anyhow::Result<tokio::net::TcpListener> {
+    let addr = format!("0.0.0.0:{port}");
 
     trace!("Trying to bind to local port on {addr}");
     let listener = tokio::net::TcpListener::bind(addr)
         .await
-        .context("could not create TCP listener for web server")?;
+        .with_context(|| format!("could not create TCP listener for {name}"))?;
 
     info!(
-        "Web server will be listening on {}",
+        "{name} will be listening on {}",
         listener
             .local_addr()
-            .context("Cannot access address of local web server socket")?
+            .with_context(|| format!("Cannot access address of local {name} socket"))?
     );
+    Ok(listener)
+}
+
+/// Our servers, not yet bound to a port. Both work on the same user stores.
+pub struct Routers {
+    /// The web server
+    pub http: Router,
+    /// The gRPC `UserService` for internal callers, see `grpc.rs`
+    #[cfg(feature = "grpc")]
+    pub grpc: Router,
+}
 
-    let user_manager = Arc::new(Mutex::new(UserManager::new()));
+/// Builds our web server from `cfg`, without binding it to a port. Starts the workers of the job
+/// queue, so this has to be called within a tokio runtime.
+pub fn router(cfg: Cfg) -> anyhow::Result<Router> {
+    Ok(routers(cfg)?.http)
+}
 
-    let app = axum::Router::new()
-        .route("/users/add/{name}", post(add_user))
-        .route("/users/read/{name}", get(read_user))
-        .with_state(user_manager)
-        .route("/hello", get(hello_route))
+/// Like [`router`], but along with the gRPC server.
+pub fn routers(cfg: Cfg) -> anyhow::Result<Routers> {
+    let audit_log =
+        Arc::new(AuditLog::open(&cfg.audit_log).context("could not open the audit log")?);
+    let tenants = Tenants::new(cfg.tenants, {
+        let audit_log = audit_log.clone();
+        move || UserManager::new().with_audit_log(audit_log.clone())
+    });
+    // The audit log is all we keep of our users, so we pick up where it left off
+    let entries = audit_log
+        .query_all(AuditQuery::default())
+        .context("could not read the audit log")?;
+    tenants
+        .restore(&entries)
+        .context("could not restore our users")?;
+    let tenants = Arc::new(tenants);
+    let state = AppState {
+        retry: Arc::new(RetryPolicy::new(cfg.retry)),
+        circuit_breaker: Arc::new(CircuitBreaker::new("user_store", cfg.circuit_breaker)),
+        enrichment: EnrichmentClient::from_cfg(&cfg.enrichment)
+            .context("invalid enrichment config")?
+            .map(Arc::new),
+        jobs: Arc::new(JobQueue::start(cfg.jobs)),
+    };
+    let authenticator =
+        Arc::new(Authenticator::from_cfg(&cfg.auth).context("invalid authentication config")?);
+
+    Ok(Routers {
+        #[cfg(feature = "grpc")]
+        grpc: grpc::router(state.clone(), authenticator.clone(), tenants.clone()),
+        http: app(state, authenticator, tenants, cfg.profiling),
+    })
+}
+
+/// Puts together all routes, the OpenAPI document describing them and the tracing layer.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn app(
+    state: AppState,
+    authenticator: Arc<Authenticator>,
+    tenants: Arc<Tenants>,
+    profiling: ProfilingCfg,
+) -> Router {
+    // -- Routes are registered through an `OpenApiRouter`, which collects the `#[utoipa::path]`
+    // -- documentation of every handler it gets. This way, no route can sneak past the document.
+    let router = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(add_user))
+        .routes(routes!(read_user))
+        .routes(routes!(list_users))
+        .routes(routes!(update_user))
+        .routes(routes!(delete_user))
+        .routes(routes!(import_users))
+        .routes(routes!(user_events))
+        .routes(routes!(audit_entries))
+        .routes(routes!(enriched_user))
+        // -- Every `/users` route works on the store of one tenant, see `tenant.rs`
+        .route_layer(middleware::from_fn_with_state(tenants, tenant::identify))
+        // -- Only the `/users` routes are authenticated. `route_layer` makes sure that requests to
+        // -- unknown paths still get a 404 instead of a 401. Layers added later run first, so we
+        // -- know who sent the request before we look for its tenant.
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.clone(),
+            auth::authenticate,
+        ))
+        .with_state(state);
+
+    // -- Admin routes need credentials, too, but belong to no tenant
+    #[cfg(target_os = "linux")]
+    let router = router.merge(
+        OpenApiRouter::new()
+            .routes(routes!(cpu_profile))
+            .route_layer(middleware::from_fn_with_state(
+                authenticator,
+                auth::authenticate,
+            ))
+            .with_state(Arc::new(profiling)),
+    );
+
+    let router = router
+        .routes(routes!(hello_route))
+        .routes(routes!(enrichment_route))
+        .routes(routes!(openapi_json))
+        .routes(routes!(health));
+    #[cfg(feature = "prometheus")]
+    let router = router.routes(routes!(metrics));
+    let (router, api) = router.split_for_parts();
+
+    router
+        .with_state(Arc::new(api))
+        // -- A panicking handler answers with a 500 instead of dropping the connection. The layer
+        // -- sits within the tracing layer, so the panic is recorded on the `http_request` span.
+        .layer(middleware::from_fn(panics::catch_panic))
         // -- Create a tracing layer that generates nicely formatted HTTP traces-
         // -- The logic displays how to fill a custom `correlation_id` field on the automatically
         // -- created spans.
@@ -50,40 +242,93 @@ pub async fn host_server(cfg: Cfg) -> anyhow::Result<()> {
                         .get::<MatchedPath>()
                         .map(MatchedPath::as_str);
 
-                    info_span!(
+                    let span = info_span!(
                         "http_request",
                         method = ?request.method(),
                         matched_path,
                         correlation_id = tracing::field::Empty, // <-- Create an empty field on the span here
-                    )
+                        { TENANT_ATTRIBUTE } = tracing::field::Empty,
+                    );
+                    // -- If another service called us, our spans join its trace (see `http_client.rs`)
+                    span.set_parent(http_client::extract_context(request.headers()));
+                    span
                 })
                 .on_request(|request: &Request<_>, span: &Span| {
-                    let id = request
-                        .headers()
-                        .get("correlation_id")
-                        .and_then(|a| a.to_str().ok());
-                    // --> Fill the empty "correlation_id" span with
-                    // a) the value included in the "correlation_id" header or
-                    // b) with a newly generated ID.
-                    if let Some(id) = id {
-                        span.record("correlation_id", id);
-                    } else {
-                        let id = uuid::Uuid::new_v4().to_string();
-                        span.record("correlation_id", id);
+                    // --> Fill the empty "correlation_id" span with the ID the `correlation_id`
+                    // middleware picked for this request
+                    if let Some(CorrelationId(id)) = request.extensions().get() {
+                        span.record("correlation_id", id.as_str());
                     }
                 })
-                .on_response(|_response: &Response<_>, latency: Duration, _span: &Span| {
+                .on_response(|response: &Response<_>, latency: Duration, _span: &Span| {
                     // The opentelemetry sdk docs specifically advice against creating an instrument like this
                     // within a hot loop. In a real application, we would instantiate the histogram once.
                     let histogram = build_latency_histogram();
-                    histogram.record(latency.as_micros() as f64, &[]);
+                    // `tenant::identify` left the tenant of the request on the response
+                    let attributes: Vec<_> = response
+                        .extensions()
+                        .get::<Tenant>()
+                        .map(|tenant| KeyValue::new(TENANT_ATTRIBUTE, tenant.to_string()))
+                        .into_iter()
+                        .collect();
+                    // We are still within the `http_request` span here, so the recording can link to it
+                    exemplars::record(
+                        &histogram,
+                        LATENCY_HISTOGRAM,
+                        latency.as_micros() as f64,
+                        &attributes,
+                    );
                     debug!("latency micros: {:#?}", latency.as_micros());
                 }),
-        );
+        )
+        // -- Layers added later run first, so the correlation ID is there when the span is created
+        .layer(middleware::from_fn(correlation_id))
+}
+
+/// Takes the correlation ID from the `correlation_id` header, or generates a new one, and stores
+/// it as a request extension for the tracing layer and the audit log.
+pub(crate) async fn correlation_id(mut request: Request<Body>, next: Next) -> Response<Body> {
+    let id = request
+        .headers()
+        .get(CORRELATION_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    request.extensions_mut().insert(CorrelationId(id));
+    next.run(request).await
+}
+
+/// Handlers that change users take the [`Actor`] from the request: the authenticated principal,
+/// the correlation ID and the tenant.
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(actor(&parts.extensions))
+    }
+}
 
-    axum::serve(listener, app).await.context("server shut down")
+/// The [`Actor`] of a request, from the extensions our middlewares left on it
+pub(crate) fn actor(extensions: &Extensions) -> Actor {
+    let name = extensions
+        .get::<Principal>()
+        .map_or("anonymous", |principal| principal.name.as_str());
+    let correlation_id = extensions
+        .get::<CorrelationId>()
+        .map(|CorrelationId(id)| id.clone());
+    let actor = Actor::new(name, correlation_id);
+    match extensions.get::<Tenant>() {
+        Some(tenant) => actor.with_tenant(tenant.as_str()),
+        None => actor,
+    }
 }
 
+/// Say hello
+#[utoipa::path(
+    get,
+    path = "/hello",
+    responses((status = OK, description = "A friendly greeting", body = String, content_type = "text/plain"))
+)]
 #[instrument(name = "my_hello_span", level = tracing::Level::WARN)]
 async fn hello_route() -> &'static str {
     info!("Within hello route");
@@ -91,34 +336,197 @@ async fn hello_route() -> &'static str {
     "hello"
 }
 
-#[instrument(skip(user_manager))]
+/// Create a new user
+#[utoipa::path(
+    post,
+    path = "/users/add/{name}",
+    tag = "users",
+    params(
+        ("name" = String, Path, description = "Name of the new user"),
+        ("email" = Option<String>, Query, description = "Email address of the new user"),
+        ("idempotency-key" = Option<String>, Header, description = "Makes the request safe to retry: the same key never creates a second user"),
+    ),
+    responses(
+        (status = OK, description = "The user was created"),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = CONFLICT, description = "There already is a user with this name"),
+        (status = TOO_MANY_REQUESTS, description = "The tenant used up its quota of users"),
+        (status = UNPROCESSABLE_ENTITY, description = "The user is invalid", body = ValidationErrors),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip(state, user_manager, query, actor, headers))]
 async fn add_user(
-    State(user_manager): State<Arc<Mutex<UserManager>>>,
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
     Path(name): Path<String>,
-) -> impl IntoResponse {
-    info!("Create new user with name {name}...");
+    Query(query): Query<NewUserQuery>,
+    actor: Actor,
+    headers: HeaderMap,
+) -> Result<StatusCode, Response<Body>> {
+    // The name is a field of our span, so it gets redacted (see `redaction.rs`)
+    info!("Create new user...");
 
-    if let Err(e) = user_manager.lock().await.create(NewUser::new(&name)) {
-        warn!("Could not create user with name {name}:\n{e:?}");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut new_user = NewUser::new(&name);
+    if let Some(email) = &query.email {
+        new_user = new_user.with_email(email);
     }
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
 
-    Ok(StatusCode::OK)
+    match create_user(&state, &user_manager, &actor, new_user, idempotency_key).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            let status = store_error_status(&e);
+            Err(match e.downcast::<ValidationErrors>() {
+                Ok(errors) => (status, Json(errors)).into_response(),
+                Err(e) if e.is::<QuotaExceeded>() => (status, e.to_string()).into_response(),
+                Err(_) => status.into_response(),
+            })
+        }
+    }
 }
 
-#[instrument(skip(user_manager), fields(user_uuid))]
+/// Creates a user, for `add_user` as well as for the `CreateUser` call of `grpc.rs`, so both
+/// behave the same. Invalid users fail with [`ValidationErrors`] and users beyond the quota with
+/// [`QuotaExceeded`], before the store is called. Names of other users fail with [`NameTaken`].
+/// Once the user is created, a job welcomes it.
+pub(crate) async fn create_user(
+    state: &AppState,
+    user_manager: &Arc<UserManager>,
+    actor: &Actor,
+    new_user: NewUser,
+    idempotency_key: Option<&str>,
+) -> anyhow::Result<uuid::Uuid> {
+    // Invalid users are not the store's fault, so they must not count against the circuit breaker
+    if let Err(errors) = new_user.validate() {
+        info!("Rejecting invalid user: {errors}");
+        return Err(errors.into());
+    }
+    if let Err(e) = user_manager.check_quota() {
+        info!("Rejecting user: {e}");
+        return Err(e.into());
+    }
+    let email = new_user.email().map(str::to_owned);
+
+    // Without an idempotency key, a retry could create the same user twice - so we do not retry.
+    let result = match idempotency_key {
+        Some(key) => {
+            state
+                .retry
+                .run("create_user", || {
+                    let (breaker, user_manager) =
+                        (state.circuit_breaker.clone(), user_manager.clone());
+                    let (actor, key, new_user) = (actor.clone(), key.to_owned(), new_user.clone());
+                    blocking(move || {
+                        breaker.call(|| user_manager.create_idempotent(&actor, &key, new_user))
+                    })
+                })
+                .await
+        }
+        None => {
+            let (breaker, user_manager) = (state.circuit_breaker.clone(), user_manager.clone());
+            let actor = actor.clone();
+            blocking(move || breaker.call(|| user_manager.create(&actor, new_user))).await
+        }
+    };
+    let id = result.inspect_err(|e| warn!("Could not create user:\n{e:?}"))?;
+
+    // -- The welcome does not hold up the response. Its job runs in a trace of its own, linked to
+    // -- this request (see `jobs.rs`). `#[instrument]` creates the span of `welcome_user` when it
+    // -- is called, so we call it once the job runs, not here within the request.
+    let welcome = async move { welcome_user(id, email).await };
+    if let Err(e) = state.jobs.enqueue("welcome_user", welcome) {
+        warn!("The new user will not be welcomed:\n{e:?}");
+    }
+
+    Ok(id)
+}
+
+/// Runs `change` on a thread where blocking is fine, within the current span and tenant. Changes
+/// of the user store wait until their audit entry is on disk, which must not hold up a worker of
+/// the runtime, and with it every other request on that worker.
+pub(crate) async fn blocking<T: Send + 'static>(change: impl FnOnce() -> T + Send + 'static) -> T {
+    let (span, tenant) = (Span::current(), tenant::current());
+    let result =
+        tokio::task::spawn_blocking(move || span.in_scope(|| tenant::sync_scope(tenant, change)))
+            .await;
+    match result {
+        Ok(output) => output,
+        // The panic carries on in the handler, where `catch_panic` turns it into a `500`
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// How long sending a welcome email takes, or so we pretend
+const WELCOME_DELAY: Duration = Duration::from_millis(50);
+
+/// Welcomes a new user, runs as a job after the user was created.
+#[instrument(skip(email))]
+pub(crate) async fn welcome_user(id: uuid::Uuid, email: Option<String>) -> anyhow::Result<()> {
+    if email.is_none() {
+        info!("No email address, nobody to welcome");
+        return Ok(());
+    }
+    tokio::time::sleep(WELCOME_DELAY).await;
+    info!("Sent welcome email");
+    Ok(())
+}
+
+/// Optional fields of a new user
+#[derive(Debug, Deserialize)]
+struct NewUserQuery {
+    email: Option<String>,
+}
+
+/// Rejects an invalid user with a `422` that lists every problem.
+fn validate(new_user: &NewUser) -> Result<(), (StatusCode, Json<ValidationErrors>)> {
+    new_user.validate().map_err(|errors| {
+        info!("Rejecting invalid user: {errors}");
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(errors))
+    })
+}
+
+/// Read a user by name
+#[utoipa::path(
+    get,
+    path = "/users/read/{name}",
+    tag = "users",
+    params(("name" = String, Path, description = "Name of the user")),
+    responses(
+        (status = OK, description = "The user as `<id>:<name>`", body = String, content_type = "text/plain"),
+        (status = NO_CONTENT, description = "There is no user with this name"),
+        (status = UNAUTHORIZED, description = "The request carried invalid credentials"),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security((), ("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip(state, user_manager), fields(user_uuid))]
 async fn read_user(
-    State(user_manager): State<Arc<Mutex<UserManager>>>,
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
-    info!("Read user with name {name}...");
+    info!("Read user...");
+
+    let (user_manager, breaker, name) = (&user_manager, &state.circuit_breaker, &name);
+    let user = state
+        .retry
+        .run("read_user", || async move {
+            breaker.call(|| user_manager.read_by_name(ReadUser::new(name)))
+        })
+        .await;
 
-    let user = match user_manager.lock().await.read_by_name(ReadUser::new(&name)) {
+    let user = match user {
         Ok(Some(user)) => user,
-        Ok(None) => return Ok((StatusCode::NO_CONTENT, format!("no user found"))),
+        Ok(None) => return Ok((StatusCode::NO_CONTENT, "no user found".to_owned())),
         Err(e) => {
-            warn!("Could not read user with name {name}:\n{e:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            warn!("Could not read user:\n{e:?}");
+            return Err(store_error_status(&e));
         }
     };
 
@@ -128,12 +536,1253 @@ async fn read_user(
     Ok((StatusCode::OK, format!("{}:{}", user.id, user.name)))
 }
 
+/// A user along with what we know about it beyond its name
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct EnrichedUser {
+    #[serde(flatten)]
+    user: User,
+    enrichment: Enrichment,
+}
+
+/// Read a user along with its enrichment
+#[utoipa::path(
+    get,
+    path = "/users/enriched/{name}",
+    tag = "users",
+    params(("name" = String, Path, description = "Name of the user")),
+    responses(
+        (status = OK, description = "The user and its enrichment", body = EnrichedUser),
+        (status = NO_CONTENT, description = "There is no user with this name"),
+        (status = UNAUTHORIZED, description = "The request carried invalid credentials"),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = BAD_GATEWAY, description = "The instance that enriches our users failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security((), ("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip(state, user_manager, correlation_id), fields(user_uuid))]
+async fn enriched_user(
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    Extension(correlation_id): Extension<CorrelationId>,
+    Path(name): Path<String>,
+) -> Result<Json<EnrichedUser>, StatusCode> {
+    info!("Read enriched user...");
+
+    let (user_manager, breaker, name) = (&user_manager, &state.circuit_breaker, &name);
+    let user = state
+        .retry
+        .run("read_user", || async move {
+            breaker.call(|| user_manager.read_by_name(ReadUser::new(name)))
+        })
+        .await;
+    let user = match user {
+        Ok(Some(user)) => user,
+        // Like `read_user`, so a missing user is not mistaken for a missing route
+        Ok(None) => return Err(StatusCode::NO_CONTENT),
+        Err(e) => {
+            warn!("Could not read user:\n{e:?}");
+            return Err(store_error_status(&e));
+        }
+    };
+    Span::current().record("user_uuid", user.id.to_string());
+
+    // -- With a second instance configured, this is where our trace continues in another process
+    let enrichment = match &state.enrichment {
+        Some(client) => {
+            let CorrelationId(id) = &correlation_id;
+            client.lookup(&user.name, Some(id)).await.map_err(|e| {
+                warn!("Could not enrich user:\n{e:?}");
+                StatusCode::BAD_GATEWAY
+            })?
+        }
+        None => enrichment::enrich(&user.name),
+    };
+
+    Ok(Json(EnrichedUser { user, enrichment }))
+}
+
+/// Enrich a user, for other instances of this service
+#[utoipa::path(
+    get,
+    path = "/enrichment/{name}",
+    params(("name" = String, Path, description = "Name of the user")),
+    responses((status = OK, description = "What we know about the user beyond its name", body = Enrichment))
+)]
+#[instrument]
+async fn enrichment_route(Path(name): Path<String>) -> Json<Enrichment> {
+    info!("Enrich user...");
+    Json(enrichment::enrich(&name))
+}
+
+/// Which profile `GET /debug/pprof/profile` captures
+#[cfg(target_os = "linux")]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ProfileQuery {
+    /// How long to sample the CPU, 10 seconds by default
+    seconds: Option<u64>,
+    /// `pprof` (the default) or `flamegraph`
+    #[param(inline)]
+    format: Option<ProfileFormat>,
+}
+
+/// Capture a CPU profile
+#[cfg(target_os = "linux")]
+#[utoipa::path(
+    get,
+    path = "/debug/pprof/profile",
+    tag = "admin",
+    params(ProfileQuery),
+    responses(
+        (status = OK, description = "The CPU profile, as protobuf encoded pprof profile or as flamegraph",
+            content((String = "application/octet-stream"), (String = "image/svg+xml"))),
+        (status = BAD_REQUEST, description = "The duration is 0 or longer than `PROFILING_MAX_SECONDS`"),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = FORBIDDEN, description = "The principal is not one of the `PROFILING_ADMINS`"),
+        (status = CONFLICT, description = "Another profile is being captured"),
+        (status = INTERNAL_SERVER_ERROR, description = "The profile could not be captured"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip_all, fields(seconds, format = ?query.format))]
+async fn cpu_profile(
+    State(cfg): State<Arc<ProfilingCfg>>,
+    principal: Option<Extension<Principal>>,
+    Query(query): Query<ProfileQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // Profiles show the inner workings of our server and keep a thread busy, so they are only for
+    // the admins
+    match principal {
+        None => return Err((StatusCode::UNAUTHORIZED, String::new())),
+        Some(Extension(principal)) if !cfg.admins.contains(&principal.name) => {
+            warn!(principal = principal.name, "Rejecting profile of non-admin");
+            return Err((StatusCode::FORBIDDEN, String::new()));
+        }
+        Some(_) => {}
+    }
+    let duration = Duration::from_secs(query.seconds.unwrap_or(10));
+    if duration.is_zero() || duration > cfg.max_duration {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "profiles last between 1 and {} seconds",
+                cfg.max_duration.as_secs()
+            ),
+        ));
+    }
+    Span::current().record("seconds", duration.as_secs());
+    let format = query.format.unwrap_or_default();
+    info!("Capture CPU profile...");
+
+    // -- Sampling blocks a thread for the whole duration, which must not be one of our workers
+    let profile = tokio::task::spawn_blocking(move || profiling::capture(&cfg, duration, format))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|profile| profile);
+    match profile {
+        Ok(profile) => Ok(([(CONTENT_TYPE, format.content_type())], profile)),
+        Err(e) if e.is::<ProfileRunning>() => Err((StatusCode::CONFLICT, e.to_string())),
+        Err(e) => {
+            warn!("Could not capture CPU profile:\n{e:?}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()))
+        }
+    }
+}
+
+/// Only the users `GET /users` should return
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListUsersQuery {
+    /// Only the user with this name
+    name: Option<String>,
+}
+
+/// List all users
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(ListUsersQuery),
+    responses(
+        (status = OK, description = "The users, ordered by name", body = Vec<User>),
+        (status = UNAUTHORIZED, description = "The request carried invalid credentials"),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security((), ("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip_all, fields(users))]
+async fn list_users(
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<Vec<User>>, StatusCode> {
+    info!("List users...");
+
+    let (user_manager, breaker) = (&user_manager, &state.circuit_breaker);
+    let users = state
+        .retry
+        .run("list_users", || async move {
+            breaker.call(|| user_manager.list())
+        })
+        .await;
+
+    let mut users = match users {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Could not list users:\n{e:?}");
+            return Err(store_error_status(&e));
+        }
+    };
+    if let Some(name) = &query.name {
+        users.retain(|user| &user.name == name);
+    }
+    Span::current().record("users", users.len());
+
+    Ok(Json(users))
+}
+
+/// Rename a user
+#[utoipa::path(
+    put,
+    path = "/users/rename/{name}/{new_name}",
+    tag = "users",
+    params(
+        ("name" = String, Path, description = "Current name of the user"),
+        ("new_name" = String, Path, description = "New name of the user"),
+    ),
+    responses(
+        (status = OK, description = "The renamed user as `<id>:<name>`", body = String, content_type = "text/plain"),
+        (status = NO_CONTENT, description = "There is no user with this name"),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = CONFLICT, description = "There already is a user with the new name"),
+        (status = UNPROCESSABLE_ENTITY, description = "The new name is invalid", body = ValidationErrors),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+// `new_name` is personal data just like `name`, so it is redacted, too (see `REDACT_FIELDS`)
+#[instrument(skip(state, user_manager, actor))]
+async fn update_user(
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    Path((name, new_name)): Path<(String, String)>,
+    actor: Actor,
+) -> Result<(StatusCode, String), Response<Body>> {
+    info!("Rename user...");
+
+    let new_user = NewUser::new(&new_name);
+    validate(&new_user).map_err(IntoResponse::into_response)?;
+
+    let (breaker, user_manager) = (state.circuit_breaker.clone(), user_manager.clone());
+    let result = blocking(move || {
+        breaker.call(|| user_manager.update(&actor, ReadUser::new(&name), new_user))
+    })
+    .await;
+    match result {
+        Ok(Some(user)) => Ok((StatusCode::OK, format!("{}:{}", user.id, user.name))),
+        Ok(None) => Ok((StatusCode::NO_CONTENT, "no user found".to_owned())),
+        Err(e) => {
+            warn!("Could not rename user:\n{e:?}");
+            Err(store_error_status(&e).into_response())
+        }
+    }
+}
+
+/// Delete a user
+#[utoipa::path(
+    delete,
+    path = "/users/delete/{name}",
+    tag = "users",
+    params(("name" = String, Path, description = "Name of the user")),
+    responses(
+        (status = OK, description = "The deleted user as `<id>:<name>`", body = String, content_type = "text/plain"),
+        (status = NO_CONTENT, description = "There is no user with this name"),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = INTERNAL_SERVER_ERROR, description = "The user store failed"),
+        (status = SERVICE_UNAVAILABLE, description = "The user store is unavailable, try again later"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip(state, user_manager, actor))]
+async fn delete_user(
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    Path(name): Path<String>,
+    actor: Actor,
+) -> impl IntoResponse {
+    info!("Delete user...");
+
+    let (breaker, user_manager) = (state.circuit_breaker.clone(), user_manager.clone());
+    let result =
+        blocking(move || breaker.call(|| user_manager.delete(&actor, ReadUser::new(&name)))).await;
+    match result {
+        Ok(Some(user)) => Ok((StatusCode::OK, format!("{}:{}", user.id, user.name))),
+        Ok(None) => Ok((StatusCode::NO_CONTENT, "no user found".to_owned())),
+        Err(e) => {
+            warn!("Could not delete user:\n{e:?}");
+            Err(store_error_status(&e))
+        }
+    }
+}
+
+/// Header a reconnecting SSE client sends with the ID of the last event it got
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Stream of user changes
+#[utoipa::path(
+    get,
+    path = "/users/events",
+    tag = "users",
+    params(("last-event-id" = Option<u64>, Header, description = "Resume after this event")),
+    responses(
+        (status = OK, description = "One server-sent event per created, updated or deleted user", body = String, content_type = "text/event-stream"),
+        (status = UNAUTHORIZED, description = "The request carried invalid credentials"),
+    ),
+    security((), ("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip_all, fields(last_event_id))]
+async fn user_events(
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+    if let Some(id) = last_event_id {
+        Span::current().record("last_event_id", id);
+    }
+
+    let (missed, receiver) = user_manager.events().subscribe(last_event_id);
+    info!(missed = missed.len(), "Subscribe to user events...");
+
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            // A client that fell behind is disconnected. It reconnects with the ID of the last
+            // event it got, and gets the ones it missed from the replay.
+            Err(RecvError::Lagged(_) | RecvError::Closed) => None,
+        }
+    });
+    let events = stream::iter(missed)
+        .chain(live)
+        .map(|event| Ok(sse_event(&event)));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Every event we send gets its own span, linked to the request that caused the change.
+fn sse_event(event: &UserEvent) -> Event {
+    let span = event.send_span();
+    let _guard = span.enter();
+
+    let sse_event = Event::default()
+        .id(event.id.to_string())
+        .event(event.kind.as_str());
+    match sse_event.json_data(event) {
+        Ok(sse_event) => sse_event,
+        Err(e) => {
+            warn!("Could not serialize user event:\n{e:?}");
+            Event::default()
+                .id(event.id.to_string())
+                .comment("unreadable event")
+        }
+    }
+}
+
+/// Import users from a CSV or NDJSON upload
+#[utoipa::path(
+    post,
+    path = "/users/import",
+    tag = "users",
+    request_body(
+        description = "CSV with a `name` and an optional `email` column, or NDJSON with one `{\"name\": ..., \"email\": ...}` object per line",
+        content((String = "text/csv"), (String = "application/x-ndjson"))
+    ),
+    responses(
+        (status = OK, description = "What happened to every row", body = ImportReport),
+        (status = BAD_REQUEST, description = "The upload could not be read on. The report lists the rows before the error, which were imported, and why the import was aborted", body = ImportReport),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = UNSUPPORTED_MEDIA_TYPE, description = "The upload is neither CSV nor NDJSON"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip_all, fields(format, rows, failed))]
+async fn import_users(
+    State(state): State<AppState>,
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    actor: Actor,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ImportReport>, Response<Body>> {
+    let Some(format) = Format::from_headers(&headers) else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected text/csv or application/x-ndjson",
+        )
+            .into_response());
+    };
+    Span::current().record("format", format.as_str());
+    info!("Import users...");
+
+    // We read the upload while we import it, so only the current batch is in memory
+    let mut rows = Rows::new(body.into_data_stream(), format);
+    let mut report = ImportReport::default();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    let mut batches = 0;
+    let result = loop {
+        match rows.next().await {
+            Ok(Some(row)) => {
+                batch.push(row);
+                if batch.len() < IMPORT_BATCH_SIZE {
+                    continue;
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+        batches += 1;
+        report = import_batch(
+            &state,
+            &user_manager,
+            &actor,
+            batches,
+            std::mem::take(&mut batch),
+            report,
+        )
+        .await;
+    };
+    if !batch.is_empty() {
+        report = import_batch(&state, &user_manager, &actor, batches + 1, batch, report).await;
+    }
+
+    let span = Span::current();
+    span.record("rows", report.created + report.failed);
+    span.record("failed", report.failed);
+
+    if let Err(e) = result {
+        warn!("Import aborted:\n{e:?}");
+        // The rows before the error were imported, so the client gets to know what happened to them
+        report.aborted = Some(format!("{e:#}"));
+        return Err((StatusCode::BAD_REQUEST, Json(report)).into_response());
+    }
+
+    info!("Import done");
+    Ok(Json(report))
+}
+
+/// Creates the users of one batch of an import within its own span, and adds them to `report`.
+/// Every user waits for its audit entry, so the batch runs on a blocking thread.
+async fn import_batch(
+    state: &AppState,
+    user_manager: &Arc<UserManager>,
+    actor: &Actor,
+    number: usize,
+    batch: Vec<Row>,
+    mut report: ImportReport,
+) -> ImportReport {
+    let span = info_span!(
+        "import_batch",
+        batch = number,
+        rows = batch.len(),
+        failed = Empty
+    );
+    let (breaker, user_manager) = (state.circuit_breaker.clone(), user_manager.clone());
+    let actor = actor.clone();
+    blocking(move || {
+        let _guard = span.enter();
+
+        let failed_before = report.failed;
+        for row in batch {
+            let result = row.user.and_then(|new_user| {
+                // Checked before the store is called, so bad rows are rejected right away. A name
+                // that is taken, e.g. by an earlier row, is only found by the store.
+                new_user.validate()?;
+                user_manager.check_quota()?;
+                breaker.call(|| user_manager.create(&actor, new_user))
+            });
+            if let Err(e) = &result {
+                warn!(row = row.number, "Could not import row: {e:#}");
+            }
+            report.record(row.number, result);
+        }
+        span.record("failed", report.failed - failed_before);
+        report
+    })
+    .await
+}
+
+/// Changes to users, oldest first and a page at a time
+#[utoipa::path(
+    get,
+    path = "/users/audit",
+    tag = "users",
+    params(AuditQuery),
+    responses(
+        (status = OK, description = "A page of the matching entries of the audit log", body = AuditPage),
+        (status = UNAUTHORIZED, description = "The request was not authenticated"),
+        (status = NOT_FOUND, description = "This server keeps no audit log"),
+        (status = INTERNAL_SERVER_ERROR, description = "The audit log could not be read"),
+    ),
+    security(("api_key" = []), ("jwt" = []))
+)]
+#[instrument(skip_all, fields(entries))]
+async fn audit_entries(
+    Extension(user_manager): Extension<Arc<UserManager>>,
+    Extension(tenant): Extension<Tenant>,
+    principal: Option<Extension<Principal>>,
+    Query(mut query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    // The audit log names our users, so unlike other reads it is not for anonymous eyes
+    if principal.is_none() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let Some(audit_log) = user_manager.audit_log() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    info!("Query audit log...");
+    // All tenants share the audit log, each of them only gets to see its own entries
+    query.tenant = Some(tenant.to_string());
+
+    match audit_log.query(&query) {
+        Ok(page) => {
+            Span::current().record("entries", page.entries.len());
+            if page.skipped > 0 {
+                warn!("Skipped {} unreadable lines of the audit log", page.skipped);
+            }
+            Ok(Json(page))
+        }
+        Err(e) => {
+            warn!("Could not query audit log:\n{e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The OpenAPI document of this API
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = OK, description = "This very document", body = Object))
+)]
+async fn openapi_json(
+    State(api): State<Arc<utoipa::openapi::OpenApi>>,
+) -> Json<utoipa::openapi::OpenApi> {
+    Json(api.as_ref().clone())
+}
+
+/// Metrics in the OpenMetrics text format, including exemplars
+#[cfg(feature = "prometheus")]
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = OK, description = "All metrics of this service", body = String, content_type = "application/openmetrics-text"),
+        (status = INTERNAL_SERVER_ERROR, description = "The metrics could not be collected"),
+    )
+)]
+async fn metrics() -> impl IntoResponse {
+    match openmetrics::scrape() {
+        Ok(metrics) => Ok(([(CONTENT_TYPE, openmetrics::CONTENT_TYPE)], metrics)),
+        Err(e) => {
+            warn!("Could not collect metrics:\n{e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether this service is healthy, including its telemetry pipeline
+///
+/// A degraded telemetry pipeline still answers with 200: the service itself works, and restarting
+/// it would lose the telemetry that is still queued.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "admin",
+    responses((status = OK, description = "How this service is doing", body = Health))
+)]
+async fn health() -> Json<Health> {
+    Json(pipeline::health())
+}
+
+/// Name of the histogram that records latencies of our HTTP requests
+const LATENCY_HISTOGRAM: &str = "http.server.latency";
+
 /// Build a new instance of the histogram, that records latencies of our HTTP requests.
 fn build_latency_histogram() -> Histogram<f64> {
     let meter = opentelemetry::global::meter("server_measurements");
     meter
-        .f64_histogram("http.server.latency")
+        .f64_histogram(LATENCY_HISTOGRAM)
         .with_description("Latency of HTTP requests")
         .with_unit("us")
         .build()
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use axum::http::Method;
+    use opentelemetry::trace::{SpanKind, TracerProvider as _};
+    use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData};
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{
+        auth::{API_KEY_HEADER, ApiKeys},
+        cfg::{CircuitBreakerCfg, EnrichmentCfg, JobsCfg, RetryCfg, TenantCfg},
+        tenant::TENANT_HEADER,
+        test_util::{CollectSpans, TempPath, eventually},
+    };
+
+    const TEST_API_KEY: &str = "test-key";
+    const TEAM_A_API_KEY: &str = "team-a-key";
+    const OPS_API_KEY: &str = "ops-key";
+
+    fn test_app() -> Router {
+        test_app_enriched_by(None)
+    }
+
+    fn test_app_enriched_by(enrichment: Option<Arc<EnrichmentClient>>) -> Router {
+        let (state, authenticator, tenants) = test_parts(enrichment);
+        app(
+            state,
+            authenticator,
+            tenants,
+            ProfilingCfg {
+                max_duration: Duration::from_secs(1),
+                frequency: 99,
+                admins: vec!["root".to_owned()],
+            },
+        )
+    }
+
+    /// What our servers are made of, with a user store that never fails
+    fn test_parts(
+        enrichment: Option<Arc<EnrichmentClient>>,
+    ) -> (AppState, Arc<Authenticator>, Arc<Tenants>) {
+        let path = TempPath::new("audit.jsonl");
+        let audit_log = Arc::new(AuditLog::open(&*path).unwrap());
+        let tenants = Tenants::new(
+            TenantCfg {
+                default_tenant: "default".to_owned(),
+                allowed_tenants: vec!["team-b".to_owned()],
+                max_tenants: 3,
+                max_users_per_tenant: 100,
+            },
+            move || {
+                // The audit log goes along with the tenants
+                let _path = &path;
+                UserManager::with_failure_rates(0.0, 0.0).with_audit_log(audit_log.clone())
+            },
+        );
+        let state = AppState {
+            retry: Arc::new(RetryPolicy::new(RetryCfg {
+                max_attempts: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            })),
+            circuit_breaker: Arc::new(CircuitBreaker::new(
+                "test",
+                CircuitBreakerCfg {
+                    failure_rate_threshold: 1.0,
+                    window_size: 10,
+                    minimum_calls: 10,
+                    open_duration: Duration::ZERO,
+                    half_open_calls: 1,
+                },
+            )),
+            enrichment,
+            jobs: Arc::new(JobQueue::start(JobsCfg {
+                workers: 1,
+                queue_capacity: 100,
+            })),
+        };
+        let api_keys = HashMap::from([
+            (TEST_API_KEY.to_owned(), "test".to_owned()),
+            (TEAM_A_API_KEY.to_owned(), "alice@team-a".to_owned()),
+            (OPS_API_KEY.to_owned(), "ops@*".to_owned()),
+        ]);
+        (
+            state,
+            Arc::new(Authenticator::default().with_provider(ApiKeys::new(api_keys))),
+            Arc::new(tenants),
+        )
+    }
+
+    async fn send(app: &Router, method: Method, uri: &str) -> Response<Body> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_route_is_documented() {
+        let app = test_app();
+
+        let response = send(&app, Method::GET, "/openapi.json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let api: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        // The document is built from the routes that go through `OpenApiRouter`. Whatever is
+        // routed in some other way, e.g. with `route_service`, would be missing from it and never
+        // probed below, so we hold the document to the list of paths we serve.
+        let mut served = vec![
+            "/hello",
+            "/enrichment/{name}",
+            "/openapi.json",
+            "/health",
+            "/users",
+            "/users/add/{name}",
+            "/users/read/{name}",
+            "/users/rename/{name}/{new_name}",
+            "/users/delete/{name}",
+            "/users/import",
+            "/users/events",
+            "/users/audit",
+            "/users/enriched/{name}",
+        ];
+        if cfg!(target_os = "linux") {
+            served.push("/debug/pprof/profile");
+        }
+        if cfg!(feature = "prometheus") {
+            served.push("/metrics");
+        }
+        let paths = api["paths"].as_object().unwrap();
+        let mut documented_paths: Vec<_> = paths.keys().map(String::as_str).collect();
+        documented_paths.sort_unstable();
+        served.sort_unstable();
+        assert_eq!(documented_paths, served);
+
+        for (path, operations) in paths {
+            // Fill in the path parameters, e.g. `/users/read/{name}` -> `/users/read/test`
+            let uri = path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "test"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let documented: HashSet<Method> = operations
+                .as_object()
+                .unwrap()
+                .keys()
+                .filter_map(|method| method.to_uppercase().parse().ok())
+                .collect();
+
+            // Everything that is documented is routed ...
+            for method in &documented {
+                let status = send(&app, method.clone(), &uri).await.status();
+                assert_ne!(status, StatusCode::NOT_FOUND, "{method} {path}");
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{method} {path}");
+            }
+
+            // ... and every method the router accepts on this path is documented.
+            let response = send(&app, Method::TRACE, &uri).await;
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+            let allowed = response.headers()["allow"].to_str().unwrap();
+            for method in allowed.split(',').map(str::trim) {
+                let method: Method = method.parse().unwrap();
+                if method != Method::HEAD {
+                    assert!(
+                        documented.contains(&method),
+                        "{method} {path} is not documented"
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn only_admins_capture_profiles() {
+        let app = test_app();
+        let profile = |key: Option<&str>| {
+            let mut request = Request::get("/debug/pprof/profile?seconds=1");
+            if let Some(key) = key {
+                request = request.header(API_KEY_HEADER, key);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = profile(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        for key in [TEST_API_KEY, TEAM_A_API_KEY] {
+            let response = profile(Some(key)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_users_row_by_row() {
+        let app = test_app();
+        let request = Request::post("/users/import")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(
+                "{\"name\":\"mert\"}\n\n{}\n{\"name\":\"ferris\"}\n{\"name\":\"mert\"}",
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(report["created"], 2);
+        assert_eq!(report["failed"], 2);
+        let rows = report["rows"].as_array().unwrap();
+        assert!(rows[0]["id"].is_string());
+        assert_eq!(rows[1]["row"], 2);
+        assert!(
+            rows[1]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid JSON")
+        );
+        assert_eq!(rows[3]["row"], 4);
+        assert_eq!(rows[3]["error"], "there already is a user with this name");
+    }
+
+    #[tokio::test]
+    async fn reports_the_rows_before_an_aborted_import() {
+        let app = test_app();
+        let request = Request::post("/users/import")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(
+                b"{\"name\":\"mert\"}\n\xff\n{\"name\":\"ferris\"}".to_vec(),
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: ImportReport = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!((report.created, report.failed), (1, 0));
+        assert!(report.aborted.unwrap().contains("UTF-8"));
+    }
+
+    #[tokio::test]
+    async fn streams_user_changes_from_the_last_event_id() {
+        let app = test_app();
+        for uri in ["/users/add/mert", "/users/add/ferris"] {
+            let request = Request::post(uri)
+                .header(API_KEY_HEADER, TEST_API_KEY)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(
+                app.clone().oneshot(request).await.unwrap().status(),
+                StatusCode::OK
+            );
+        }
+
+        let request = Request::get("/users/events")
+            .header(LAST_EVENT_ID_HEADER, "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body().into_data_stream();
+        let event = body.next().await.unwrap().unwrap();
+        let event = std::str::from_utf8(&event).unwrap();
+        assert!(
+            event.starts_with("id: 2\nevent: created\ndata: "),
+            "{event}"
+        );
+        assert!(event.contains(r#""name":"ferris""#), "{event}");
+    }
+
+    #[tokio::test]
+    async fn audits_who_changed_a_user() {
+        let app = test_app();
+        for (method, uri) in [
+            (Method::POST, "/users/add/mert"),
+            (Method::PUT, "/users/rename/mert/ferris"),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(API_KEY_HEADER, TEST_API_KEY)
+                .header(CORRELATION_ID_HEADER, "42")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(
+                app.clone().oneshot(request).await.unwrap().status(),
+                StatusCode::OK
+            );
+        }
+
+        let response = send(&app, Method::GET, "/users/audit").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::get("/users/audit?from=0")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let entries = serde_json::from_slice::<AuditPage>(&body).unwrap().entries;
+
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.actor == "test"));
+        assert!(
+            entries
+                .iter()
+                .all(|entry| entry.correlation_id.as_deref() == Some("42"))
+        );
+        assert!(entries[0].before.is_none());
+        assert_eq!(entries[1].before.as_ref().unwrap().name, "mert");
+        assert_eq!(entries[1].after.as_ref().unwrap().name, "ferris");
+        assert_eq!(entries[0].user_id, entries[1].user_id);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn user_service_shares_the_users_of_the_web_server() {
+        use tonic::{Code, codec::ProstCodec, transport::Endpoint};
+
+        use crate::grpc::proto::{
+            CreateUserRequest, CreateUserResponse, GetUserRequest, UserEvent, UserEventKind,
+            WatchUsersRequest, get_user_request::Key,
+        };
+
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // The test runs on a single thread, so the gRPC server reports to this subscriber, too
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (state, authenticator, tenants) = test_parts(None);
+        let http = app(
+            state.clone(),
+            authenticator.clone(),
+            tenants.clone(),
+            ProfilingCfg {
+                max_duration: Duration::from_secs(1),
+                frequency: 99,
+                admins: vec!["root".to_owned()],
+            },
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let grpc = grpc::router(state, authenticator, tenants);
+        tokio::spawn(axum::serve(listener, grpc).into_future());
+
+        let channel = Endpoint::from_shared(url).unwrap().connect().await.unwrap();
+        let mut client = tonic::client::Grpc::new(channel);
+        let call = axum::http::uri::PathAndQuery::from_static;
+        fn authenticated<T>(message: T) -> tonic::Request<T> {
+            let mut request = tonic::Request::new(message);
+            request
+                .metadata_mut()
+                .insert(API_KEY_HEADER, TEST_API_KEY.parse().unwrap());
+            request
+        }
+
+        client.ready().await.unwrap();
+        let mut events = client
+            .server_streaming::<_, UserEvent, _>(
+                authenticated(WatchUsersRequest::default()),
+                call("/users.v1.UserService/WatchUsers"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+
+        client.ready().await.unwrap();
+        let created = client
+            .unary::<_, CreateUserResponse, _>(
+                authenticated(CreateUserRequest {
+                    name: "ferris".to_owned(),
+                    ..Default::default()
+                }),
+                call("/users.v1.UserService/CreateUser"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!(event.kind(), UserEventKind::Created);
+        assert_eq!(event.user.unwrap().id, created.id);
+
+        // The web server knows the user created via gRPC
+        let request = Request::get("/users/read/ferris")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .body(Body::empty())
+            .unwrap();
+        let response = http.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, format!("{}:ferris", created.id));
+
+        client.ready().await.unwrap();
+        let missing = client
+            .unary::<_, crate::grpc::proto::User, _>(
+                authenticated(GetUserRequest {
+                    key: Some(Key::Name("mert".to_owned())),
+                }),
+                call("/users.v1.UserService/GetUser"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), Code::NotFound);
+
+        client.ready().await.unwrap();
+        let anonymous = client
+            .unary::<_, CreateUserResponse, _>(
+                tonic::Request::new(CreateUserRequest::default()),
+                call("/users.v1.UserService/CreateUser"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(anonymous.code(), Code::Unauthenticated);
+
+        // The span of a call may end just after its response arrived
+        let traced = |spans: &[SpanData]| {
+            let call = spans.iter().find(|span| {
+                span.name == "grpc_request"
+                    && span.attributes.iter().any(|kv| {
+                        kv.key.as_str() == "rpc.method" && kv.value.as_str() == "CreateUser"
+                    })
+            })?;
+            let handler = spans.iter().find(|span| {
+                span.name == "create_user" && span.parent_span_id == call.span_context.span_id()
+            })?;
+            Some((call.clone(), handler.clone()))
+        };
+        let (call, _handler) = eventually(|| traced(&collect.0.lock().unwrap()))
+            .await
+            .expect("the call is traced");
+        let attribute = |key: &str| {
+            call.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(attribute("rpc.system").as_deref(), Some("grpc"));
+        assert_eq!(attribute("rpc.grpc.status_code").as_deref(), Some("0"));
+        assert_eq!(attribute(TENANT_ATTRIBUTE).as_deref(), Some("default"));
+    }
+
+    #[tokio::test]
+    async fn continues_the_trace_in_the_instance_it_calls() {
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // The test runs on a single thread, so the second instance reports to this subscriber, too
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, test_app()).into_future());
+        let enrichment = EnrichmentClient::from_cfg(&EnrichmentCfg {
+            url: Some(url),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap()
+        .unwrap();
+        let app = test_app_enriched_by(Some(Arc::new(enrichment)));
+
+        let request = Request::post("/users/add/mert")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            app.clone().oneshot(request).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let request = Request::get("/users/enriched/mert")
+            .header(CORRELATION_ID_HEADER, "42")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let user: EnrichedUser = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user.user.name, "mert");
+        assert_eq!(user.enrichment, enrichment::enrich("mert"));
+
+        // The span of the second instance may end just after we got its response
+        let called = |spans: &[SpanData]| {
+            let client = spans
+                .iter()
+                .find(|span| span.span_kind == SpanKind::Client)?;
+            let server = spans.iter().find(|span| {
+                span.name == "http_request" && span.parent_span_id == client.span_context.span_id()
+            })?;
+            Some((client.clone(), server.clone()))
+        };
+        let (client, server) = eventually(|| called(&collect.0.lock().unwrap()))
+            .await
+            .expect("the second instance joins the trace");
+
+        assert_eq!(client.name, "GET");
+        assert_eq!(
+            server.span_context.trace_id(),
+            client.span_context.trace_id()
+        );
+        let attribute = |span: &SpanData, key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(
+            attribute(&client, "url.template").as_deref(),
+            Some("/enrichment/{name}")
+        );
+        assert_eq!(
+            attribute(&client, "http.response.status_code").as_deref(),
+            Some("200")
+        );
+        assert_eq!(attribute(&server, "correlation_id").as_deref(), Some("42"));
+    }
+
+    #[tokio::test]
+    async fn keeps_tenants_apart() {
+        let app = test_app();
+        let send_as = |key: Option<&str>, tenant: Option<&str>, method: Method, uri: &str| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(key) = key {
+                request = request.header(API_KEY_HEADER, key);
+            }
+            if let Some(tenant) = tenant {
+                request = request.header(TENANT_HEADER, tenant);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // The key of alice belongs to `team-a`
+        let response = send_as(Some(TEAM_A_API_KEY), None, Method::POST, "/users/add/mert").await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+
+        // Only credentials for every tenant may pick one, everyone else gets the default tenant
+        for (key, tenant, status) in [
+            (Some(OPS_API_KEY), Some("team-a"), StatusCode::OK),
+            (Some(OPS_API_KEY), Some("team-b"), StatusCode::NO_CONTENT),
+            (Some(OPS_API_KEY), None, StatusCode::NO_CONTENT),
+            (Some(TEST_API_KEY), Some("default"), StatusCode::NO_CONTENT),
+            (Some(TEST_API_KEY), Some("team-a"), StatusCode::FORBIDDEN),
+            (None, Some("team-a"), StatusCode::FORBIDDEN),
+            (None, None, StatusCode::NO_CONTENT),
+        ] {
+            let response = send_as(key, tenant, Method::GET, "/users/read/mert").await;
+            assert_eq!(response.unwrap().status(), status, "{key:?} {tenant:?}");
+        }
+
+        // Credentials of one tenant cannot be used for another one
+        let response = send_as(
+            Some(TEAM_A_API_KEY),
+            Some("team-b"),
+            Method::POST,
+            "/users/add/ferris",
+        )
+        .await;
+        assert_eq!(response.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let response = send_as(
+            Some(OPS_API_KEY),
+            Some("Team B"),
+            Method::GET,
+            "/users/read/mert",
+        )
+        .await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        // Picking a tenant does not create its store, only credentials naming it do
+        let response = send_as(
+            Some(OPS_API_KEY),
+            Some("team-c"),
+            Method::GET,
+            "/users/read/mert",
+        )
+        .await;
+        assert_eq!(response.unwrap().status(), StatusCode::FORBIDDEN);
+
+        // The audit log is shared, but every tenant only sees its own entries
+        for (key, entries) in [(TEAM_A_API_KEY, 1), (TEST_API_KEY, 0)] {
+            let response = send_as(Some(key), None, Method::GET, "/users/audit")
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let audit = serde_json::from_slice::<AuditPage>(&body).unwrap().entries;
+            assert_eq!(audit.len(), entries, "{key}");
+            assert!(
+                audit
+                    .iter()
+                    .all(|entry| entry.tenant.as_deref() == Some("team-a"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_users_with_field_errors() {
+        let app = test_app();
+        let request = Request::post("/users/add/%3Cscript%3E?email=nope")
+            .header(API_KEY_HEADER, TEST_API_KEY)
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let errors: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let errors: Vec<_> = errors["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| {
+                (
+                    error["field"].as_str().unwrap(),
+                    error["code"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [("name", "invalid_characters"), ("email", "invalid_email")]
+        );
+    }
+}
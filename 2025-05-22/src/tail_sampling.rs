@@ -0,0 +1,299 @@
+//! Tail based sampling.
+//!
+//! The `TraceIdRatioBased` sampler in `otel.rs` decides whether to keep a trace when it starts.
+//! At that point we do not know yet whether the request is going to fail or take ages - which are
+//! exactly the traces we are interested in. Tail based sampling decides at the _end_ instead: we
+//! buffer all spans of a trace until its root span ends and then
+//! - always keep traces that contain an error,
+//! - always keep traces whose root span took longer than a threshold,
+//! - keep only a share of all other traces.
+//!
+//! To keep the memory bounded, we buffer a limited number of traces and spans per trace. Traces that
+//! do not fit are dropped and counted in the `tail_sampling.dropped_traces` metric.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use opentelemetry::{
+    Context, KeyValue,
+    metrics::Counter,
+    trace::{SpanId, Status, TraceId},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+};
+
+use crate::cfg::TailSamplingCfg;
+
+/// The span our web server creates for every request, see `server.rs`
+const ROOT_SPAN_NAME: &str = "http_request";
+/// The span our gRPC server creates for every call, see `grpc.rs`
+const GRPC_ROOT_SPAN_NAME: &str = "grpc_request";
+
+/// How many recent decisions we remember for spans that end after their root span
+const REMEMBERED_DECISIONS: usize = 1_024;
+
+#[derive(Default)]
+struct Buffer {
+    /// Spans of undecided traces
+    traces: HashMap<TraceId, Vec<SpanData>>,
+    /// Undecided traces, oldest first
+    order: VecDeque<TraceId>,
+    /// Recently decided traces and whether we kept them
+    decisions: VecDeque<(TraceId, bool)>,
+}
+
+/// Buffers spans per trace and passes on the spans of the traces we keep to the `next` processor.
+#[derive(Debug)]
+pub struct TailSamplingProcessor<P> {
+    next: P,
+    cfg: TailSamplingCfg,
+    buffer: Mutex<Buffer>,
+    dropped_traces: Counter<u64>,
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Buffer")
+            .field("traces", &self.traces.len())
+            .finish()
+    }
+}
+
+impl<P: SpanProcessor> TailSamplingProcessor<P> {
+    pub fn new(next: P, cfg: TailSamplingCfg) -> Self {
+        let dropped_traces = opentelemetry::global::meter("telemetry_pipeline")
+            .u64_counter("tail_sampling.dropped_traces")
+            .with_description("Traces dropped because the tail sampling buffer was full")
+            .build();
+
+        Self {
+            next,
+            cfg,
+            buffer: Mutex::new(Buffer::default()),
+            dropped_traces,
+        }
+    }
+
+    fn keep(&self, spans: &[SpanData]) -> bool {
+        let has_error = spans
+            .iter()
+            .any(|span| matches!(span.status, Status::Error { .. }));
+        let too_slow = spans.iter().any(|span| {
+            is_root(span)
+                && span
+                    .end_time
+                    .duration_since(span.start_time)
+                    .unwrap_or(Duration::ZERO)
+                    > self.cfg.latency_threshold
+        });
+
+        has_error || too_slow || rand::random_bool(self.cfg.sample_rate)
+    }
+
+    fn drop_trace(&self, reason: &'static str) {
+        self.dropped_traces
+            .add(1, &[KeyValue::new("reason", reason)]);
+    }
+
+    fn forward(&self, spans: Vec<SpanData>) {
+        for span in spans {
+            self.next.on_end(span);
+        }
+    }
+}
+
+/// Root spans either have no parent or are the entry point of a request into our service (which
+/// might have a parent in another service).
+fn is_root(span: &SpanData) -> bool {
+    span.parent_span_id == SpanId::INVALID
+        || span.name == ROOT_SPAN_NAME
+        || span.name == GRPC_ROOT_SPAN_NAME
+}
+
+impl<P: SpanProcessor> SpanProcessor for TailSamplingProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.next.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+        let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Late spans of traces we already decided on follow the decision
+        if let Some((_, keep)) = buffer.decisions.iter().find(|(id, _)| *id == trace_id) {
+            let keep = *keep;
+            drop(buffer);
+            if keep {
+                self.next.on_end(span);
+            }
+            return;
+        }
+
+        let root = is_root(&span);
+        if !buffer.traces.contains_key(&trace_id) {
+            if buffer.order.len() >= self.cfg.max_traces
+                && let Some(oldest) = buffer.order.pop_front()
+            {
+                buffer.traces.remove(&oldest);
+                remember(&mut buffer.decisions, oldest, false);
+                self.drop_trace("max_traces");
+            }
+            buffer.order.push_back(trace_id);
+        }
+
+        let spans = buffer.traces.entry(trace_id).or_default();
+        spans.push(span);
+        if spans.len() > self.cfg.max_spans_per_trace {
+            buffer.traces.remove(&trace_id);
+            buffer.order.retain(|id| *id != trace_id);
+            remember(&mut buffer.decisions, trace_id, false);
+            self.drop_trace("max_spans_per_trace");
+            return;
+        }
+
+        if !root {
+            return;
+        }
+
+        let spans = buffer.traces.remove(&trace_id).unwrap_or_default();
+        buffer.order.retain(|id| *id != trace_id);
+        let keep = self.keep(&spans);
+        remember(&mut buffer.decisions, trace_id, keep);
+        drop(buffer);
+
+        if keep {
+            self.forward(spans);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        // Decide on whatever we have of the traces that are still waiting for their root span
+        let traces = {
+            let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+            buffer.order.clear();
+            std::mem::take(&mut buffer.traces)
+        };
+        for spans in traces.into_values() {
+            if self.keep(&spans) {
+                self.forward(spans);
+            }
+        }
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+fn remember(decisions: &mut VecDeque<(TraceId, bool)>, trace_id: TraceId, keep: bool) {
+    if decisions.len() >= REMEMBERED_DECISIONS {
+        decisions.pop_front();
+    }
+    decisions.push_back((trace_id, keep));
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use opentelemetry::{
+        InstrumentationScope,
+        trace::{SpanContext, SpanKind, TraceFlags, TraceState},
+    };
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+
+    use super::*;
+    use crate::test_util::CollectSpans;
+
+    fn processor(max_traces: usize) -> (TailSamplingProcessor<CollectSpans>, CollectSpans) {
+        let collect = CollectSpans::default();
+        let cfg = TailSamplingCfg {
+            sample_rate: 0.0,
+            latency_threshold: Duration::from_millis(100),
+            max_traces,
+            max_spans_per_trace: 10,
+        };
+        (TailSamplingProcessor::new(collect.clone(), cfg), collect)
+    }
+
+    fn span(trace: u128, id: u64, parent: u64, duration: Duration, status: Status) -> SpanData {
+        let start_time = SystemTime::now();
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(trace),
+                SpanId::from(id),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::from(parent),
+            span_kind: SpanKind::Internal,
+            name: if parent == 0 { ROOT_SPAN_NAME } else { "child" }.into(),
+            start_time,
+            end_time: start_time + duration,
+            attributes: vec![],
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status,
+            instrumentation_scope: InstrumentationScope::builder("test").build(),
+        }
+    }
+
+    fn exported(collect: &CollectSpans) -> usize {
+        collect.0.lock().unwrap().len()
+    }
+
+    #[test]
+    fn keeps_traces_with_errors() {
+        let (processor, collect) = processor(10);
+        let error = Status::error("store failed");
+
+        processor.on_end(span(1, 2, 1, Duration::ZERO, error));
+        assert_eq!(exported(&collect), 0, "waits for the root span");
+        processor.on_end(span(1, 1, 0, Duration::ZERO, Status::Unset));
+        assert_eq!(exported(&collect), 2);
+    }
+
+    #[test]
+    fn keeps_slow_traces_and_samples_the_rest() {
+        let (processor, collect) = processor(10);
+
+        processor.on_end(span(1, 2, 1, Duration::ZERO, Status::Unset));
+        processor.on_end(span(1, 1, 0, Duration::from_millis(10), Status::Unset));
+        assert_eq!(exported(&collect), 0);
+
+        processor.on_end(span(2, 1, 0, Duration::from_secs(1), Status::Unset));
+        assert_eq!(exported(&collect), 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_trace_when_full() {
+        let (processor, collect) = processor(1);
+        let error = Status::error("store failed");
+
+        processor.on_end(span(1, 2, 1, Duration::ZERO, error.clone()));
+        processor.on_end(span(2, 2, 1, Duration::ZERO, error));
+        processor.on_end(span(1, 1, 0, Duration::ZERO, Status::Unset));
+        processor.on_end(span(2, 1, 0, Duration::ZERO, Status::Unset));
+
+        let exported = collect.0.lock().unwrap();
+        assert_eq!(exported.len(), 2);
+        assert!(
+            exported
+                .iter()
+                .all(|span| span.span_context.trace_id() == TraceId::from(2))
+        );
+    }
+}
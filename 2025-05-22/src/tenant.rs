@@ -0,0 +1,457 @@
+//! Keeping the users of our tenants apart.
+//!
+//! We host this service for several teams. Each of them is a _tenant_ with its own
+//! [`UserManager`], so they can neither see nor change each other's users, and each of them has a
+//! quota of users. The [`identify`] middleware finds out which tenant a request belongs to:
+//! - credentials can name a tenant, as the `tenant` claim of a JWT or an API key configured as
+//!   `key=principal@tenant`,
+//! - credentials that may act for every tenant, with the tenant [`ANY_TENANT`], pick one with the
+//!   `x-tenant-id` header,
+//! - all other requests, anonymous ones included, belong to the default tenant.
+//!
+//! The stores of the default tenant and of the tenants in `TENANT_ALLOWED` exist from the start.
+//! Other tenants get theirs with the first request whose credentials name them, so nobody can
+//! use up our tenants by making some up.
+//!
+//! To break usage down per tenant, every span a request produces carries a `tenant.id` attribute,
+//! added by the [`TenantLayer`], and so does every log record we export, added by the
+//! [`TenantLogProcessor`]. The metrics of requests and jobs get it from [`attributes`]. Metrics of
+//! what all tenants share, like the circuit breaker of the user store or the telemetry pipeline,
+//! do not belong to any tenant and have no `tenant.id`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context as _;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use opentelemetry::{
+    InstrumentationScope, KeyValue,
+    logs::{AnyValue, LogRecord},
+    metrics::ObservableGauge,
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{LogProcessor, SdkLogRecord},
+};
+use tracing::{Span, Subscriber, span, warn};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+use crate::{audit::AuditEntry, auth::Principal, business::UserManager, cfg::TenantCfg};
+
+/// Header a request picks its tenant with
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// Tenant of credentials that may act for every tenant, e.g. `key=ops@*`
+pub const ANY_TENANT: &str = "*";
+
+/// Name of the span and metric attribute
+pub const TENANT_ATTRIBUTE: &str = "tenant.id";
+
+/// Longest tenant ID we accept
+const MAX_TENANT_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    /// The tenant of the request the current task works on
+    static CURRENT: Tenant;
+}
+
+/// The ID of a tenant: lower case letters, digits, `-` and `_`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    pub fn parse(id: &str) -> anyhow::Result<Self> {
+        if id.is_empty()
+            || id.len() > MAX_TENANT_ID_LEN
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_'))
+        {
+            anyhow::bail!(
+                "invalid tenant `{id}`, expected up to {MAX_TENANT_ID_LEN} lower case letters, \
+                 digits, `-` and `_`"
+            );
+        }
+        Ok(Self(id.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The tenant of the current request, if there is one
+pub fn current() -> Option<Tenant> {
+    CURRENT.try_with(Tenant::clone).ok()
+}
+
+/// Runs `future` on behalf of `tenant`, e.g. a job that a request of the tenant enqueued.
+pub async fn scope<F: Future>(tenant: Option<Tenant>, future: F) -> F::Output {
+    match tenant {
+        Some(tenant) => CURRENT.scope(tenant, future).await,
+        None => future.await,
+    }
+}
+
+/// Like [`scope`], for work that leaves the task, e.g. for a blocking thread.
+pub fn sync_scope<R>(tenant: Option<Tenant>, f: impl FnOnce() -> R) -> R {
+    match tenant {
+        Some(tenant) => CURRENT.sync_scope(tenant, f),
+        None => f(),
+    }
+}
+
+/// Metric attributes for the current request: its `tenant.id`, if there is one
+pub fn attributes() -> Vec<KeyValue> {
+    current()
+        .map(|tenant| KeyValue::new(TENANT_ATTRIBUTE, tenant.0))
+        .into_iter()
+        .collect()
+}
+
+/// The user stores of all tenants
+pub struct Tenants {
+    cfg: TenantCfg,
+    new_store: Box<dyn Fn() -> UserManager + Send + Sync>,
+    stores: Arc<RwLock<HashMap<Tenant, Arc<UserManager>>>>,
+    _users_gauge: ObservableGauge<u64>,
+}
+
+impl Tenants {
+    /// `new_store` creates the store of a new tenant, we add the quota. The default tenant and
+    /// the allowed ones get their stores right away.
+    pub fn new(
+        cfg: TenantCfg,
+        new_store: impl Fn() -> UserManager + Send + Sync + 'static,
+    ) -> Self {
+        let stores: HashMap<_, _> = std::iter::once(&cfg.default_tenant)
+            .chain(&cfg.allowed_tenants)
+            .map(|id| {
+                let store = new_store().with_max_users(cfg.max_users_per_tenant);
+                (Tenant(id.clone()), Arc::new(store))
+            })
+            .collect();
+        let stores = Arc::new(RwLock::new(stores));
+
+        let observed = stores.clone();
+        let users_gauge = opentelemetry::global::meter("server_measurements")
+            .u64_observable_gauge("tenant.users")
+            .with_description("Number of users per tenant")
+            .with_callback(move |observer| {
+                let stores = observed.read().unwrap_or_else(|e| e.into_inner());
+                for (tenant, store) in stores.iter() {
+                    observer.observe(
+                        store.user_count() as u64,
+                        &[KeyValue::new(TENANT_ATTRIBUTE, tenant.0.clone())],
+                    );
+                }
+            })
+            .build();
+
+        Self {
+            cfg,
+            new_store: Box::new(new_store),
+            stores,
+            _users_gauge: users_gauge,
+        }
+    }
+
+    /// The store of `tenant`, `None` if it has none yet.
+    pub fn store(&self, tenant: &Tenant) -> Option<Arc<UserManager>> {
+        self.read().get(tenant).cloned()
+    }
+
+    /// The store of `tenant`, created if it has none yet. Only for tenants named by credentials.
+    /// Fails if we already serve as many tenants as we are configured to.
+    pub fn store_or_create(&self, tenant: &Tenant) -> anyhow::Result<Arc<UserManager>> {
+        if let Some(store) = self.store(tenant) {
+            return Ok(store);
+        }
+
+        let mut stores = self.stores.write().unwrap_or_else(|e| e.into_inner());
+        // Someone else might have been faster
+        if let Some(store) = stores.get(tenant) {
+            return Ok(store.clone());
+        }
+        if stores.len() >= self.cfg.max_tenants {
+            anyhow::bail!("we already serve {} tenants", self.cfg.max_tenants);
+        }
+        let store = Arc::new((self.new_store)().with_max_users(self.cfg.max_users_per_tenant));
+        stores.insert(tenant.clone(), store.clone());
+        Ok(store)
+    }
+
+    /// Restores the users of all tenants from the entries of the audit log, oldest first.
+    pub fn restore(&self, entries: &[AuditEntry]) -> anyhow::Result<()> {
+        for entry in entries {
+            // Entries written before we had tenants belong to the default tenant
+            let id = entry.tenant.as_deref().unwrap_or(&self.cfg.default_tenant);
+            let tenant = Tenant::parse(id)?;
+            // Credentials named the tenant when the change was made
+            self.store_or_create(&tenant)
+                .with_context(|| format!("could not restore tenant `{tenant}`"))?
+                .replay(entry)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<Tenant, Arc<UserManager>>> {
+        self.stores.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Middleware that finds the tenant of a request and hands its store to the handler as an
+/// `Arc<UserManager>` extension. Runs after [`authenticate`](crate::auth::authenticate), as the
+/// credentials may name the tenant.
+pub async fn identify(
+    State(tenants): State<Arc<Tenants>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let claimed = request
+        .extensions()
+        .get::<Principal>()
+        .and_then(|principal| principal.tenant.clone());
+    let requested = match request.headers().get(TENANT_HEADER).map(|id| id.to_str()) {
+        Some(Ok(id)) => Some(id.to_owned()),
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "the tenant is not valid ASCII").into_response();
+        }
+        None => None,
+    };
+
+    let default_tenant = &tenants.cfg.default_tenant;
+    // Only credentials that name the tenant may create its store
+    let (id, named_by_credentials) = match (claimed, requested) {
+        (Some(claimed), requested) if claimed == ANY_TENANT => {
+            (requested.unwrap_or_else(|| default_tenant.clone()), false)
+        }
+        (Some(claimed), Some(requested)) if claimed != requested => {
+            warn!("Rejecting request for tenant `{requested}` with credentials of `{claimed}`");
+            return (
+                StatusCode::FORBIDDEN,
+                format!("the credentials belong to tenant `{claimed}`"),
+            )
+                .into_response();
+        }
+        (Some(claimed), _) => (claimed, true),
+        (None, Some(requested)) if requested != *default_tenant => {
+            warn!("Rejecting request for tenant `{requested}` without credentials of it");
+            return (
+                StatusCode::FORBIDDEN,
+                format!("no credentials for tenant `{requested}`"),
+            )
+                .into_response();
+        }
+        (None, _) => (default_tenant.clone(), false),
+    };
+    let tenant = match Tenant::parse(&id) {
+        Ok(tenant) => tenant,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    };
+    let store = if named_by_credentials {
+        tenants.store_or_create(&tenant)
+    } else {
+        tenants
+            .store(&tenant)
+            .ok_or_else(|| anyhow::anyhow!("no credentials named it yet"))
+    };
+    let store = match store {
+        Ok(store) => store,
+        Err(e) => {
+            warn!("Rejecting request for new tenant `{tenant}`: {e:#}");
+            return (StatusCode::FORBIDDEN, format!("unknown tenant `{tenant}`")).into_response();
+        }
+    };
+
+    // The `http_request` span is the one that is current here
+    Span::current().record(TENANT_ATTRIBUTE, tenant.as_str());
+    request.extensions_mut().insert(store);
+    request.extensions_mut().insert(tenant.clone());
+
+    let mut response = CURRENT.scope(tenant.clone(), next.run(request)).await;
+    // For the latency histogram, which is recorded after we are done here
+    response.extensions_mut().insert(tenant);
+    response
+}
+
+/// Adds `tenant.id` to every span that is created while a request of a tenant is handled.
+///
+/// Must be added to the subscriber after the `OpenTelemetryLayer`, so the span data we add the
+/// attribute to already exists.
+pub struct TenantLayer;
+
+impl<S> Layer<S> for TenantLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        // Spans with a `tenant.id` field of their own record it themselves
+        if attrs.metadata().fields().field(TENANT_ATTRIBUTE).is_some() {
+            return;
+        }
+        let (Some(tenant), Some(span)) = (current(), ctx.span(id)) else {
+            return;
+        };
+        if let Some(otel) = span.extensions_mut().get_mut::<OtelData>() {
+            otel.builder
+                .attributes
+                .get_or_insert_with(Vec::new)
+                .push(KeyValue::new(TENANT_ATTRIBUTE, tenant.0));
+        }
+    }
+}
+
+/// Adds `tenant.id` to every log record that is emitted while a request of a tenant is handled.
+#[derive(Debug)]
+pub struct TenantLogProcessor<P> {
+    next: P,
+}
+
+impl<P: LogProcessor> TenantLogProcessor<P> {
+    pub fn new(next: P) -> Self {
+        Self { next }
+    }
+}
+
+impl<P: LogProcessor> LogProcessor for TenantLogProcessor<P> {
+    fn emit(&self, record: &mut SdkLogRecord, scope: &InstrumentationScope) {
+        // Log records are emitted right where the event happens, so the task is still ours
+        if let Some(tenant) = current() {
+            record.add_attribute(TENANT_ATTRIBUTE, AnyValue::from(tenant.0));
+        }
+        self.next.emit(record, scope);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.next.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.next.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.next.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::test_util::{CollectLogs, CollectSpans};
+
+    #[test]
+    fn parses_tenant_ids() {
+        assert_eq!(Tenant::parse("team-a_2").unwrap().as_str(), "team-a_2");
+        for id in ["", "Team-A", "team a", "../etc", &"a".repeat(65)] {
+            assert!(Tenant::parse(id).is_err(), "{id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn spans_of_a_request_carry_the_tenant() {
+        let collect = CollectSpans::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(collect.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .with(TenantLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let tenant = Tenant::parse("team-a").unwrap();
+        CURRENT
+            .scope(tenant, async {
+                info_span!("read_user").in_scope(|| {});
+                assert_eq!(attributes(), [KeyValue::new(TENANT_ATTRIBUTE, "team-a")]);
+            })
+            .await;
+        info_span!("startup").in_scope(|| {});
+
+        let spans = collect.0.lock().unwrap();
+        let tenant_of = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap()
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == TENANT_ATTRIBUTE)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(tenant_of("read_user").as_deref(), Some("team-a"));
+        assert_eq!(tenant_of("startup"), None);
+    }
+
+    #[tokio::test]
+    async fn log_records_of_a_request_carry_the_tenant() {
+        use opentelemetry::logs::{Logger as _, LoggerProvider as _};
+
+        let collect = CollectLogs::default();
+        let provider = opentelemetry_sdk::logs::SdkLoggerProvider::builder()
+            .with_log_processor(TenantLogProcessor::new(collect.clone()))
+            .build();
+        let logger = provider.logger("test");
+
+        let tenant = Tenant::parse("team-a").unwrap();
+        CURRENT
+            .scope(tenant, async { logger.emit(logger.create_log_record()) })
+            .await;
+        logger.emit(logger.create_log_record());
+
+        let records = collect.0.lock().unwrap();
+        let tenants: Vec<_> = records
+            .iter()
+            .map(|record| {
+                record
+                    .attributes_iter()
+                    .find(|(key, _)| key.as_str() == TENANT_ATTRIBUTE)
+                    .map(|(_, value)| value.clone())
+            })
+            .collect();
+        assert_eq!(tenants, [Some(AnyValue::from("team-a")), None]);
+    }
+
+    #[test]
+    fn only_creates_stores_for_tenants_named_by_credentials() {
+        let cfg = TenantCfg {
+            default_tenant: "default".to_owned(),
+            allowed_tenants: vec!["team-a".to_owned()],
+            max_tenants: 3,
+            max_users_per_tenant: 10,
+        };
+        let tenants = Tenants::new(cfg, || UserManager::with_failure_rates(0.0, 0.0));
+        let tenant = |id: &str| Tenant::parse(id).unwrap();
+
+        assert!(tenants.store(&tenant("default")).is_some());
+        assert!(tenants.store(&tenant("team-a")).is_some());
+        assert!(tenants.store(&tenant("team-b")).is_none());
+        assert_eq!(tenants.read().len(), 2);
+
+        tenants.store_or_create(&tenant("team-b")).unwrap();
+        assert!(tenants.store_or_create(&tenant("team-c")).is_err());
+        assert_eq!(tenants.read().len(), 3);
+    }
+}
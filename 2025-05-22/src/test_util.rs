@@ -0,0 +1,86 @@
+//! Fixtures the tests of several modules share
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use opentelemetry::InstrumentationScope;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    logs::{LogProcessor, SdkLogRecord},
+    trace::{Span, SpanData, SpanProcessor},
+};
+use uuid::Uuid;
+
+/// Collects the finished spans, like a batch processor that never gets to export them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CollectSpans(pub(crate) Arc<Mutex<Vec<SpanData>>>);
+
+impl SpanProcessor for CollectSpans {
+    fn on_start(&self, _span: &mut Span, _cx: &opentelemetry::Context) {}
+    fn on_end(&self, span: SpanData) {
+        self.0.lock().unwrap().push(span);
+    }
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+    fn shutdown(&self) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// Collects the emitted log records
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CollectLogs(pub(crate) Arc<Mutex<Vec<SdkLogRecord>>>);
+
+impl LogProcessor for CollectLogs {
+    fn emit(&self, record: &mut SdkLogRecord, _scope: &InstrumentationScope) {
+        self.0.lock().unwrap().push(record.clone());
+    }
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+    fn shutdown(&self) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// Calls `find` every 10ms until it finds something, for up to a second. For what background
+/// tasks, like the workers of the job queue, finish after the test stopped waiting for them.
+pub(crate) async fn eventually<T>(mut find: impl FnMut() -> Option<T>) -> Option<T> {
+    for _ in 0..100 {
+        if let Some(found) = find() {
+            return Some(found);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    None
+}
+
+/// A unique path in the temp dir, for a file or directory that is removed once the test drops it
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    /// `name` with a random prefix, e.g. `audit.jsonl`
+    pub(crate) fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("{}-{name}", Uuid::new_v4())))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // Whatever the test left there, if anything
+        let _ = std::fs::remove_file(&self.0).or_else(|_| std::fs::remove_dir_all(&self.0));
+    }
+}